@@ -0,0 +1,535 @@
+use crate::{
+    child,
+    cli::{BuildArgs, RunArgs},
+    container, export,
+    image::{self, Image, ImageConfig},
+    overlay, state,
+    store::{self, ImageRecord, SHORT_ID_LEN},
+};
+use eyre::{bail, eyre, WrapErr};
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    env,
+    fmt::Write,
+    fs::{self, File, Metadata},
+    io::BufWriter,
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+    slice,
+    str::FromStr,
+};
+use tracing::debug;
+
+const LAYER_TAR_NAME: &str = "layer.tar";
+const STAGING_DIR_NAME: &str = "root";
+/// A step of a Bentofile. COPY-BINARY, COPY, MKDIR, and RUN add a layer each, and the other steps
+/// change the config of the image.
+#[derive(Debug, Clone)]
+enum Step {
+    /// A host binary along with the libraries it links to, at the same paths as on the host
+    CopyBinary(String),
+    /// A file or directory of the build context
+    Copy {
+        source: PathBuf,
+        destination: String,
+    },
+    Mkdir(String),
+    Env {
+        name: String,
+        value: String,
+    },
+    Workdir(String),
+    User(String),
+    Run(Vec<String>),
+    Entrypoint(Vec<String>),
+}
+
+/// Arguments of RUN and ENTRYPOINT, either as a JSON array or split on whitespace like
+/// `--command`.
+fn parse_argv(args: &str) -> eyre::Result<Vec<String>> {
+    let argv: Vec<String> = if args.starts_with('[') {
+        serde_json::from_str(args).wrap_err("Invalid JSON array of arguments")?
+    } else {
+        args.split_whitespace().map(str::to_owned).collect()
+    };
+    if argv.is_empty() {
+        bail!("Missing command");
+    }
+    Ok(argv)
+}
+
+fn parse_single_arg(instruction: &str, args: &str) -> eyre::Result<String> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        bail!("{instruction} needs exactly one argument");
+    }
+    Ok(args.to_owned())
+}
+
+impl FromStr for Step {
+    type Err = eyre::Report;
+
+    fn from_str(line: &str) -> eyre::Result<Self> {
+        let (instruction, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        match instruction.to_ascii_uppercase().as_str() {
+            "COPY-BINARY" => Ok(Self::CopyBinary(parse_single_arg(instruction, args)?)),
+            "COPY" => match args.split_whitespace().collect::<Vec<_>>()[..] {
+                [source, destination] => Ok(Self::Copy {
+                    source: PathBuf::from(source),
+                    destination: destination.to_owned(),
+                }),
+                _ => bail!("{instruction} needs a source and a destination"),
+            },
+            "MKDIR" => Ok(Self::Mkdir(parse_single_arg(instruction, args)?)),
+            "ENV" => match args.split_once('=') {
+                Some((name, value)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                    Ok(Self::Env {
+                        name: name.to_owned(),
+                        value: value.to_owned(),
+                    })
+                }
+                _ => bail!("{instruction} needs NAME=VALUE"),
+            },
+            "WORKDIR" => Ok(Self::Workdir(parse_single_arg(instruction, args)?)),
+            "USER" => Ok(Self::User(parse_single_arg(instruction, args)?)),
+            "RUN" => Ok(Self::Run(parse_argv(args)?)),
+            "ENTRYPOINT" => Ok(Self::Entrypoint(parse_argv(args)?)),
+            _ => bail!("Unknown instruction {instruction}"),
+        }
+    }
+}
+
+/// Steps of a Bentofile along with their lines, skipping blank lines and `#` comments.
+fn parse(contents: &str) -> eyre::Result<Vec<(&str, Step)>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let step = line
+                .parse()
+                .wrap_err_with(|| format!("Invalid step on line {number}"))?;
+            Ok((line, step))
+        })
+        .collect()
+}
+
+/// Splits an image name into its repository and tag, which defaults to `latest`.
+fn split_tag(name: &str) -> (&str, &str) {
+    match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+        _ => (name, "latest"),
+    }
+}
+
+/// Absolute path inside the image, where relative paths start at the WORKDIR so far.
+fn image_path(config: &ImageConfig, path: &str) -> eyre::Result<PathBuf> {
+    let path = Path::new(config.working_dir.as_deref().unwrap_or("/")).join(path);
+    let is_inside_root = path.components().all(|component| {
+        matches!(
+            component,
+            Component::RootDir | Component::CurDir | Component::Normal(_)
+        )
+    });
+    if !is_inside_root {
+        bail!("Path {} should not contain ..", path.display());
+    }
+    Ok(path)
+}
+
+fn staged_path(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn set_env(config: &mut ImageConfig, name: &str, value: &str) {
+    let env = config.env.get_or_insert_with(Vec::new);
+    let prefix = format!("{name}=");
+    env.retain(|var| !var.starts_with(&prefix));
+    env.push(format!("{name}={value}"));
+}
+
+/// Adds a directory to the store as a layer, by way of the same tar that an OCI image layout
+/// would hold, so that the layer keeps its digest when exported.
+fn add_layer(dir: &Path, tar_path: &Path, exclude: &[&str]) -> eyre::Result<String> {
+    export::write_layer(dir, BufWriter::new(File::create(tar_path)?), exclude)?;
+    let hex = export::file_sha256(tar_path)?;
+    if store::layer_dir(&hex)?.exists() {
+        println!("Layer {} is in the store already", &hex[..SHORT_ID_LEN]);
+    }
+    image::unpack_layer(&format!("sha256:{hex}"), None, || Ok(File::open(tar_path)?))?;
+    Ok(hex)
+}
+
+/// Fills an empty directory with the files of a layer and adds it to the store. Layers that
+/// come out the same get the same digest, so unchanged steps reuse the layer in the store.
+fn add_staged_layer(fill: impl FnOnce(&Path) -> eyre::Result<()>) -> eyre::Result<String> {
+    let work_dir = store::tmp_layer_dir("build")?;
+    let root = work_dir.join(STAGING_DIR_NAME);
+    fs::create_dir_all(&root)?;
+    let result = fill(&root).and_then(|()| add_layer(&root, &work_dir.join(LAYER_TAR_NAME), &[]));
+    store::remove_layer(&work_dir)?;
+    result
+}
+
+/// A host binary along with the libraries it links to.
+fn binary_paths(binary: &str) -> eyre::Result<HashSet<PathBuf>> {
+    let binary = match binary.contains('/') {
+        true => PathBuf::from(binary),
+        false => {
+            let path = env::var("PATH").unwrap_or_else(|_| image::DEFAULT_PATH.to_owned());
+            image::find_in_path(binary, &path)
+                .ok_or_else(|| eyre!("Binary {binary} not found in PATH {path}"))?
+        }
+    };
+    if !binary.is_absolute() {
+        bail!("Binary {} should be an absolute path", binary.display());
+    }
+    let mut paths = child::get_library_paths(&binary.to_string_lossy())?;
+    paths.insert(binary);
+    debug!("Binary and libraries: {paths:?}");
+    Ok(paths)
+}
+
+/// The source of a COPY step, which has to be inside the build context.
+fn context_source(context: &Path, source: &Path) -> eyre::Result<PathBuf> {
+    let source = context
+        .join(source)
+        .canonicalize()
+        .wrap_err_with(|| format!("COPY source {} not found", source.display()))?;
+    if !source.starts_with(context) {
+        bail!(
+            "COPY source {} is outside the build context",
+            source.display()
+        );
+    }
+    Ok(source)
+}
+
+fn copy_from_context(root: &Path, source: &Path, destination: &Path) -> eyre::Result<()> {
+    let destination = staged_path(root, destination);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    overlay::copy_layer(source, &destination)
+        .wrap_err_with(|| format!("Failed to copy {}", source.display()))
+}
+
+/// Digest of the paths, modes, owners, and contents of the host files that a step copies, which
+/// its cache key has to cover as well. Copying follows the given paths if they are symlinks, but
+/// not the symlinks inside a directory.
+fn fingerprint(paths: &[PathBuf]) -> eyre::Result<String> {
+    let mut manifest = String::new();
+    for path in paths {
+        add_to_fingerprint(path, &fs::metadata(path)?, &mut manifest)?;
+    }
+    Ok(image::sha256_hex(manifest.as_bytes()))
+}
+
+fn add_to_fingerprint(path: &Path, metadata: &Metadata, manifest: &mut String) -> eyre::Result<()> {
+    let file_type = metadata.file_type();
+    let contents = if file_type.is_file() {
+        export::file_sha256(path)?
+    } else if file_type.is_symlink() {
+        fs::read_link(path)?.to_string_lossy().into_owned()
+    } else {
+        String::new()
+    };
+    writeln!(
+        manifest,
+        "{} {:o} {}:{} {contents}",
+        path.display(),
+        metadata.mode(),
+        metadata.uid(),
+        metadata.gid()
+    )?;
+    if file_type.is_dir() {
+        for name in export::sorted_names(path)? {
+            let child = path.join(name);
+            add_to_fingerprint(&child, &fs::symlink_metadata(&child)?, manifest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reuses the layer of a step from an earlier build, or adds it with `add_layer` and caches it.
+/// The layer is cached by everything the step may depend on: the layers below it, the config so
+/// far, the step itself, and the fingerprint of what it copies from the host.
+fn cached_step(
+    layers: &[String],
+    config: &ImageConfig,
+    step: &Step,
+    inputs: Option<String>,
+    add_layer: impl FnOnce() -> eyre::Result<String>,
+) -> eyre::Result<String> {
+    let key = image::sha256_hex(&serde_json::to_vec(&json!({
+        "layers": layers,
+        "config": config,
+        "step": format!("{step:?}"),
+        "inputs": inputs,
+    }))?);
+    if let Some(hex) = store::cached_layer(&key)? {
+        println!("Using cached layer {}", &hex[..SHORT_ID_LEN]);
+        return Ok(hex);
+    }
+    let hex = add_layer()?;
+    store::cache_layer(&key, &hex)?;
+    Ok(hex)
+}
+
+/// Runs a command in a container on top of the layers so far, and adds what it changed as a
+/// layer.
+fn run_step(
+    layers: &[String],
+    config: &ImageConfig,
+    argv: &[String],
+    allow_no_cgroup: bool,
+) -> eyre::Result<String> {
+    if layers.is_empty() {
+        bail!("RUN needs an earlier step to provide its command");
+    }
+
+    let image = Image {
+        layers: layers
+            .iter()
+            .map(|hex| store::layer_dir(hex))
+            .collect::<eyre::Result<_>>()?,
+        config: ImageConfig {
+            entrypoint: None,
+            cmd: Some(argv.to_vec()),
+            ..config.clone()
+        },
+    };
+    let id = format!("build-{}", state::generate_id()?);
+    let mut flags = vec!["--id", &id];
+    if allow_no_cgroup {
+        flags.push("--allow-no-cgroup");
+    }
+    let run_args = RunArgs::parse_from(flags)?;
+
+    let container_dir = state::container_dir(&id)?;
+    let result =
+        container::run(run_args, Some(image), store::lock_shared()?).and_then(|exit_code| {
+            match exit_code {
+                0 => add_layer(
+                    &overlay::upper_dir(&id)?,
+                    &container_dir.join(LAYER_TAR_NAME),
+                    &export::RUNTIME_PATHS,
+                ),
+                _ => Err(eyre!("RUN exited with code {exit_code}")),
+            }
+        });
+    // Whether or not the container ran, nothing refers to it after the build
+    if container_dir.exists() {
+        store::remove_layer(&container_dir)?;
+    }
+    result
+}
+
+/// Builds the image of a Bentofile, step by step, into the store or an OCI image layout.
+pub fn build(
+    BuildArgs {
+        file,
+        tag,
+        output,
+        allow_no_cgroup,
+    }: BuildArgs,
+) -> eyre::Result<()> {
+    let (repository, tag) = split_tag(&tag);
+    if repository.is_empty() || tag.is_empty() {
+        bail!("Invalid image name {repository}:{tag}");
+    }
+    let contents =
+        fs::read_to_string(&file).wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+    let steps = parse(&contents).wrap_err_with(|| format!("Invalid {}", file.display()))?;
+    let file = file.canonicalize()?;
+    let context = file.parent().unwrap_or(Path::new("/"));
+
+    // Held until the image record refers to the layers of the build
+    let _store_lock = store::lock_shared()?;
+    let mut layers = vec![];
+    let mut config = ImageConfig::default();
+    for (i, (line, step)) in steps.iter().enumerate() {
+        println!("Step {}/{}: {line}", i + 1, steps.len());
+        match step {
+            Step::CopyBinary(binary) => {
+                let paths = binary_paths(binary)?;
+                let mut sorted_paths = paths.iter().cloned().collect::<Vec<_>>();
+                sorted_paths.sort();
+                let inputs = fingerprint(&sorted_paths)?;
+                layers.push(cached_step(&layers, &config, step, Some(inputs), || {
+                    add_staged_layer(|root| child::mkdir_and_copy(root, &paths))
+                })?);
+            }
+            Step::Copy {
+                source,
+                destination,
+            } => {
+                let source = context_source(context, source)?;
+                let mut destination_path = image_path(&config, destination)?;
+                if destination.ends_with('/') {
+                    if let Some(name) = source.file_name() {
+                        destination_path.push(name);
+                    }
+                }
+                let inputs = fingerprint(slice::from_ref(&source))?;
+                layers.push(cached_step(&layers, &config, step, Some(inputs), || {
+                    add_staged_layer(|root| copy_from_context(root, &source, &destination_path))
+                })?);
+            }
+            Step::Mkdir(path) => {
+                let path = image_path(&config, path)?;
+                layers.push(cached_step(&layers, &config, step, None, || {
+                    add_staged_layer(|root| Ok(fs::create_dir_all(staged_path(root, &path))?))
+                })?);
+            }
+            // Steps that only change the config have no layer to cache, but the config is part of
+            // the cache keys of the steps after them
+            Step::Env { name, value } => set_env(&mut config, name, value),
+            Step::Workdir(path) => {
+                config.working_dir =
+                    Some(image_path(&config, path)?.to_string_lossy().into_owned());
+            }
+            Step::User(user) => config.user = Some(user.clone()),
+            Step::Run(argv) => {
+                layers.push(cached_step(&layers, &config, step, None, || {
+                    run_step(&layers, &config, argv, allow_no_cgroup)
+                })?);
+            }
+            Step::Entrypoint(argv) => config.entrypoint = Some(argv.clone()),
+        }
+    }
+
+    let diff_ids = layers
+        .iter()
+        .map(|hex| format!("sha256:{hex}"))
+        .collect::<Vec<_>>();
+    let id = image::sha256_hex(&export::config_json(&config, &diff_ids)?);
+    match output {
+        Some(dir) => {
+            let layer_dirs = layers
+                .iter()
+                .map(|hex| store::layer_dir(hex))
+                .collect::<eyre::Result<Vec<_>>>()?;
+            export::write_layout(&dir, tag, &layer_dirs, &config, &[])?;
+        }
+        None => ImageRecord {
+            id: id.clone(),
+            names: vec![format!("{repository}:{tag}")],
+            layers,
+            config,
+        }
+        .save()?,
+    }
+    println!("{id}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parses_bentofile_skipping_comments_and_blank_lines() {
+        let steps = parse(
+            "# A comment\n\
+             \n\
+             COPY-BINARY sh\n\
+             copy app.conf /etc/\n\
+             ENV GREETING=hello world\n\
+             WORKDIR /app\n\
+             USER 1000:1000\n\
+             RUN [\"sh\", \"-c\", \"echo hi > out\"]\n\
+             ENTRYPOINT sh -c  run\n",
+        )
+        .unwrap();
+        let lines = steps.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+        assert_eq!(lines[0], "COPY-BINARY sh");
+        assert_eq!(lines.len(), 7);
+
+        assert!(matches!(&steps[0].1, Step::CopyBinary(binary) if binary == "sh"));
+        assert!(matches!(
+            &steps[1].1,
+            Step::Copy { source, destination } if source == Path::new("app.conf") && destination == "/etc/"
+        ));
+        assert!(matches!(
+            &steps[2].1,
+            Step::Env { name, value } if name == "GREETING" && value == "hello world"
+        ));
+        assert!(matches!(&steps[3].1, Step::Workdir(path) if path == "/app"));
+        assert!(matches!(&steps[4].1, Step::User(user) if user == "1000:1000"));
+        assert!(matches!(&steps[5].1, Step::Run(argv) if argv == &["sh", "-c", "echo hi > out"]));
+        assert!(matches!(&steps[6].1, Step::Entrypoint(argv) if argv == &["sh", "-c", "run"]));
+    }
+
+    #[test]
+    fn rejects_malformed_steps() {
+        for line in [
+            "FROM scratch",
+            "COPY-BINARY",
+            "COPY-BINARY sh ls",
+            "COPY app.conf",
+            "COPY a b c",
+            "MKDIR",
+            "ENV NAME",
+            "ENV =value",
+            "WORKDIR /a /b",
+            "USER",
+            "RUN",
+            "RUN []",
+            "RUN [\"sh\"",
+            "ENTRYPOINT",
+        ] {
+            assert!(line.parse::<Step>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn reports_line_of_invalid_step() {
+        let err = parse("COPY-BINARY sh\n\nBOGUS\n").unwrap_err();
+        assert!(format!("{err:#}").contains("line 3"), "{err:#}");
+    }
+
+    #[test]
+    fn checks_run_arguments_like_the_cli() {
+        assert!(RunArgs::parse_from(["--id", "build-1"]).is_ok());
+        assert!(RunArgs::parse_from(["--id", "build-1", "--nat"]).is_err());
+        assert!(RunArgs::parse_from(["--id", ".hidden"]).is_err());
+    }
+
+    #[test]
+    fn splits_tags() {
+        assert_eq!(split_tag("app"), ("app", "latest"));
+        assert_eq!(split_tag("app:1.0"), ("app", "1.0"));
+        assert_eq!(
+            split_tag("localhost:5000/app"),
+            ("localhost:5000/app", "latest")
+        );
+    }
+
+    #[test]
+    fn resolves_image_paths_from_workdir() {
+        let mut config = ImageConfig::default();
+        assert_eq!(image_path(&config, "app").unwrap(), Path::new("/app"));
+        config.working_dir = Some("/srv".to_owned());
+        assert_eq!(image_path(&config, "app").unwrap(), Path::new("/srv/app"));
+        assert_eq!(image_path(&config, "/etc").unwrap(), Path::new("/etc"));
+        assert!(image_path(&config, "../etc").is_err());
+    }
+
+    #[test]
+    fn fingerprint_follows_contents() {
+        let context = TempDir::new().unwrap();
+        let file = context.path().join("dir/app.conf");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "before").unwrap();
+        let dir = [context.path().join("dir")];
+        let before = fingerprint(&dir).unwrap();
+        assert_eq!(before, fingerprint(&dir).unwrap());
+
+        fs::write(&file, "after!").unwrap();
+        assert_ne!(before, fingerprint(&dir).unwrap());
+    }
+}
@@ -0,0 +1,289 @@
+use cgroups_rs::{
+    cgroup::Cgroup,
+    cgroup_builder::CgroupBuilder,
+    cpu::CpuController,
+    freezer::FreezerController,
+    hierarchies::{UNIFIED_MOUNTPOINT, V2},
+    memory::MemController,
+    pid::PidController,
+    ControllIdentifier, Controller, MaxValue, Subsystem,
+};
+use eyre::{bail, eyre};
+use nix::unistd::getuid;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+const CGROUP_PARENT: &str = "bento";
+
+const GIB: i64 = 1024 * 1024 * 1024;
+const KERNEL_MEMORY_LIMIT: i64 = GIB;
+const MEMORY_HARD_LIMIT: i64 = GIB;
+const MAX_PROCESSES: i64 = 64;
+const CPU_SHARES: u64 = 256;
+const CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Hard memory limit in bytes
+    pub memory: i64,
+    pub pids_limit: i64,
+    /// CPU bandwidth in number of CPUs, unlimited if `None`
+    pub cpus: Option<f64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            memory: MEMORY_HARD_LIMIT,
+            pids_limit: MAX_PROCESSES,
+            cpus: None,
+        }
+    }
+}
+
+fn cpu_quota_us(cpus: f64) -> i64 {
+    (cpus * CPU_PERIOD_US as f64) as i64
+}
+
+/// Controllers that bento sets limits with.
+const CONTROLLERS: [&str; 3] = ["cpu", "memory", "pids"];
+
+/// Each container gets its own cgroup nested under a shared `bento` parent. Root creates the
+/// parent directly under the cgroup root, while other users create it inside the subtree delegated
+/// to them, e.g. `user.slice/user-UID.slice/user@UID.service` by systemd.
+///
+/// Returns `None` if the current user has no delegated subtree.
+pub fn cgroup_path(id: &str) -> eyre::Result<Option<String>> {
+    if getuid().is_root() {
+        return Ok(Some(format!("{CGROUP_PARENT}/{id}")));
+    }
+
+    Ok(delegated_root()?.map(|root| format!("{root}/{CGROUP_PARENT}/{id}")))
+}
+
+/// Finds the topmost ancestor of the current cgroup that is owned by the current user. Processes
+/// can only be moved between cgroups below it, so a delegated subtree elsewhere, like systemd's
+/// `user@UID.service` when bento runs in a login session, is only reported.
+fn delegated_root() -> eyre::Result<Option<String>> {
+    let uid = getuid();
+    let mut path = PathBuf::from(current_cgroup_path()?);
+    let mut delegated_root = None;
+    loop {
+        let dir = Path::new(UNIFIED_MOUNTPOINT).join(&path);
+        let is_owned = fs::metadata(&dir).is_ok_and(|metadata| metadata.uid() == uid.as_raw());
+        if !is_owned {
+            break;
+        }
+        delegated_root = Some(path.clone());
+        if !path.pop() {
+            break;
+        }
+    }
+
+    match delegated_root {
+        Some(root) => {
+            let root = root
+                .to_str()
+                .ok_or_else(|| eyre!("Cgroup path {} is not valid UTF-8", root.display()))?
+                .to_owned();
+            debug!("Found delegated cgroup subtree {root}");
+            Ok(Some(root))
+        }
+        None => {
+            if let Some(service) = user_service_root() {
+                warn!(
+                    "Cgroup subtree {service} is delegated to the current user, but bento runs \
+                     outside of it, so it cannot move containers there. Start bento inside it, \
+                     e.g. with `systemd-run --user --scope`"
+                );
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// The subtree that systemd delegates to the user manager, `user@UID.service`, if the current
+/// user may enable controllers in it.
+fn user_service_root() -> Option<String> {
+    let uid = getuid();
+    let service = format!("user.slice/user-{uid}.slice/user@{uid}.service");
+    let subtree_control = Path::new(UNIFIED_MOUNTPOINT)
+        .join(&service)
+        .join("cgroup.subtree_control");
+    let is_delegated =
+        fs::metadata(&subtree_control).is_ok_and(|metadata| metadata.uid() == uid.as_raw());
+    debug!("Cgroup subtree {service} delegated: {is_delegated}");
+    is_delegated.then_some(service)
+}
+
+/// Controllers that can be enabled below the parent of `path`'s own parent, i.e. below the cgroup
+/// root or the delegated subtree.
+fn available_controllers(path: &str) -> eyre::Result<Vec<String>> {
+    let root = Path::new(path)
+        .parent()
+        .and_then(Path::parent)
+        .ok_or_else(|| eyre!("Cgroup path {path} should have a parent"))?;
+    let controllers_path = Path::new(UNIFIED_MOUNTPOINT)
+        .join(root)
+        .join("cgroup.controllers");
+    let controllers = fs::read_to_string(controllers_path)?;
+
+    let mut available = vec![];
+    for controller in CONTROLLERS {
+        if controllers.split_whitespace().any(|c| c == controller) {
+            available.push(controller.to_owned());
+        } else {
+            warn!("Controller {controller} is not delegated, so its limits will not apply");
+        }
+    }
+    Ok(available)
+}
+
+pub fn build_cgroup(path: &str, limits: &Limits) -> eyre::Result<Cgroup> {
+    let controllers = available_controllers(path)?;
+    debug!("Enabling controllers {controllers:?}");
+
+    let mut cpu = CgroupBuilder::new(path)
+        .memory()
+        .kernel_memory_limit(KERNEL_MEMORY_LIMIT)
+        .memory_hard_limit(limits.memory)
+        .done()
+        .pid()
+        .maximum_number_of_processes(MaxValue::Value(limits.pids_limit))
+        .done()
+        .cpu()
+        .shares(CPU_SHARES);
+    if let Some(cpus) = limits.cpus {
+        cpu = cpu.quota(cpu_quota_us(cpus)).period(CPU_PERIOD_US);
+    }
+
+    let cgroup = cpu
+        .done()
+        .set_specified_controllers(controllers)
+        .build(Box::new(V2::new()))?;
+
+    // Reload with every subsystem, since the freezer and moving tasks do not depend on which
+    // controllers are enabled
+    load_cgroup(cgroup.path())
+}
+
+pub fn load_cgroup(path: &str) -> eyre::Result<Cgroup> {
+    let cgroup = Cgroup::load(Box::new(V2::new()), path);
+    if !cgroup.exists() {
+        bail!("Cgroup {path} does not exist");
+    }
+    Ok(cgroup)
+}
+
+/// Path of the cgroup of the current process, relative to the cgroup v2 root.
+pub fn current_cgroup_path() -> eyre::Result<String> {
+    let contents = fs::read_to_string("/proc/self/cgroup")?;
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.trim_start_matches('/').to_owned())
+        .ok_or_else(|| eyre!("Current process is not in a cgroup v2 hierarchy"))
+}
+
+pub fn cgroup_dir(cgroup: &Cgroup) -> PathBuf {
+    Path::new(UNIFIED_MOUNTPOINT).join(cgroup.path())
+}
+
+fn controller<'a, T>(cgroup: &'a Cgroup) -> eyre::Result<&'a T>
+where
+    T: Controller + ControllIdentifier,
+    &'a T: From<&'a Subsystem>,
+{
+    cgroup.controller_of().ok_or_else(|| {
+        eyre!(
+            "Cgroup {path} has no {controller:?} controller",
+            path = cgroup.path(),
+            controller = T::controller_type()
+        )
+    })
+}
+
+pub fn memory_usage(cgroup: &Cgroup) -> eyre::Result<i64> {
+    let current = fs::read_to_string(cgroup_dir(cgroup).join("memory.current"))?;
+    Ok(current.trim().parse()?)
+}
+
+pub fn set_memory_limit(cgroup: &Cgroup, memory: i64) -> eyre::Result<()> {
+    debug!(
+        "Setting memory limit of cgroup {} to {memory}",
+        cgroup.path()
+    );
+    controller::<MemController>(cgroup)?.set_limit(memory)?;
+    Ok(())
+}
+
+pub fn set_pids_limit(cgroup: &Cgroup, pids_limit: i64) -> eyre::Result<()> {
+    debug!(
+        "Setting PIDs limit of cgroup {} to {pids_limit}",
+        cgroup.path()
+    );
+    controller::<PidController>(cgroup)?.set_pid_max(MaxValue::Value(pids_limit))?;
+    Ok(())
+}
+
+pub fn set_cpus(cgroup: &Cgroup, cpus: f64) -> eyre::Result<()> {
+    debug!(
+        "Setting CPU bandwidth of cgroup {} to {cpus}",
+        cgroup.path()
+    );
+    controller::<CpuController>(cgroup)?
+        .set_cfs_quota_and_period(Some(cpu_quota_us(cpus)), Some(CPU_PERIOD_US))?;
+    Ok(())
+}
+
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+const FREEZE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Reads the `frozen` key of `cgroup.events`, which the kernel only flips once every process in
+/// the cgroup has actually stopped (or resumed), unlike `cgroup.freeze` which reflects the request.
+fn is_frozen(cgroup: &Cgroup) -> eyre::Result<bool> {
+    let events = fs::read_to_string(cgroup_dir(cgroup).join("cgroup.events"))?;
+    let frozen = events
+        .lines()
+        .find_map(|line| line.strip_prefix("frozen "))
+        .ok_or_else(|| eyre!("cgroup.events of {} has no frozen key", cgroup.path()))?;
+    Ok(frozen.trim() == "1")
+}
+
+fn wait_for_frozen(cgroup: &Cgroup, frozen: bool) -> eyre::Result<()> {
+    let start = Instant::now();
+    while is_frozen(cgroup)? != frozen {
+        if start.elapsed() > FREEZE_TIMEOUT {
+            bail!(
+                "Timed out waiting for cgroup {path} to become {state}",
+                path = cgroup.path(),
+                state = if frozen { "frozen" } else { "thawed" }
+            );
+        }
+        thread::sleep(FREEZE_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+pub fn freeze(cgroup: &Cgroup) -> eyre::Result<()> {
+    debug!("Freezing cgroup {}", cgroup.path());
+    controller::<FreezerController>(cgroup)?.freeze()?;
+    wait_for_frozen(cgroup, true)?;
+    debug!("Cgroup {} is frozen", cgroup.path());
+    Ok(())
+}
+
+pub fn thaw(cgroup: &Cgroup) -> eyre::Result<()> {
+    debug!("Thawing cgroup {}", cgroup.path());
+    controller::<FreezerController>(cgroup)?.thaw()?;
+    wait_for_frozen(cgroup, false)?;
+    debug!("Cgroup {} is thawed", cgroup.path());
+    Ok(())
+}
@@ -1,13 +1,22 @@
-use crate::{container_config::ContainerConfig, sockets};
+use crate::{
+    container_config::ContainerConfig,
+    image::{self, Image},
+    mounts::{self, MountConfig},
+    namespaces::{Namespaces, NetworkMode},
+    network::{self, NetworkConfig},
+    sockets, ulimit, user_network,
+};
 use capctl::{bounding, Cap, CapState};
-use eyre::bail;
+use eyre::{eyre, WrapErr};
 use lddtree::DependencyAnalyzer;
 use nix::{
+    errno::Errno,
     mount::{mount, umount2, MntFlags, MsFlags},
-    sched::{clone, unshare, CloneFlags},
+    sched::{clone, CloneFlags},
     sys::signal::Signal,
     unistd::{
-        chdir, execve, pivot_root, setgroups, sethostname, setresgid, setresuid, Gid, Pid, Uid,
+        chdir, execve, getuid, pivot_root, setgroups, sethostname, setresgid, setresuid, Gid, Pid,
+        Uid,
     },
 };
 use seccompiler::{
@@ -30,24 +39,35 @@ use tracing::{debug, error, info, warn};
 
 const STACK_SIZE: usize = 1024 * 1024;
 
-pub fn clone_process(config: &ContainerConfig, socket: UnixDatagram) -> eyre::Result<Pid> {
+/// Returns the PID of the child, and whether it is in a new user namespace.
+pub fn clone_process(config: &ContainerConfig, socket: UnixDatagram) -> eyre::Result<(Pid, bool)> {
     let socket_fd = socket.into_raw_fd();
-    let cb = Box::new(|| spawn(config.clone(), socket_fd));
     let mut stack = [0; STACK_SIZE];
-
-    let flags = CloneFlags::from_iter([
-        CloneFlags::CLONE_FILES, // fd of socket must be shared to child
-        CloneFlags::CLONE_NEWCGROUP,
-        CloneFlags::CLONE_NEWIPC,
-        CloneFlags::CLONE_NEWNET,
-        CloneFlags::CLONE_NEWNS,
-        CloneFlags::CLONE_NEWPID,
-        CloneFlags::CLONE_NEWUTS,
-    ]);
-
     let signal = Signal::SIGCHLD as i32;
 
-    Ok(unsafe { clone(cb, &mut stack, flags, Some(signal)) }?)
+    let flags = CloneFlags::CLONE_FILES // fd of socket must be shared to child
+        | config.namespaces.clone_flags();
+
+    config.namespaces.with_joined_namespaces(|| {
+        let mut clone_child = |flags| {
+            debug!("Cloning child with flags {flags:?}");
+            let cb = Box::new(|| spawn(config.clone(), socket_fd));
+            unsafe { clone(cb, &mut stack, flags, Some(signal)) }
+        };
+
+        // Creating the user namespace together with the others makes it their owner, so that the
+        // child can set them up without any privileges on the host
+        match clone_child(flags | CloneFlags::CLONE_NEWUSER) {
+            Ok(pid) => Ok((pid, true)),
+            Err(err) if getuid().is_root() => {
+                warn!("User namespaces not supported: {err}");
+                Ok((clone_child(flags)?, false))
+            }
+            Err(err) => {
+                Err(err).wrap_err("Failed to create user namespace, which is needed without root")
+            }
+        }
+    })
 }
 
 fn spawn(config: ContainerConfig, socket_fd: RawFd) -> isize {
@@ -66,60 +86,127 @@ fn spawn_with_result(
     ContainerConfig {
         command,
         argv,
+        env,
         uid,
         mount_dir,
         hostname,
         commands_to_copy,
+        ulimits,
+        namespaces,
+        network,
+        mounts,
+        image,
     }: ContainerConfig,
     socket_fd: RawFd,
 ) -> eyre::Result<Infallible> {
     let socket = unsafe { UnixDatagram::from_raw_fd(socket_fd) };
 
+    let user_namespace_created = sockets::recv_bool(&socket)?;
+    if user_namespace_created {
+        // The child has all capabilities in its user namespace, but its IDs were unmapped until
+        // now, so it becomes the container's root user before creating any files
+        set_uid(Uid::from_raw(0))?;
+    }
+
+    if namespaces.net == NetworkMode::User {
+        // Bento waits for the tap device, so it has to hear about failures as well
+        let tap = user_network::create_tap();
+        sockets::send_fd(&socket, tap.as_ref().ok().copied())?;
+        tap?;
+    }
+
+    namespaces.create_time_namespace()?;
+
     if let Some(hostname) = hostname {
         sethostname(&hostname)?;
         debug!("Hostname is now {hostname}");
     }
 
-    mount_roots_and_paths(&mount_dir, &command, commands_to_copy)?;
-    switch_root(&mount_dir)?;
-
-    let user_namespace_created = create_user_namespace();
-    debug!("User namespace created {user_namespace_created}, sending to container");
-    sockets::send_bool(&socket, user_namespace_created)?;
-
-    let uid_and_gid_created = sockets::recv_bool(&socket)?;
-    if !uid_and_gid_created {
-        bail!("BUG: uid_and_gid_created should never be false");
+    mount_roots_and_paths(
+        &mount_dir,
+        &command,
+        commands_to_copy,
+        &namespaces,
+        &mounts,
+        image.as_ref(),
+        user_namespace_created,
+    )?;
+    set_up_network(&mount_dir, &namespaces.net, &network)?;
+    if let Some(working_dir) = image
+        .as_ref()
+        .and_then(|image| image.config.working_dir.as_ref())
+    {
+        fs::create_dir_all(mounts::resolve_in_root(&mount_dir, Path::new(working_dir))?)?;
+    }
+    // Only once the files in /etc and the working directory are written
+    if mounts.read_only {
+        mounts::make_root_read_only(&mount_dir)?;
     }
-    set_uid(uid)?;
+    switch_root(&mount_dir)?;
 
+    // Dropping bounding capabilities needs CAP_SETPCAP, which a non-root user no longer has
     restrict_caps()?;
+    let command = match &image {
+        Some(image) => {
+            let (uid, gid) = match uid {
+                Some(uid) => (uid, Gid::from_raw(uid.as_raw())),
+                None => image::resolve_user(image.config.user.as_deref().unwrap_or("0"))?,
+            };
+            if let Some(working_dir) = &image.config.working_dir {
+                chdir(working_dir.as_str())?;
+            }
+            set_uid_and_gid(uid, gid)?;
+
+            let path = env
+                .iter()
+                .find_map(|var| var.to_str().ok()?.strip_prefix("PATH="))
+                .unwrap_or(image::DEFAULT_PATH);
+            image::find_in_path(&command, path)
+                .ok_or_else(|| eyre!("Command {command} not found in PATH {path}"))?
+                .to_string_lossy()
+                .into_owned()
+        }
+        None => {
+            set_uid(uid.unwrap_or(Uid::from_raw(0)))?;
+            command
+        }
+    };
+
+    // Only now, so that the container's limits do not cut its setup short, e.g. a low fsize
+    // while copying libraries, or a low nofile with bento's descriptors in the shared fd table
+    ulimit::set_ulimits(&ulimits)?;
     apply_seccomp_filter()?;
 
     info!("Running command {command} with args {argv:?}");
-    Ok(execve::<_, CString>(&CString::new(command)?, &argv, &[])?)
+    Ok(execve(&CString::new(command)?, &argv, &env)?)
 }
 
-fn create_user_namespace() -> bool {
-    match unshare(CloneFlags::CLONE_NEWUSER) {
-        Ok(()) => {
-            debug!("User namespace created");
-            true
-        }
-        Err(err) => {
-            warn!("User namespaces not supported: {err}");
-            false
-        }
+fn set_up_network(new_root: &Path, net: &NetworkMode, network: &NetworkConfig) -> eyre::Result<()> {
+    // Shared and joined namespaces are already configured, and none is meant to stay empty
+    if matches!(
+        net,
+        NetworkMode::Private | NetworkMode::Bridge | NetworkMode::User
+    ) {
+        network::bring_up_loopback()?;
     }
+    network::write_etc_files(new_root, network)
 }
 
 fn set_uid(uid: Uid) -> eyre::Result<()> {
-    debug!("Setting uid as {uid}");
+    set_uid_and_gid(uid, Gid::from_raw(uid.as_raw()))
+}
+
+fn set_uid_and_gid(uid: Uid, gid: Gid) -> eyre::Result<()> {
+    debug!("Setting uid as {uid} and gid as {gid}");
 
-    let gid = Gid::from_raw(uid.as_raw());
-    setgroups(&[gid])?;
-    setresgid(gid, gid, gid)?;
-    setresuid(uid, uid, uid)?;
+    match setgroups(&[gid]) {
+        // Denied in user namespaces whose GID map was written without privileges
+        Err(Errno::EPERM) => debug!("Not allowed to set groups, keeping them"),
+        result => result?,
+    }
+    // Fails with EINVAL for IDs outside the user namespace's mapping
+    setresgid(gid, gid, gid).wrap_err_with(|| format!("Failed to set gid to {gid}"))?;
+    setresuid(uid, uid, uid).wrap_err_with(|| format!("Failed to set uid to {uid}"))?;
 
     Ok(())
 }
@@ -130,25 +217,36 @@ fn mount_at_path(path: Option<&Path>, mount_point: &Path, flags: Vec<MsFlags>) -
     Ok(())
 }
 
-fn get_library_paths(command: &str) -> eyre::Result<HashSet<PathBuf>> {
+pub fn get_library_paths(command: &str) -> eyre::Result<HashSet<PathBuf>> {
     let dependency_analyzer = DependencyAnalyzer::new("/".into());
     let dependency_tree = dependency_analyzer.analyze(command)?;
     Ok(dependency_tree
         .libraries
         .into_values()
-        .map(|library| (library.path))
+        .map(|library| library.path)
         .collect())
 }
 
-fn mkdir_and_copy(new_root: &Path, paths: &HashSet<PathBuf>) -> eyre::Result<()> {
+/// Commands along with the libraries they load, which are copied into the root.
+pub fn command_and_lib_paths(commands: &[String]) -> eyre::Result<HashSet<PathBuf>> {
+    let mut paths = commands.iter().map(PathBuf::from).collect::<HashSet<_>>();
+    for command in commands {
+        paths.extend(get_library_paths(command)?);
+    }
+    debug!("Commands and libraries: {paths:?}");
+    Ok(paths)
+}
+
+/// Copies host files to the same paths in the new root. The paths are resolved inside it, since
+/// an imported layer may have symlinks that point to the same files on the host.
+pub fn mkdir_and_copy(new_root: &Path, paths: &HashSet<PathBuf>) -> eyre::Result<()> {
     for path in paths {
-        let stripped_path = path.strip_prefix("/").unwrap_or(path);
-        if let Some(new_parent_path) = new_root.join(stripped_path).parent() {
+        let new_path = mounts::resolve_in_root(new_root, path)?;
+        if let Some(new_parent_path) = new_path.parent() {
             debug!("Creating dir {}", new_parent_path.display());
             fs::create_dir_all(new_parent_path)?;
         }
 
-        let new_path = new_root.join(stripped_path);
         debug!("Copying file {} to {}", path.display(), new_path.display());
         fs::copy(path, &new_path)?;
 
@@ -164,29 +262,37 @@ fn mount_roots_and_paths(
     new_root: &Path,
     command: &str,
     mut commands_to_copy: Vec<String>,
+    namespaces: &Namespaces,
+    mounts: &MountConfig,
+    image: Option<&Image>,
+    in_user_namespace: bool,
 ) -> eyre::Result<()> {
     mount_at_path(
         None,
         &PathBuf::from("/"),
         vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE],
     )?;
-    mount_at_path(
-        Some(new_root),
-        new_root,
-        vec![MsFlags::MS_BIND, MsFlags::MS_PRIVATE],
-    )?;
+    // Either way the new root becomes a mount point, as pivot_root needs
+    match (&mounts.overlay, mounts.root_size) {
+        (Some(overlay), _) => overlay.mount(new_root, in_user_namespace)?,
+        (None, Some(size)) => mounts::mount_root_tmpfs(new_root, size)?,
+        (None, None) => mount_at_path(
+            Some(new_root),
+            new_root,
+            vec![MsFlags::MS_BIND, MsFlags::MS_PRIVATE],
+        )?,
+    }
 
-    commands_to_copy.push(command.to_owned());
-    let mut command_and_lib_paths = commands_to_copy
-        .iter()
-        .map(PathBuf::from)
-        .collect::<HashSet<_>>();
-    for command in commands_to_copy {
-        command_and_lib_paths.extend(get_library_paths(&command)?);
+    // The command of an image is already inside its layers
+    if image.is_none() {
+        commands_to_copy.push(command.to_owned());
     }
-    debug!("Commands and libraries: {command_and_lib_paths:?}");
+    mkdir_and_copy(new_root, &command_and_lib_paths(&commands_to_copy)?)?;
 
-    mkdir_and_copy(new_root, &command_and_lib_paths)?;
+    // Volumes go last, so that they can be mounted into /dev as well
+    mounts::mount_pseudo_filesystems(new_root, namespaces, mounts)?;
+    mounts::mount_volumes(new_root, mounts)?;
+    mounts::restrict_paths(new_root, mounts)?;
 
     Ok(())
 }
@@ -381,3 +487,32 @@ fn apply_seccomp_filter() -> eyre::Result<()> {
     seccompiler::apply_filter(&filter)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn copies_inside_root_through_absolute_symlink() {
+        let host = TempDir::new().unwrap();
+        let source = host.path().join("lib/libfoo.so");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "library").unwrap();
+        let host_usr_lib = host.path().join("usr/lib");
+        fs::create_dir_all(&host_usr_lib).unwrap();
+        // A layer linking the library's directory elsewhere, as `lib -> /usr/lib` would
+        let root = TempDir::new().unwrap();
+        let layer_dir = root.path().join(host.path().strip_prefix("/").unwrap());
+        fs::create_dir_all(&layer_dir).unwrap();
+        symlink(&host_usr_lib, layer_dir.join("lib")).unwrap();
+
+        mkdir_and_copy(root.path(), &HashSet::from([source.clone()])).unwrap();
+
+        assert_eq!(fs::read_dir(&host_usr_lib).unwrap().count(), 0);
+        let copied = mounts::resolve_in_root(root.path(), &source).unwrap();
+        assert!(copied.starts_with(root.path()));
+        assert_eq!(fs::read_to_string(copied).unwrap(), "library");
+    }
+}
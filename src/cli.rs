@@ -1,22 +1,172 @@
-use clap::Parser;
-use nix::libc::uid_t;
-use std::{fs, path::PathBuf};
-use tracing::debug;
+use crate::{
+    devices::DeviceRule,
+    egress::EgressRule,
+    export::LayoutTarget,
+    image::ImageSource,
+    mounts::{Tmpfs, Volume},
+    namespaces::{NamespaceMode, NetworkMode},
+    network::HostEntry,
+    ports::PortMapping,
+    pressure::PressureTrigger,
+    state,
+    ulimit::Ulimit,
+};
+use clap::{Parser, Subcommand};
+use eyre::bail;
+use ipnet::Ipv4Net;
+use nix::{libc::uid_t, unistd::getuid};
+use std::{net::IpAddr, path::PathBuf};
 
 #[derive(Debug, Parser)]
 pub struct Args {
-    /// Command to execute inside the container
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a command inside a new container
+    Run(Box<RunArgs>),
+
+    /// Print the state of a container
+    State {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Freeze all processes of a running container
+    Pause {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Thaw all processes of a paused container
+    Resume {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Update the resource limits of a running container
+    Update(UpdateArgs),
+
+    /// Print the pressure stall events of a container
+    Events {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Copy the upper layer of a stopped container with an overlay root to a directory, to be
+    /// used as another --rootfs-lower, or write the container to an OCI image layout
+    Commit {
+        /// ID of the container
+        id: String,
+
+        /// Directory to create for the layer
+        #[clap(required_unless_present = "oci_layout")]
+        dir: Option<PathBuf>,
+
+        /// OCI image layout to write the container's root to as an image, with a layer for each
+        /// lower directory and one for the upper directory, or a single layer for a plain root
+        #[clap(long, value_name = "DIR[:TAG]", conflicts_with = "dir")]
+        oci_layout: Option<LayoutTarget>,
+    },
+
+    /// Write the root filesystem of a stopped container to a tar archive
+    Export {
+        /// ID of the container
+        id: String,
+
+        /// Archive to write
+        #[clap(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+
+    /// List the paths a container added, modified, or deleted in its root
+    Diff {
+        /// ID of the container
+        id: String,
+
+        /// Print the changes as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Build an image from a Bentofile into the local store or an OCI image layout
+    Build(BuildArgs),
+
+    /// List the images in the local store
+    Images,
+
+    /// Remove images from the local store, along with the layers no other image uses
+    Rmi {
+        /// Names or ID prefixes of the images
+        #[clap(required = true)]
+        images: Vec<String>,
+    },
+
+    /// Remove images without a name and layers that no image or container uses
+    Prune,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BuildArgs {
+    /// Bentofile to build, whose directory holds the sources of COPY steps
+    #[clap(short, long, default_value = "Bentofile")]
+    pub file: PathBuf,
+
+    /// Name of the image, optionally with a :TAG
+    #[clap(short, long)]
+    pub tag: String,
+
+    /// OCI image layout to write the image to under its tag, instead of naming it in the store
+    #[clap(short, long, value_name = "DIR")]
+    pub output: Option<PathBuf>,
+
+    /// Run the RUN steps without resource limits if no cgroup subtree is delegated to an
+    /// unprivileged user
+    #[clap(long)]
+    pub allow_no_cgroup: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RunArgs {
+    /// ID of the container, generated if not given
     #[clap(long)]
-    pub command: String,
+    pub id: Option<String>,
 
-    /// User ID to create inside the container
+    /// Command to execute inside the container, replacing the Cmd of the image with --image
     #[clap(long)]
-    pub uid: uid_t,
+    pub command: Option<String>,
 
-    // TODO: Consider creating a tempdir as default
-    /// Directory to mount as root of the container
+    /// User ID to create inside the container, defaulting to the User of the image or 0
+    #[clap(long)]
+    pub uid: Option<uid_t>,
+
+    /// Image whose layers are the bottom of an overlay root, as DIR[:TAG] for an OCI image layout,
+    /// the path of a `docker save` archive, or the name or ID of an image in the local store. Its
+    /// Entrypoint, Cmd, Env, WorkingDir, and User are the defaults
+    #[clap(long, value_name = "IMAGE")]
+    pub image: Option<ImageSource>,
+
+    /// Directory to mount as root of the container. Without it, the root is a tmpfs that is
+    /// discarded once the container exits
     #[clap(long = "mount")]
-    pub mount_dir: PathBuf,
+    pub mount_dir: Option<PathBuf>,
+
+    /// Size of the tmpfs root when no --mount is given, in bytes or with a K, M, G, or T suffix.
+    /// Defaults to 256M
+    #[clap(long, value_parser = parse_size, conflicts_with_all = ["mount_dir", "rootfs_lower", "image"])]
+    pub rootfs_size: Option<i64>,
+
+    /// Read-only lower directory of an overlay root, whose changes go to an upper directory in
+    /// the container's state directory instead. Later ones are stacked on top of earlier ones and
+    /// of the layers of --image
+    #[clap(long = "rootfs-lower", value_name = "DIR")]
+    pub rootfs_lower: Vec<PathBuf>,
+
+    /// Discard the upper directory of the overlay root once the container exits
+    #[clap(long)]
+    pub rm: bool,
 
     /// Hostname of the container
     #[clap(long)]
@@ -25,16 +175,281 @@ pub struct Args {
     /// Other commands to copy into the container
     #[clap(long = "copy")]
     pub commands_to_copy: Vec<String>,
+
+    /// Bind-mount a host path into the container, as HOST:CONTAINER[:ro|rw][,nosuid,nodev,noexec]
+    #[clap(short = 'v', long = "volume", value_name = "HOST:CONTAINER[:OPTIONS]")]
+    pub volumes: Vec<Volume>,
+
+    /// Mount a tmpfs into the container, as PATH[:OPTIONS], e.g. /tmp:size=64m,mode=1777
+    #[clap(long = "tmpfs", value_name = "PATH[:OPTIONS]")]
+    pub tmpfs: Vec<Tmpfs>,
+
+    /// Make the root filesystem of the container read-only, except for volumes, tmpfs mounts, and
+    /// the filesystems in /proc, /sys, and /dev
+    #[clap(long)]
+    pub read_only: bool,
+
+    /// Hide a path inside the container, on top of /proc/kcore, /proc/keys, /proc/timer_list,
+    /// /proc/sched_debug, and /sys/firmware
+    #[clap(long = "mask", value_name = "PATH")]
+    pub masked_paths: Vec<PathBuf>,
+
+    /// Make a path inside the container read-only, on top of /proc/sys, /proc/sysrq-trigger,
+    /// /proc/irq, and /proc/bus
+    #[clap(long = "read-only-path", value_name = "PATH")]
+    pub read_only_paths: Vec<PathBuf>,
+
+    /// Leave a default masked or read-only path as it is, or all of them with `all`
+    #[clap(long = "unmask", value_name = "PATH")]
+    pub unmasked_paths: Vec<PathBuf>,
+
+    /// PSI trigger on the container cgroup, as RESOURCE:some|full:STALL/WINDOW[:ACTION], where
+    /// RESOURCE is memory, cpu, or io, and ACTION is log (default), freeze, or kill. WINDOW is
+    /// between 500ms and 10s, and a multiple of 2s when not running as root
+    #[clap(long = "pressure-trigger", value_name = "TRIGGER")]
+    pub pressure_triggers: Vec<PressureTrigger>,
+
+    /// Run without resource limits if no cgroup subtree is delegated to an unprivileged user
+    #[clap(long)]
+    pub allow_no_cgroup: bool,
+
+    /// Additional device to allow on top of the standard ones, as TYPE MAJOR:MINOR ACCESS, e.g.
+    /// 'c 10:229 rwm'
+    #[clap(long = "device-cgroup-rule", value_name = "RULE")]
+    pub device_rules: Vec<DeviceRule>,
+
+    /// Resource limit of the container process, as NAME=SOFT[:HARD], where NAME is one of nofile,
+    /// nproc, core, as, data, stack, fsize, cpu, memlock, msgqueue, rtprio, or nice. nofile
+    /// defaults to 64
+    #[clap(long = "ulimit", value_name = "ULIMIT")]
+    pub ulimits: Vec<Ulimit>,
+
+    /// Network namespace: private (only loopback), none (no networking set up), bridge (connected
+    /// to the bento0 bridge, needs root), user (outbound TCP and UDP through a userspace stack,
+    /// works without root), host, ns:PATH to join a namespace file, or container:ID to join
+    /// another container's
+    #[clap(long, default_value = "private")]
+    pub net: NetworkMode,
+
+    /// Subnet of the bento0 bridge, whose first address is the gateway
+    #[clap(long, default_value = "10.87.0.0/16")]
+    pub subnet: Ipv4Net,
+
+    /// Masquerade traffic from the bridge, so that the container can reach beyond the host
+    #[clap(long)]
+    pub nat: bool,
+
+    /// Let the container reach services on the host's loopback address through the gateway of
+    /// --net=user, which are off limits by default
+    #[clap(long)]
+    pub allow_host_loopback: bool,
+
+    /// Nameserver for /etc/resolv.conf, defaulting to the host's non-loopback nameservers, or to
+    /// the built-in DNS forwarder with --net=user
+    #[clap(long, value_name = "IP")]
+    pub dns: Vec<IpAddr>,
+
+    /// Search domain for /etc/resolv.conf, defaulting to the host's
+    #[clap(long, value_name = "DOMAIN")]
+    pub dns_search: Vec<String>,
+
+    /// Extra /etc/hosts entry, as HOST:IP
+    #[clap(long = "add-host", value_name = "HOST:IP")]
+    pub extra_hosts: Vec<HostEntry>,
+
+    /// Publish a container port on the host, as [HOSTIP:]HOSTPORT:CONTAINERPORT[/tcp|udp]. Needs
+    /// --net=bridge or --net=user
+    #[clap(
+        short = 'p',
+        long = "publish",
+        value_name = "[HOSTIP:]HOSTPORT:CONTAINERPORT[/PROTOCOL]"
+    )]
+    pub published_ports: Vec<PortMapping>,
+
+    /// Allow egress to a destination that --deny-egress covers, as CIDR[:PORT] or all[:PORT].
+    /// CIDRs are IPv4 only, while all covers IPv6 destinations too
+    #[clap(long = "allow-egress", value_name = "CIDR[:PORT]")]
+    pub allowed_egress: Vec<EgressRule>,
+
+    /// Deny egress to a destination, as CIDR[:PORT] or all[:PORT]. CIDRs are IPv4 only, so
+    /// denying IPv6 destinations takes all. Needs --net=bridge or --net=user, and denied
+    /// connection attempts are logged
+    #[clap(long = "deny-egress", value_name = "CIDR[:PORT]")]
+    pub denied_egress: Vec<EgressRule>,
+
+    /// PID namespace: private, host, ns:PATH, or container:ID
+    #[clap(long, default_value = "private")]
+    pub pid: NamespaceMode,
+
+    /// Leave /proc empty instead of mounting a procfs of the container's PID namespace, as
+    /// --pid=host needs
+    #[clap(long)]
+    pub no_proc: bool,
+
+    /// IPC namespace: private, host, ns:PATH, or container:ID
+    #[clap(long, default_value = "private")]
+    pub ipc: NamespaceMode,
+
+    /// UTS namespace: private, host, ns:PATH, or container:ID
+    #[clap(long, default_value = "private")]
+    pub uts: NamespaceMode,
+
+    /// Create a private time namespace, where CLOCK_MONOTONIC and CLOCK_BOOTTIME start near zero
+    /// unless offsets are given
+    #[clap(long)]
+    pub time_namespace: bool,
+
+    /// Offset of CLOCK_MONOTONIC from the host, in seconds
+    #[clap(
+        long,
+        requires = "time_namespace",
+        allow_negative_numbers = true,
+        value_name = "SECONDS"
+    )]
+    pub monotonic_offset: Option<i64>,
+
+    /// Offset of CLOCK_BOOTTIME from the host, in seconds
+    #[clap(
+        long,
+        requires = "time_namespace",
+        allow_negative_numbers = true,
+        value_name = "SECONDS"
+    )]
+    pub boottime_offset: Option<i64>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct UpdateArgs {
+    /// ID of the container
+    pub id: String,
+
+    /// Hard memory limit, in bytes or with a K, M, G, or T suffix
+    #[clap(long, value_parser = parse_size)]
+    pub memory: Option<i64>,
+
+    /// Maximum number of processes
+    #[clap(long, value_parser = clap::value_parser!(i64).range(1..))]
+    pub pids_limit: Option<i64>,
+
+    /// CPU bandwidth in number of CPUs, e.g. 1.5
+    #[clap(long, value_parser = parse_cpus)]
+    pub cpus: Option<f64>,
+
+    /// Allow lowering the memory limit below the current memory usage
+    #[clap(long)]
+    pub force: bool,
+}
+
+fn parse_size(s: &str) -> Result<i64, String> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        Some((i, 't' | 'T')) => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<i64>()
+        .ok()
+        .filter(|size| *size > 0)
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {s:?}"))
+}
+
+fn parse_cpus(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|cpus| cpus.is_finite() && *cpus > 0.0)
+        .ok_or_else(|| format!("invalid number of CPUs {s:?}"))
+}
+
+impl RunArgs {
+    /// Arguments of a container that bento runs itself from an image that it passes along, with
+    /// the defaults and checks of `bento run`.
+    pub fn parse_from<'a>(flags: impl IntoIterator<Item = &'a str>) -> eyre::Result<Self> {
+        let run_args =
+            match Args::try_parse_from(["bento", "run"].into_iter().chain(flags))?.command {
+                Command::Run(run_args) => *run_args,
+                command => bail!("Parsed {command:?} instead of run arguments"),
+            };
+        run_args.validate(true)?;
+        Ok(run_args)
+    }
+
+    /// Rejects combinations of arguments that bento cannot run. `has_image` tells whether an
+    /// image comes along with the arguments, either through `--image` or from bento itself.
+    fn validate(&self, has_image: bool) -> eyre::Result<()> {
+        if let Some(id) = &self.id {
+            state::validate_id(id)?;
+        }
+        if self.command.is_none() && !has_image {
+            bail!("--command is needed unless the command comes from --image");
+        }
+        if self.pid == NamespaceMode::Host && !self.no_proc {
+            bail!("--pid=host needs --no-proc, as a fresh /proc would be the host's procfs");
+        }
+        if self.uts != NamespaceMode::Private && self.hostname.is_some() {
+            bail!("--hostname needs a private UTS namespace, as it would rename the host");
+        }
+        if self.net == NetworkMode::Bridge && !getuid().is_root() {
+            bail!("--net=bridge needs root to create links on the host");
+        }
+        if self.net != NetworkMode::Bridge && self.nat {
+            bail!("--nat needs --net=bridge");
+        }
+        if self.net != NetworkMode::User && self.allow_host_loopback {
+            bail!("--allow-host-loopback needs --net=user");
+        }
+        let is_managed_network = matches!(self.net, NetworkMode::Bridge | NetworkMode::User);
+        if !self.published_ports.is_empty() && !is_managed_network {
+            bail!("--publish needs --net=bridge or --net=user");
+        }
+        if !self.allowed_egress.is_empty() && self.denied_egress.is_empty() {
+            bail!("--allow-egress only makes exceptions to --deny-egress");
+        }
+        if !self.denied_egress.is_empty() && !is_managed_network {
+            bail!("--deny-egress needs --net=bridge or --net=user");
+        }
+        if self.rm && self.rootfs_lower.is_empty() && !has_image {
+            bail!("--rm needs --rootfs-lower or --image, as it discards the upper directory");
+        }
+        Ok(())
+    }
 }
 
 impl Args {
     /// # Errors
     ///
-    /// Returns an error if parsing `Args` fails, or creating `mount_dir` fails.
+    /// Returns an error if parsing `Args` fails or a container ID is invalid.
     pub fn try_parse_and_validate() -> eyre::Result<Self> {
         let args = Args::try_parse()?;
-        debug!("Creating dir {}", args.mount_dir.display());
-        fs::create_dir_all(&args.mount_dir)?;
+        match &args.command {
+            Command::Run(run_args) => run_args.validate(run_args.image.is_some())?,
+            Command::State { id }
+            | Command::Pause { id }
+            | Command::Resume { id }
+            | Command::Events { id }
+            | Command::Commit { id, .. }
+            | Command::Export { id, .. }
+            | Command::Diff { id, .. } => {
+                state::validate_id(id)?;
+            }
+            Command::Update(update_args) => {
+                state::validate_id(&update_args.id)?;
+                if update_args.memory.is_none()
+                    && update_args.pids_limit.is_none()
+                    && update_args.cpus.is_none()
+                {
+                    bail!("At least one of --memory, --pids-limit, or --cpus must be given");
+                }
+            }
+            Command::Build(build_args) => {
+                if !build_args.file.is_file() {
+                    bail!("Bentofile {} not found", build_args.file.display());
+                }
+            }
+            Command::Images | Command::Rmi { .. } | Command::Prune => {}
+        }
 
         Ok(args)
     }
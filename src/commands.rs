@@ -0,0 +1,31 @@
+use crate::{
+    build,
+    cli::{Args, Command},
+    container, diff, export, overlay, pressure, state, store,
+};
+
+pub fn run(Args { command }: Args) -> eyre::Result<()> {
+    match command {
+        Command::Run(run_args) => container::start(*run_args),
+        Command::State { id } => state::print(&id),
+        Command::Pause { id } => container::pause(&id),
+        Command::Resume { id } => container::resume(&id),
+        Command::Update(update_args) => container::update(update_args),
+        Command::Events { id } => pressure::print_events(&id),
+        Command::Commit {
+            id,
+            dir,
+            oci_layout,
+        } => match (dir, oci_layout) {
+            (_, Some(target)) => export::commit_layout(&id, &target),
+            (Some(dir), None) => overlay::commit(&id, &dir),
+            (None, None) => unreachable!("clap requires a directory or --oci-layout"),
+        },
+        Command::Export { id, output } => export::export(&id, &output),
+        Command::Diff { id, json } => diff::print_diff(&id, json),
+        Command::Build(build_args) => build::build(build_args),
+        Command::Images => store::print_images(),
+        Command::Rmi { images } => store::remove_images(&images),
+        Command::Prune => store::prune(),
+    }
+}
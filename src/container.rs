@@ -1,68 +1,210 @@
 use crate::{
-    child, cli::Args, container_config::ContainerConfig, sockets,
-    uid_gid_mapping::read_and_write_uid_and_gid_mappings,
+    cgroup::{self, Limits},
+    child,
+    cli::{RunArgs, UpdateArgs},
+    container_config::ContainerConfig,
+    devices::{self, DeviceRule, DEFAULT_DEVICE_RULES},
+    diff,
+    egress::EgressPolicy,
+    image::{self, Image},
+    mounts::{self, MountConfig, DEFAULT_MASKED_PATHS, DEFAULT_READ_ONLY_PATHS, DEFAULT_ROOT_SIZE},
+    namespaces::{Namespaces, NetworkMode, TimeOffsets},
+    network::{self, NetworkConfig},
+    overlay::Overlay,
+    ports::{self, PortProxy},
+    pressure::{PressureMonitor, PressureTrigger},
+    sockets,
+    state::{self, ContainerState, Status},
+    store::{self, StoreLock},
+    uid_gid_mapping::{write_uid_and_gid_mappings, HostIds},
+    ulimit::{self, Ulimit},
+    user_network::{self, UserNetwork},
 };
-use cgroups_rs::{cgroup::Cgroup, cgroup_builder::CgroupBuilder};
-use eyre::{bail, WrapErr};
+use cgroups_rs::cgroup::Cgroup;
+use eyre::{bail, eyre, WrapErr};
 use nix::{
-    libc::uid_t,
-    sys::wait::{waitpid, WaitStatus},
-    unistd::Pid,
+    sys::{
+        signal::{kill, Signal},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{chown, getuid, Pid},
 };
-use std::{net::Shutdown, os::unix::net::UnixDatagram, path::PathBuf};
-use tracing::{debug, error};
+use std::{
+    fs, mem,
+    net::{IpAddr, Ipv4Addr, Shutdown},
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::{fs::MetadataExt, net::UnixDatagram},
+    },
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, warn};
 
 #[derive(Debug)]
 pub struct Container {
+    id: String,
     child_pid: Pid,
+    /// Whether the child was reaped, after which its PID may belong to another process
+    child_exited: bool,
     socket: UnixDatagram,
-    cgroup: Cgroup,
+    cgroup: Option<Cgroup>,
+    pressure_monitor: Option<PressureMonitor>,
+    ip_address: Option<Ipv4Addr>,
+    user_network: Option<UserNetwork>,
+    port_proxy: Option<PortProxy>,
+    /// Whether a running state was saved, which has to be marked as stopped
+    state_saved: bool,
 }
 
-const CGROUP_NAME: &str = "bento";
+/// Deletes the cgroup if setting up the container fails before the child is cloned into it.
+struct CgroupGuard(Option<Cgroup>);
+
+impl Drop for CgroupGuard {
+    fn drop(&mut self) {
+        if let Some(cgroup) = self.0.take() {
+            let _ = cgroup.delete();
+        }
+    }
+}
 
 impl Container {
     pub fn new(
-        command: String,
-        uid: uid_t,
-        mount_dir: PathBuf,
-        hostname: Option<String>,
-        commands_to_copy: Vec<String>,
+        id: String,
+        config: ContainerConfig,
+        pressure_triggers: Vec<PressureTrigger>,
+        allow_no_cgroup: bool,
+        device_rules: Vec<DeviceRule>,
     ) -> eyre::Result<Self> {
-        let config = ContainerConfig::new(command, uid, mount_dir, hostname, commands_to_copy)?;
+        let cgroup_path = cgroup::cgroup_path(&id)?;
+        if cgroup_path.is_none() {
+            if !allow_no_cgroup {
+                bail!(
+                    "No cgroup subtree is delegated to the current user, run bento inside one, \
+                     e.g. with `systemd-run --user --scope`, or pass --allow-no-cgroup to run \
+                     without resource limits"
+                );
+            }
+            if !pressure_triggers.is_empty() {
+                bail!("Pressure triggers need a cgroup, but none is delegated to the current user");
+            }
+            // Printed regardless of the log level, since the container is not contained as expected
+            eprintln!(
+                "WARNING: no cgroup subtree is delegated to the current user, so container {id} \
+                 runs WITHOUT memory, PID, or CPU limits"
+            );
+        }
 
-        let cgroup = build_cgroup(CGROUP_NAME)?;
-        debug!("Created cgroup {CGROUP_NAME}");
+        // Before anything is created, so that a port conflict leaves nothing to clean up
+        let mut published_ports = ports::bind(&config.network.published_ports)?;
 
-        let cgroup_pid = u64::try_from(Pid::this().as_raw()).unwrap().into();
-        cgroup.add_task_by_tgid(cgroup_pid)?;
-        debug!("Added PID to cgroup");
+        let limits = Limits::default();
+        let mut cgroup_guard = CgroupGuard(None);
+        if let Some(cgroup_path) = &cgroup_path {
+            let cgroup = cgroup_guard
+                .0
+                .insert(cgroup::build_cgroup(cgroup_path, &limits)?);
+            debug!("Created cgroup {cgroup_path}");
+            // The device filter is attached to the cgroup, so it goes away together with it
+            apply_device_rules(cgroup, device_rules)?;
+        }
 
-        set_fd_limit()?;
-        debug!("Set file descriptor limit");
+        // The child inherits the cgroup at clone time, which also makes it the root of the child's
+        // cgroup namespace. Bento itself moves back afterwards, so that freezing or killing the
+        // container cgroup never affects it.
+        let cgroup_pid = u64::try_from(Pid::this().as_raw()).unwrap();
+        let original_cgroup = match &cgroup_guard.0 {
+            Some(cgroup) => {
+                let original_cgroup = cgroup::load_cgroup(&cgroup::current_cgroup_path()?)?;
+                cgroup.add_task_by_tgid(cgroup_pid.into())?;
+                debug!("Added PID to cgroup");
+                Some(original_cgroup)
+            }
+            None => None,
+        };
 
         let (container_socket, child_socket) = UnixDatagram::pair()?;
 
-        let child_pid = child::clone_process(&config, child_socket)?;
+        let clone_result = child::clone_process(&config, child_socket);
+        if let Some(original_cgroup) = original_cgroup {
+            original_cgroup.add_task_by_tgid(cgroup_pid.into())?;
+            debug!("Moved PID back to original cgroup");
+        }
+        let (child_pid, user_namespace_created) = clone_result?;
         debug!("Created container with child PID {child_pid}");
 
-        let user_namespace_created = sockets::recv_bool(&container_socket)?;
-        debug!("User namespace created {user_namespace_created}, received");
+        // From here on, dropping the container kills the child and tears down whatever was set up
+        // for it, as the child would otherwise wait for bento forever
+        let mut container = Self {
+            id: id.clone(),
+            child_pid,
+            child_exited: false,
+            socket: container_socket,
+            cgroup: cgroup_guard.0.take(),
+            pressure_monitor: None,
+            ip_address: None,
+            user_network: None,
+            port_proxy: None,
+            state_saved: false,
+        };
+
         if user_namespace_created {
-            read_and_write_uid_and_gid_mappings(child_pid)?;
+            let (root_uid, root_gid) = write_uid_and_gid_mappings(child_pid)?;
+            if let Some(overlay) = &config.mounts.overlay {
+                overlay.chown(root_uid, root_gid)?;
+            }
         }
 
+        if config.namespaces.net == NetworkMode::Bridge {
+            let ip_address = network::connect_to_bridge(&id, child_pid, &config.network)?;
+            container.ip_address = Some(ip_address);
+            container.port_proxy =
+                PortProxy::start(id.clone(), ip_address, mem::take(&mut published_ports))?;
+        }
+
+        ulimit::raise_hard_limits(child_pid, &config.ulimits)?;
+
         debug!("Notifying child that UID and GID mappings are ready");
-        sockets::send_bool(&container_socket, true)?;
+        sockets::send_bool(&container.socket, user_namespace_created)?;
+
+        if config.namespaces.net == NetworkMode::User {
+            let tap_fd = sockets::recv_fd(&container.socket)?
+                .ok_or_else(|| eyre!("Child failed to create the tap device"))?;
+            // The child left the fd open in the shared fd table for bento to take over
+            let tap = unsafe { OwnedFd::from_raw_fd(tap_fd) };
+            container.user_network = Some(UserNetwork::start(
+                id.clone(),
+                tap,
+                mem::take(&mut published_ports),
+                config.network.egress.clone(),
+                config.network.allow_host_loopback,
+            )?);
+        }
 
-        Ok(Self {
+        let mut state = ContainerState::new(
+            id.clone(),
             child_pid,
-            socket: container_socket,
-            cgroup,
-        })
+            cgroup_path.clone(),
+            config.mount_dir,
+            limits,
+        );
+        state.ip_address = container.ip_address;
+        if let Some(overlay) = &config.mounts.overlay {
+            state.rootfs_lower.clone_from(&overlay.lower);
+        }
+        state.image_config = config.image.as_ref().map(|image| image.config.clone());
+        state.save()?;
+        container.state_saved = true;
+        debug!("Container {id} is running");
+
+        if let Some(cgroup_path) = cgroup_path {
+            container.pressure_monitor =
+                PressureMonitor::start(id.clone(), cgroup_path, pressure_triggers)?;
+        }
+
+        Ok(container)
     }
 
-    pub fn wait_for_child(&mut self) -> eyre::Result<()> {
+    pub fn wait_for_child(&mut self) -> eyre::Result<i32> {
         debug!(
             "Waiting for child PID {child_pid} to finish",
             child_pid = self.child_pid
@@ -70,10 +212,17 @@ impl Container {
         let wait_status = waitpid(self.child_pid, None)?;
         match wait_status {
             WaitStatus::Exited(_, exit_code) => {
+                self.child_exited = true;
                 if exit_code != 0 {
                     error!("Child process exited with code {exit_code}");
                 }
-                Ok(())
+                Ok(exit_code)
+            }
+            // The child can be killed from outside, e.g. by a pressure trigger
+            WaitStatus::Signaled(_, signal, _) => {
+                self.child_exited = true;
+                error!("Child process was killed by signal {signal:?}");
+                Ok(128 + signal as i32)
             }
             _ => {
                 bail!("Unexpected wait status from child: {wait_status:?}");
@@ -81,69 +230,422 @@ impl Container {
         }
     }
 
-    pub fn destroy(self) -> eyre::Result<()> {
+    pub fn destroy(mut self) -> eyre::Result<()> {
         debug!("Destroying container");
 
         self.socket.shutdown(Shutdown::Both)?;
         debug!("Socket shut down");
 
-        let cgroup_pid = u64::try_from(Pid::this().as_raw()).unwrap().into();
-        self.cgroup.remove_task_by_tgid(cgroup_pid)?;
-        debug!("Removed PID from cgroup");
+        self.tear_down()
+    }
+
+    /// Kills the child unless it exited, and removes everything set up for it. Each resource is
+    /// taken before it is removed, so that dropping the container after a failed `destroy` only
+    /// retries the rest.
+    fn tear_down(&mut self) -> eyre::Result<()> {
+        if !self.child_exited {
+            debug!("Killing child PID {child_pid}", child_pid = self.child_pid);
+            let _ = kill(self.child_pid, Signal::SIGKILL);
+            let _ = waitpid(self.child_pid, None);
+            self.child_exited = true;
+        }
+
+        if let Some(pressure_monitor) = self.pressure_monitor.take() {
+            // A failed monitor does not keep the rest of the container from being torn down
+            if let Err(err) = pressure_monitor.stop() {
+                error!("{err:#}");
+            }
+        }
+
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.delete()?;
+            debug!("Cgroup deleted");
+        }
+
+        if let Some(port_proxy) = self.port_proxy.take() {
+            port_proxy.stop()?;
+        }
+
+        if let Some(user_network) = self.user_network.take() {
+            user_network.stop()?;
+        }
+
+        if self.ip_address.take().is_some() {
+            network::disconnect_from_bridge(&self.id)?;
+        }
 
-        self.cgroup.delete()?;
-        debug!("Cgroup deleted");
+        if mem::take(&mut self.state_saved) {
+            ContainerState::modify(&self.id, |state| {
+                state.status = Status::Stopped;
+                Ok(())
+            })?;
+            debug!("Container {id} is stopped", id = self.id);
+        }
 
         Ok(())
     }
 }
 
-const GIB: i64 = 1024 * 1024 * 1024;
-const KERNEL_MEMORY_LIMIT: i64 = GIB;
-const MEMORY_HARD_LIMIT: i64 = GIB;
-const MAX_PROCESSES: i64 = 64;
-const CPU_SHARES: u64 = 256;
-
-fn build_cgroup(name: &str) -> eyre::Result<Cgroup> {
-    use cgroups_rs::{hierarchies::V2, MaxValue};
-    Ok(CgroupBuilder::new(name)
-        .memory()
-        .kernel_memory_limit(KERNEL_MEMORY_LIMIT)
-        .memory_hard_limit(MEMORY_HARD_LIMIT)
-        .done()
-        .pid()
-        .maximum_number_of_processes(MaxValue::Value(MAX_PROCESSES))
-        .done()
-        .cpu()
-        .shares(CPU_SHARES)
-        .done()
-        .build(Box::new(V2::new()))?)
-}
-
-const FD_LIMIT: u64 = 64;
-
-fn set_fd_limit() -> eyre::Result<()> {
-    use rlimit::{setrlimit, Resource};
-    setrlimit(Resource::NOFILE, FD_LIMIT, FD_LIMIT)?;
+impl Drop for Container {
+    fn drop(&mut self) {
+        if let Err(err) = self.tear_down() {
+            error!("Failed to clean up container {id}: {err:#}", id = self.id);
+        }
+    }
+}
+
+/// Loading and attaching BPF programs needs privileges in the initial user namespace, so device
+/// rules are only applied when running as root.
+fn apply_device_rules(cgroup: &Cgroup, extra_rules: Vec<DeviceRule>) -> eyre::Result<()> {
+    if !getuid().is_root() {
+        if !extra_rules.is_empty() {
+            bail!("Device cgroup rules can only be applied when running as root");
+        }
+        debug!("Not running as root, skipping device filter");
+        return Ok(());
+    }
+
+    let mut rules = DEFAULT_DEVICE_RULES.to_vec();
+    rules.extend(extra_rules);
+    devices::apply_device_rules(&cgroup::cgroup_dir(cgroup), &rules)
+}
+
+pub fn start(run_args: RunArgs) -> eyre::Result<()> {
+    // Before loading the image, whose layers nothing refers to until its record is saved
+    let store_lock = store::lock_shared()?;
+    let image = run_args.image.as_ref().map(image::load).transpose()?;
+    run(run_args, image, store_lock)?;
     Ok(())
 }
 
-pub fn start(
-    Args {
+/// Runs a container until its command exits, and returns the exit code of the command, or 128
+/// plus the number of the signal that killed it. The lock on the store is released once the
+/// state of the container records the layers it stacks.
+pub fn run(
+    RunArgs {
+        id,
         command,
         uid,
+        image: _,
         mount_dir,
+        rootfs_size,
+        rootfs_lower,
+        rm,
         hostname,
         commands_to_copy,
-    }: Args,
-) -> eyre::Result<()> {
+        volumes,
+        tmpfs,
+        read_only,
+        masked_paths,
+        read_only_paths,
+        unmasked_paths,
+        pressure_triggers,
+        allow_no_cgroup,
+        device_rules,
+        ulimits,
+        net,
+        subnet,
+        nat,
+        allow_host_loopback,
+        dns,
+        dns_search,
+        extra_hosts,
+        published_ports,
+        allowed_egress,
+        denied_egress,
+        pid,
+        no_proc,
+        ipc,
+        uts,
+        time_namespace,
+        monotonic_offset,
+        boottime_offset,
+    }: RunArgs,
+    image: Option<Image>,
+    store_lock: StoreLock,
+) -> eyre::Result<i32> {
     debug!("Container PID: {}", Pid::this());
 
-    let mut container = Container::new(command, uid, mount_dir, hostname, commands_to_copy)
-        .wrap_err("Error creating container")?;
-    container.wait_for_child()?;
+    let id = match id {
+        Some(id) => id,
+        None => state::generate_id()?,
+    };
+    if let Ok(state) = ContainerState::load(&id) {
+        if state.status != Status::Stopped {
+            bail!("Container {id} already exists and is {:?}", state.status);
+        }
+        if state.stale {
+            remove_leftovers(&state);
+        }
+    }
+
+    // The userspace stack forwards DNS queries to the host's nameserver
+    let dns = if net == NetworkMode::User && dns.is_empty() {
+        vec![IpAddr::V4(user_network::DNS_ADDRESS)]
+    } else {
+        dns
+    };
+
+    let time = time_namespace.then_some(TimeOffsets {
+        monotonic: monotonic_offset,
+        boottime: boottime_offset,
+    });
+    let mut namespaces = Namespaces {
+        net,
+        pid,
+        ipc,
+        uts,
+        time,
+    };
+    namespaces.resolve_containers()?;
+
+    // The layers of an image go below any other lower directories
+    let lower = image
+        .iter()
+        .flat_map(|image| image.layers.iter().cloned())
+        .chain(rootfs_lower)
+        .collect::<Vec<_>>();
+    // Declared before the container, so that the root is cleaned up after the container is
+    // destroyed, whether it ran or failed to start
+    let mut root_guard = RootGuard {
+        overlay: None,
+        discard: rm,
+        ephemeral_mount_dir: None,
+    };
+    if !lower.is_empty() {
+        root_guard.overlay = Some(Overlay::create(&id, lower)?);
+    }
+    let overlay = root_guard.overlay.clone();
+    // Without a mount directory, the root is mounted over an empty directory in the state
+    // directory, and is a tmpfs unless it is an overlay
+    let (mount_dir, root_size) = match mount_dir {
+        Some(mount_dir) if mount_dir.exists() => {
+            // Bento only hands directories it creates to the container's root user
+            if overlay.is_none() {
+                check_mount_dir_owner(&mount_dir)?;
+            }
+            (mount_dir, None)
+        }
+        Some(mount_dir) => {
+            create_mount_dir(&mount_dir)?;
+            (mount_dir, None)
+        }
+        None => {
+            let mount_dir = state::rootfs_dir(&id)?;
+            root_guard.ephemeral_mount_dir = Some(mount_dir.clone());
+            create_mount_dir(&mount_dir)?;
+            let root_size = rootfs_size.unwrap_or(DEFAULT_ROOT_SIZE);
+            (mount_dir, overlay.is_none().then_some(root_size))
+        }
+    };
+
+    let config = ContainerConfig::new(
+        command,
+        uid,
+        mount_dir.clone(),
+        hostname,
+        commands_to_copy,
+        Ulimit::with_defaults(ulimits),
+        namespaces,
+        NetworkConfig {
+            dns,
+            dns_search,
+            extra_hosts,
+            subnet,
+            nat,
+            allow_host_loopback,
+            published_ports,
+            egress: EgressPolicy {
+                allow: allowed_egress,
+                deny: denied_egress,
+            },
+        },
+        MountConfig {
+            volumes,
+            tmpfs,
+            read_only,
+            masked_paths: mounts::with_defaults(
+                &DEFAULT_MASKED_PATHS,
+                masked_paths,
+                &unmasked_paths,
+            ),
+            read_only_paths: mounts::with_defaults(
+                &DEFAULT_READ_ONLY_PATHS,
+                read_only_paths,
+                &unmasked_paths,
+            ),
+            proc: !no_proc,
+            overlay,
+            root_size,
+        },
+        image,
+    )?;
+    // A tmpfs root is gone by the time it could be compared with the snapshot
+    if root_size.is_none() {
+        if let Err(err) = diff::take_snapshot(&id, &config) {
+            // Printed regardless of the log level, since `bento diff` will fail without a reason
+            eprintln!(
+                "WARNING: failed to take a snapshot of container {id}, so `bento diff` cannot \
+                 compare its root: {err:#}"
+            );
+        }
+    }
+    let mut container =
+        Container::new(id, config, pressure_triggers, allow_no_cgroup, device_rules)
+            .wrap_err("Error creating container")?;
+    drop(store_lock);
+    let exit_code = container.wait_for_child()?;
 
     debug!("Cleaning up container...");
     container.destroy()?;
+    root_guard.clean_up()?;
+    Ok(exit_code)
+}
+
+/// Removes the overlay's directories and the mount directory that bento created for a container,
+/// when it stops or fails to start.
+struct RootGuard {
+    overlay: Option<Overlay>,
+    /// Whether to discard the upper layer as well
+    discard: bool,
+    ephemeral_mount_dir: Option<PathBuf>,
+}
+
+impl RootGuard {
+    fn clean_up(&mut self) -> eyre::Result<()> {
+        if let Some(overlay) = self.overlay.take() {
+            overlay.clean_up(self.discard)?;
+        }
+        if let Some(mount_dir) = self.ephemeral_mount_dir.take() {
+            if mount_dir.exists() {
+                fs::remove_dir(&mount_dir)
+                    .wrap_err_with(|| format!("Failed to remove {}", mount_dir.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RootGuard {
+    fn drop(&mut self) {
+        if let Err(err) = self.clean_up() {
+            error!("Failed to clean up the root of a container: {err:#}");
+        }
+    }
+}
+
+/// Removes what a killed bento left behind for a container, which would get in the way of a new
+/// one with the same ID.
+fn remove_leftovers(state: &ContainerState) {
+    let id = &state.id;
+    warn!("Container {id} was left behind by a killed bento, removing what remains of it");
+    if let Ok(cgroup) = state.cgroup() {
+        if let Err(err) = cgroup.delete() {
+            warn!("Failed to delete the cgroup of container {id}: {err}");
+        }
+    }
+    if state.ip_address.is_some() {
+        if let Err(err) = network::disconnect_from_bridge(id) {
+            warn!("Failed to disconnect container {id} from the bridge: {err:#}");
+        }
+    }
+}
+
+/// Creates a mount directory owned by the container's root user, who sets up the root
+/// filesystem in it.
+fn create_mount_dir(mount_dir: &Path) -> eyre::Result<()> {
+    debug!("Creating dir {}", mount_dir.display());
+    fs::create_dir_all(mount_dir)?;
+    let host_ids = HostIds::load()?;
+    chown(mount_dir, Some(host_ids.uid(0)), Some(host_ids.gid(0)))
+        .wrap_err_with(|| format!("Failed to chown {}", mount_dir.display()))?;
+    Ok(())
+}
+
+/// The container's root user has to own an existing mount directory to set up a plain root in
+/// it, and bento does not change the owner of directories it did not create.
+fn check_mount_dir_owner(mount_dir: &Path) -> eyre::Result<()> {
+    let owner = fs::metadata(mount_dir)?.uid();
+    let root_uid = HostIds::load()?.uid(0);
+    if owner != root_uid.as_raw() {
+        bail!(
+            "Mount directory {} is owned by UID {owner}, but the container's root user is UID \
+             {root_uid} on the host, chown it or give a directory that does not exist yet",
+            mount_dir.display()
+        );
+    }
+    Ok(())
+}
+
+pub fn pause(id: &str) -> eyre::Result<()> {
+    ContainerState::modify(id, |state| {
+        if state.status != Status::Running {
+            bail!("Container {id} is {:?}, not running", state.status);
+        }
+
+        let cgroup = state.cgroup()?;
+        cgroup::freeze(&cgroup)?;
+
+        state.status = Status::Paused;
+        Ok(())
+    })?;
+    debug!("Container {id} is paused");
+    Ok(())
+}
+
+pub fn resume(id: &str) -> eyre::Result<()> {
+    ContainerState::modify(id, |state| {
+        if state.status != Status::Paused {
+            bail!("Container {id} is {:?}, not paused", state.status);
+        }
+
+        let cgroup = state.cgroup()?;
+        cgroup::thaw(&cgroup)?;
+
+        state.status = Status::Running;
+        Ok(())
+    })?;
+    debug!("Container {id} is resumed");
+    Ok(())
+}
+
+pub fn update(
+    UpdateArgs {
+        id,
+        memory,
+        pids_limit,
+        cpus,
+        force,
+    }: UpdateArgs,
+) -> eyre::Result<()> {
+    let limits = ContainerState::modify(&id, |state| {
+        if state.status == Status::Stopped {
+            bail!("Container {id} is stopped");
+        }
+
+        let cgroup = state.cgroup()?;
+
+        if let Some(memory) = memory {
+            let usage = cgroup::memory_usage(&cgroup)?;
+            if memory < usage && !force {
+                bail!(
+                    "Memory limit {memory} is below the current usage {usage} of container {id}, \
+                     use --force to set it anyway"
+                );
+            }
+            cgroup::set_memory_limit(&cgroup, memory)?;
+            state.limits.memory = memory;
+        }
+        if let Some(pids_limit) = pids_limit {
+            cgroup::set_pids_limit(&cgroup, pids_limit)?;
+            state.limits.pids_limit = pids_limit;
+        }
+        if let Some(cpus) = cpus {
+            cgroup::set_cpus(&cgroup, cpus)?;
+            state.limits.cpus = Some(cpus);
+        }
+        Ok(state.limits.clone())
+    })?;
+    debug!("Updated limits of container {id} to {limits:?}");
     Ok(())
 }
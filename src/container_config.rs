@@ -1,37 +1,90 @@
+use crate::{
+    image::Image, mounts::MountConfig, namespaces::Namespaces, network::NetworkConfig,
+    ulimit::Ulimit,
+};
+use eyre::bail;
 use nix::{libc::uid_t, unistd::Uid};
 use std::{ffi::CString, path::PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct ContainerConfig {
+    /// Path of the executable, looked up in PATH inside the container if it comes from an image
     pub command: String,
     pub argv: Vec<CString>,
-    pub uid: Uid,
+    pub env: Vec<CString>,
+    /// Taken from the image or 0 if `None`
+    pub uid: Option<Uid>,
     pub mount_dir: PathBuf,
     pub hostname: Option<String>,
     pub commands_to_copy: Vec<String>,
+    pub ulimits: Vec<Ulimit>,
+    pub namespaces: Namespaces,
+    pub network: NetworkConfig,
+    pub mounts: MountConfig,
+    pub image: Option<Image>,
 }
 
 impl ContainerConfig {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        command: String,
-        uid: uid_t,
+        command: Option<String>,
+        uid: Option<uid_t>,
         mount_dir: PathBuf,
         hostname: Option<String>,
         commands_to_copy: Vec<String>,
+        ulimits: Vec<Ulimit>,
+        namespaces: Namespaces,
+        network: NetworkConfig,
+        mounts: MountConfig,
+        image: Option<Image>,
     ) -> eyre::Result<ContainerConfig> {
-        let argv = command
-            .split_ascii_whitespace()
+        let command_args = command.map(|command| {
+            command
+                .split_ascii_whitespace()
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        });
+        // Like with Docker, a command replaces the image's Cmd but not its Entrypoint
+        let (args, env) = match &image {
+            Some(image) => {
+                let config = &image.config;
+                let mut args = config.entrypoint.clone().unwrap_or_default();
+                args.extend(
+                    command_args
+                        .or_else(|| config.cmd.clone())
+                        .unwrap_or_default(),
+                );
+                (args, config.env.clone().unwrap_or_default())
+            }
+            None => (command_args.unwrap_or_default(), vec![]),
+        };
+        let Some(command) = args.first().cloned() else {
+            bail!("No command given, and the image has neither an Entrypoint nor a Cmd");
+        };
+
+        let argv = args
+            .into_iter()
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let env = env
+            .into_iter()
             .map(CString::new)
             .collect::<Result<Vec<_>, _>>()?;
-        let uid = Uid::from_raw(uid);
+        let uid = uid.map(Uid::from_raw);
 
         Ok(Self {
             command,
             argv,
+            env,
             uid,
             mount_dir,
             hostname,
             commands_to_copy,
+            ulimits,
+            namespaces,
+            network,
+            mounts,
+            image,
         })
     }
 }
@@ -0,0 +1,459 @@
+use eyre::{bail, eyre, WrapErr};
+use nix::{errno::Errno, libc};
+use std::{
+    ffi::CStr,
+    fs::File,
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    str::FromStr,
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum DeviceType {
+    Block,
+    Char,
+}
+
+impl DeviceType {
+    /// Values of `BPF_DEVCG_DEV_*`
+    fn bpf_value(self) -> i32 {
+        match self {
+            DeviceType::Block => 1,
+            DeviceType::Char => 2,
+        }
+    }
+}
+
+// Values of `BPF_DEVCG_ACC_*`
+const ACCESS_MKNOD: i32 = 1;
+const ACCESS_READ: i32 = 2;
+const ACCESS_WRITE: i32 = 4;
+const ACCESS_ALL: i32 = ACCESS_MKNOD | ACCESS_READ | ACCESS_WRITE;
+
+/// A device cgroup rule, written as `TYPE MAJOR:MINOR ACCESS` like in cgroup v1's
+/// `devices.allow`, e.g. `c 10:229 rwm`. `TYPE` can be `a` for all devices, and `MAJOR` or `MINOR`
+/// can be `*` for any number.
+#[derive(Debug, Clone)]
+pub struct DeviceRule {
+    device_type: Option<DeviceType>,
+    major: Option<u32>,
+    minor: Option<u32>,
+    access: i32,
+}
+
+fn parse_device_number(s: &str) -> eyre::Result<Option<u32>> {
+    if s == "*" {
+        return Ok(None);
+    }
+
+    let number = s
+        .parse::<u32>()
+        .wrap_err_with(|| format!("Invalid device number {s}"))?;
+    if i32::try_from(number).is_err() {
+        bail!("Device number {s} is too large");
+    }
+    Ok(Some(number))
+}
+
+impl FromStr for DeviceRule {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let [type_str, numbers_str, access_str] = s
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|vec: Vec<_>| {
+                eyre!(
+                    "Device cgroup rule {s} should have 3 parts, but has {len}",
+                    len = vec.len()
+                )
+            })?;
+
+        let device_type = match type_str {
+            "a" => None,
+            "b" => Some(DeviceType::Block),
+            "c" => Some(DeviceType::Char),
+            _ => bail!("Device type {type_str} should be a, b, or c"),
+        };
+        let (major_str, minor_str) = numbers_str
+            .split_once(':')
+            .ok_or_else(|| eyre!("Device numbers {numbers_str} should be MAJOR:MINOR"))?;
+        let major = parse_device_number(major_str)?;
+        let minor = parse_device_number(minor_str)?;
+
+        let mut access = 0;
+        for c in access_str.chars() {
+            access |= match c {
+                'r' => ACCESS_READ,
+                'w' => ACCESS_WRITE,
+                'm' => ACCESS_MKNOD,
+                _ => bail!("Device access {access_str} should only contain r, w, and m"),
+            };
+        }
+
+        Ok(Self {
+            device_type,
+            major,
+            minor,
+            access,
+        })
+    }
+}
+
+const fn char_rule(major: u32, minor: Option<u32>) -> DeviceRule {
+    DeviceRule {
+        device_type: Some(DeviceType::Char),
+        major: Some(major),
+        minor,
+        access: ACCESS_ALL,
+    }
+}
+
+pub const DEFAULT_DEVICE_RULES: [DeviceRule; 8] = [
+    char_rule(1, Some(3)), // /dev/null
+    char_rule(1, Some(5)), // /dev/zero
+    char_rule(1, Some(7)), // /dev/full
+    char_rule(1, Some(8)), // /dev/random
+    char_rule(1, Some(9)), // /dev/urandom
+    char_rule(5, Some(0)), // /dev/tty
+    char_rule(5, Some(2)), // /dev/ptmx
+    char_rule(136, None),  // /dev/pts/*
+];
+
+/// A raw `struct bpf_insn`, with the destination register in the low nibble of `regs`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const BPF_LDX_MEM_W: u8 = 0x61;
+const BPF_ALU64_AND_K: u8 = 0x57;
+const BPF_ALU64_RSH_K: u8 = 0x77;
+const BPF_ALU64_MOV_K: u8 = 0xb7;
+const BPF_ALU64_MOV_X: u8 = 0xbf;
+const BPF_JMP_JNE_K: u8 = 0x55;
+const BPF_JMP_JNE_X: u8 = 0x5d;
+const BPF_EXIT: u8 = 0x95;
+
+const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+/// Compiles rules into a `BPF_PROG_TYPE_CGROUP_DEVICE` program that returns 1 to allow an access
+/// if any rule matches, and 0 to deny it otherwise.
+///
+/// The program receives a `struct bpf_cgroup_dev_ctx` in r1, which is loaded as:
+/// - r2: device type
+/// - r3: requested access
+/// - r4: major number
+/// - r5: minor number
+fn compile(rules: &[DeviceRule]) -> Vec<BpfInsn> {
+    let mut program = vec![
+        insn(BPF_LDX_MEM_W, 2, 1, 0, 0),
+        insn(BPF_ALU64_AND_K, 2, 0, 0, 0xffff),
+        insn(BPF_LDX_MEM_W, 3, 1, 0, 0),
+        insn(BPF_ALU64_RSH_K, 3, 0, 0, 16),
+        insn(BPF_LDX_MEM_W, 4, 1, 4, 0),
+        insn(BPF_LDX_MEM_W, 5, 1, 8, 0),
+    ];
+
+    for rule in rules {
+        // Each check jumps past the rest of the block when it fails, so the jump offsets are
+        // patched in once the block size is known
+        let mut block = vec![];
+        if let Some(device_type) = rule.device_type {
+            block.push(insn(BPF_JMP_JNE_K, 2, 0, 0, device_type.bpf_value()));
+        }
+        if rule.access != ACCESS_ALL {
+            // The requested access must be a subset of the allowed access
+            block.push(insn(BPF_ALU64_MOV_X, 1, 3, 0, 0));
+            block.push(insn(BPF_ALU64_AND_K, 1, 0, 0, rule.access));
+            block.push(insn(BPF_JMP_JNE_X, 1, 3, 0, 0));
+        }
+        if let Some(major) = rule.major {
+            block.push(insn(BPF_JMP_JNE_K, 4, 0, 0, major as i32));
+        }
+        if let Some(minor) = rule.minor {
+            block.push(insn(BPF_JMP_JNE_K, 5, 0, 0, minor as i32));
+        }
+        block.push(insn(BPF_ALU64_MOV_K, 0, 0, 0, 1));
+        block.push(insn(BPF_EXIT, 0, 0, 0, 0));
+
+        let block_len = block.len();
+        for (i, insn) in block.iter_mut().enumerate() {
+            if matches!(insn.code, BPF_JMP_JNE_K | BPF_JMP_JNE_X) {
+                insn.off = (block_len - i - 1) as i16;
+            }
+        }
+        program.extend(block);
+    }
+
+    program.push(insn(BPF_ALU64_MOV_K, 0, 0, 0, 0));
+    program.push(insn(BPF_EXIT, 0, 0, 0, 0));
+    program
+}
+
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_ATTACH: libc::c_int = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+const BPF_F_ALLOW_MULTI: u32 = 2;
+
+/// Prefix of the `BPF_PROG_LOAD` variant of `union bpf_attr`.
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// Prefix of the `BPF_PROG_ATTACH` variant of `union bpf_attr`.
+#[repr(C)]
+struct BpfProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> nix::Result<RawFd> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    Errno::result(ret).map(|fd| fd as RawFd)
+}
+
+const LICENSE: &CStr = c"MIT";
+const LOG_SIZE: usize = 64 * 1024;
+
+fn load_program(program: &[BpfInsn]) -> eyre::Result<OwnedFd> {
+    let mut attr = BpfProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+        insn_cnt: program.len().try_into()?,
+        insns: program.as_ptr() as u64,
+        license: LICENSE.as_ptr() as u64,
+        expected_attach_type: BPF_CGROUP_DEVICE,
+        ..Default::default()
+    };
+    attr.prog_name[..6].copy_from_slice(b"bento\0");
+
+    match bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        Err(err) => {
+            // Load again with the verifier log enabled to explain the failure
+            let mut log = vec![0u8; LOG_SIZE];
+            attr.log_level = 1;
+            attr.log_size = LOG_SIZE as u32;
+            attr.log_buf = log.as_mut_ptr() as u64;
+            if let Ok(fd) = bpf(BPF_PROG_LOAD, &mut attr) {
+                return Ok(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+
+            let log = CStr::from_bytes_until_nul(&log)
+                .map(CStr::to_string_lossy)
+                .unwrap_or_default();
+            bail!("Failed to load device filter: {err}\n{log}")
+        }
+    }
+}
+
+/// Attaches a device filter to a cgroup. Ancestor cgroups' filters still apply, so a device must
+/// be allowed by all of them.
+pub fn apply_device_rules(cgroup_dir: &Path, rules: &[DeviceRule]) -> eyre::Result<()> {
+    let program = compile(rules);
+    debug!(
+        "Loading device filter with {len} instructions for rules {rules:?}",
+        len = program.len()
+    );
+    let program_fd = load_program(&program)?;
+
+    let cgroup_file = File::open(cgroup_dir)
+        .wrap_err_with(|| format!("Failed to open cgroup {}", cgroup_dir.display()))?;
+    let mut attr = BpfProgAttachAttr {
+        target_fd: cgroup_file.as_raw_fd().try_into()?,
+        attach_bpf_fd: program_fd.as_raw_fd().try_into()?,
+        attach_type: BPF_CGROUP_DEVICE,
+        attach_flags: BPF_F_ALLOW_MULTI,
+    };
+    bpf(BPF_PROG_ATTACH, &mut attr).wrap_err("Failed to attach device filter")?;
+    debug!("Attached device filter to {}", cgroup_dir.display());
+
+    // The attachment holds its own reference to the program, so the fd can be closed
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of instructions that load the context before the first rule
+    const PROLOGUE_LEN: usize = 6;
+
+    /// Runs a compiled program on a device access, supporting only the instructions it emits.
+    fn run(
+        program: &[BpfInsn],
+        device_type: DeviceType,
+        access: i32,
+        major: u32,
+        minor: u32,
+    ) -> u64 {
+        let ctx = [
+            (access << 16 | device_type.bpf_value()) as u32,
+            major,
+            minor,
+        ];
+        let mut regs = [0u64; 11];
+        let mut pc = 0;
+        loop {
+            let BpfInsn {
+                code,
+                regs: reg_nibbles,
+                off,
+                imm,
+            } = program[pc];
+            let (dst, src) = (
+                usize::from(reg_nibbles & 0xf),
+                usize::from(reg_nibbles >> 4),
+            );
+            pc += 1;
+            match code {
+                BPF_LDX_MEM_W => {
+                    assert_eq!(src, 1);
+                    regs[dst] = u64::from(ctx[off as usize / 4]);
+                }
+                BPF_ALU64_AND_K => regs[dst] &= imm as u64,
+                BPF_ALU64_RSH_K => regs[dst] >>= imm,
+                BPF_ALU64_MOV_K => regs[dst] = imm as u64,
+                BPF_ALU64_MOV_X => regs[dst] = regs[src],
+                BPF_JMP_JNE_K if regs[dst] != imm as u64 => pc += off as usize,
+                BPF_JMP_JNE_X if regs[dst] != regs[src] => pc += off as usize,
+                BPF_JMP_JNE_K | BPF_JMP_JNE_X => {}
+                BPF_EXIT => return regs[0],
+                _ => panic!("Unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    fn rule(s: &str) -> DeviceRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn denies_everything_without_rules() {
+        let program = compile(&[]);
+        assert_eq!(program.len(), PROLOGUE_LEN + 2);
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_READ, 1, 3), 0);
+    }
+
+    #[test]
+    fn wildcard_rule_is_a_plain_allow() {
+        let program = compile(&[rule("a *:* rwm")]);
+        assert_eq!(
+            program[PROLOGUE_LEN..],
+            [
+                insn(BPF_ALU64_MOV_K, 0, 0, 0, 1),
+                insn(BPF_EXIT, 0, 0, 0, 0),
+                insn(BPF_ALU64_MOV_K, 0, 0, 0, 0),
+                insn(BPF_EXIT, 0, 0, 0, 0),
+            ]
+        );
+        assert_eq!(run(&program, DeviceType::Block, ACCESS_ALL, 8, 0), 1);
+    }
+
+    #[test]
+    fn major_only_rule_matches_any_minor() {
+        let program = compile(&[rule("c 136:* rwm")]);
+        assert_eq!(
+            program[PROLOGUE_LEN..PROLOGUE_LEN + 4],
+            [
+                insn(BPF_JMP_JNE_K, 2, 0, 3, DeviceType::Char.bpf_value()),
+                insn(BPF_JMP_JNE_K, 4, 0, 2, 136),
+                insn(BPF_ALU64_MOV_K, 0, 0, 0, 1),
+                insn(BPF_EXIT, 0, 0, 0, 0),
+            ]
+        );
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_WRITE, 136, 7), 1);
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_WRITE, 137, 7), 0);
+        assert_eq!(run(&program, DeviceType::Block, ACCESS_WRITE, 136, 7), 0);
+    }
+
+    #[test]
+    fn access_subset_rule_denies_other_access() {
+        let program = compile(&[rule("c 1:3 r")]);
+        assert_eq!(
+            program[PROLOGUE_LEN + 1..PROLOGUE_LEN + 4],
+            [
+                insn(BPF_ALU64_MOV_X, 1, 3, 0, 0),
+                insn(BPF_ALU64_AND_K, 1, 0, 0, ACCESS_READ),
+                insn(BPF_JMP_JNE_X, 1, 3, 4, 0),
+            ]
+        );
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_READ, 1, 3), 1);
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_WRITE, 1, 3), 0);
+        assert_eq!(
+            run(&program, DeviceType::Char, ACCESS_READ | ACCESS_MKNOD, 1, 3),
+            0
+        );
+    }
+
+    #[test]
+    fn falls_through_to_later_rules() {
+        let program = compile(&DEFAULT_DEVICE_RULES);
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_ALL, 1, 9), 1);
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_READ, 136, 0), 1);
+        assert_eq!(run(&program, DeviceType::Char, ACCESS_READ, 1, 1), 0);
+        assert_eq!(run(&program, DeviceType::Block, ACCESS_READ, 1, 3), 0);
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule = rule("b 8:* rw");
+        assert_eq!(rule.device_type, Some(DeviceType::Block));
+        assert_eq!(rule.major, Some(8));
+        assert_eq!(rule.minor, None);
+        assert_eq!(rule.access, ACCESS_READ | ACCESS_WRITE);
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for rule in [
+            "c 1:3",
+            "c 1:3 rwm extra",
+            "x 1:3 rwm",
+            "c 1 rwm",
+            "c one:3 rwm",
+            "c 1:-3 rwm",
+            "c 4294967295:3 rwm",
+            "c 1:3 rwx",
+        ] {
+            assert!(rule.parse::<DeviceRule>().is_err(), "{rule}");
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use crate::{
+    child,
+    container_config::ContainerConfig,
+    export::{self, RUNTIME_PATHS},
+    overlay,
+    state::{self, ContainerState},
+};
+use eyre::{bail, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, Metadata},
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+const SNAPSHOT_FILE_NAME: &str = "snapshot.json";
+
+/// What a path of a plain root was, as far as telling whether it changed goes
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Entry {
+    Directory,
+    File {
+        size: u64,
+        /// Only for the files that bento copies into the root, whose modification time is the
+        /// time of the copy. Reading every file of the root would be slow, and fail on the ones
+        /// bento cannot read.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+        /// In nanoseconds, for the other files
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<i64>,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+    Other,
+}
+
+impl Entry {
+    fn new(path: &Path, metadata: &Metadata, hash: bool) -> eyre::Result<Self> {
+        let file_type = metadata.file_type();
+        Ok(if file_type.is_dir() {
+            Self::Directory
+        } else if file_type.is_file() {
+            Self::File {
+                size: metadata.len(),
+                sha256: hash.then(|| export::file_sha256(path)).transpose()?,
+                mtime: (!hash).then(|| metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec()),
+            }
+        } else if file_type.is_symlink() {
+            Self::Symlink {
+                target: fs::read_link(path)?,
+            }
+        } else {
+            Self::Other
+        })
+    }
+
+    /// Whether a path is still what the snapshot recorded, comparing the contents of a file if
+    /// the snapshot has their hash, or else its modification time
+    fn matches(&self, path: &Path, metadata: &Metadata) -> eyre::Result<bool> {
+        let hash = matches!(
+            self,
+            Self::File {
+                sha256: Some(_),
+                ..
+            }
+        );
+        Ok(*self == Self::new(path, metadata, hash)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    /// Absolute path inside the container
+    path: PathBuf,
+    kind: ChangeKind,
+}
+
+impl Change {
+    fn new(path: &Path, kind: ChangeKind) -> Self {
+        Self {
+            path: Path::new("/").join(path),
+            kind,
+        }
+    }
+}
+
+fn is_runtime_path(path: &Path) -> bool {
+    RUNTIME_PATHS
+        .iter()
+        .any(|runtime| path == Path::new(runtime))
+}
+
+/// Records the commands and libraries that the child is about to copy into the root, and what
+/// a plain root holds before the container starts, since nothing else would tell them apart
+/// from what the container writes.
+pub fn take_snapshot(id: &str, config: &ContainerConfig) -> eyre::Result<()> {
+    let dir = state::container_dir(id)?;
+    let path = dir.join(SNAPSHOT_FILE_NAME);
+    // Left by an earlier run of a container with the same ID
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    let mut entries = BTreeMap::new();
+    if config.mounts.overlay.is_none() {
+        walk(&config.mount_dir, Path::new(""), &mut entries)?;
+    }
+
+    for runtime in RUNTIME_PATHS {
+        if let Some(parent) = Path::new(runtime).parent() {
+            if !parent.as_os_str().is_empty() {
+                entries.entry(parent.to_owned()).or_insert(Entry::Directory);
+            }
+        }
+    }
+    let mut commands = config.commands_to_copy.clone();
+    if config.image.is_none() {
+        commands.push(config.command.clone());
+    }
+    for source in child::command_and_lib_paths(&commands)? {
+        let path = source.strip_prefix("/").unwrap_or(&source);
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.as_os_str().is_empty() {
+                entries
+                    .entry(ancestor.to_owned())
+                    .or_insert(Entry::Directory);
+            }
+        }
+        // Copying follows symlinks, so the copy is a file with the contents of their target
+        let metadata = fs::metadata(&source)?;
+        entries.insert(path.to_owned(), Entry::new(&source, &metadata, true)?);
+    }
+
+    fs::create_dir_all(&dir)?;
+    fs::write(&path, serde_json::to_string(&entries)?)?;
+    debug!(
+        "Wrote snapshot of {} paths to {}",
+        entries.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn walk(root: &Path, dir: &Path, entries: &mut BTreeMap<PathBuf, Entry>) -> eyre::Result<()> {
+    let names = export::sorted_names(&root.join(dir))
+        .wrap_err_with(|| format!("Failed to list {}", root.join(dir).display()))?;
+    for name in names {
+        let path = dir.join(name);
+        if is_runtime_path(&path) {
+            continue;
+        }
+        let source = root.join(&path);
+        let metadata = fs::symlink_metadata(&source)?;
+        let entry = Entry::new(&source, &metadata, false)
+            .wrap_err_with(|| format!("Failed to read {}", source.display()))?;
+        let is_dir = entry == Entry::Directory;
+        entries.insert(path.clone(), entry);
+        if is_dir {
+            walk(root, &path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compares a plain root with its snapshot. Directories only count as added or deleted, as
+/// their contents show up on their own.
+fn plain_root_changes(
+    state: &ContainerState,
+    snapshot: &BTreeMap<PathBuf, Entry>,
+) -> eyre::Result<Vec<Change>> {
+    let mut current = BTreeMap::new();
+    walk(&state.mount_dir, Path::new(""), &mut current)?;
+
+    let mut changes = vec![];
+    for path in current.keys() {
+        let Some(earlier) = snapshot.get(path) else {
+            changes.push(Change::new(path, ChangeKind::Added));
+            continue;
+        };
+        let source = state.mount_dir.join(path);
+        if !earlier.matches(&source, &fs::symlink_metadata(&source)?)? {
+            changes.push(Change::new(path, ChangeKind::Modified));
+        }
+    }
+    for path in snapshot.keys() {
+        if !current.contains_key(path) {
+            changes.push(Change::new(path, ChangeKind::Deleted));
+        }
+    }
+    Ok(changes)
+}
+
+/// Whether a path shows in the merged view of lower directories, top first, which stops at a
+/// whiteout or at a file or opaque directory in place of one of its parents.
+fn exists_below(lowers: &[PathBuf], path: &Path) -> bool {
+    for layer in lowers {
+        if let Ok(metadata) = fs::symlink_metadata(layer.join(path)) {
+            return !export::is_whiteout(&metadata);
+        }
+        let is_hidden = path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| {
+                let dir = layer.join(ancestor);
+                match fs::symlink_metadata(&dir) {
+                    Ok(metadata) => !metadata.is_dir() || overlay::is_opaque(&dir).unwrap_or(false),
+                    Err(_) => false,
+                }
+            });
+        if is_hidden {
+            return false;
+        }
+    }
+    false
+}
+
+/// Walks the upper layer of an overlay root: whiteouts are deletions, and anything else is a
+/// modification if it shadows a path of the lower directories, or an addition otherwise. What
+/// bento copied there is left out while it matches the snapshot.
+fn upper_changes(
+    upper: &Path,
+    lowers: &[PathBuf],
+    snapshot: &BTreeMap<PathBuf, Entry>,
+    dir: &Path,
+    changes: &mut Vec<Change>,
+) -> eyre::Result<()> {
+    for name in export::sorted_names(&upper.join(dir))? {
+        let path = dir.join(name);
+        if is_runtime_path(&path) {
+            continue;
+        }
+        let source = upper.join(&path);
+        let metadata = fs::symlink_metadata(&source)?;
+        if export::is_whiteout(&metadata) {
+            changes.push(Change::new(&path, ChangeKind::Deleted));
+            continue;
+        }
+        let existed = exists_below(lowers, &path);
+        let kind = if existed {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Added
+        };
+        if !metadata.is_dir() {
+            let is_copy = match snapshot.get(&path) {
+                Some(copied) => copied.matches(&source, &metadata)?,
+                None => false,
+            };
+            if !is_copy {
+                changes.push(Change::new(&path, kind));
+            }
+            continue;
+        }
+        changes.push(Change::new(&path, kind));
+        let change_count = changes.len();
+
+        // An opaque directory replaced the one below, whose entries are gone unless the upper
+        // layer has them again
+        if existed && overlay::is_opaque(&source)? {
+            let mut lower_names = BTreeSet::new();
+            for layer in lowers {
+                if let Ok(names) = export::sorted_names(&layer.join(&path)) {
+                    lower_names.extend(names);
+                }
+            }
+            for name in lower_names {
+                let lower_path = path.join(&name);
+                if exists_below(lowers, &lower_path)
+                    && fs::symlink_metadata(source.join(&name)).is_err()
+                {
+                    changes.push(Change::new(&lower_path, ChangeKind::Deleted));
+                }
+            }
+        }
+        upper_changes(upper, lowers, snapshot, &path, changes)?;
+        // Left in the upper layer only by bento
+        if snapshot.contains_key(&path) && changes.len() == change_count {
+            changes.pop();
+        }
+    }
+    Ok(())
+}
+
+fn load_snapshot(id: &str) -> eyre::Result<BTreeMap<PathBuf, Entry>> {
+    let path = state::container_dir(id)?.join(SNAPSHOT_FILE_NAME);
+    let contents = fs::read_to_string(&path)
+        .wrap_err_with(|| format!("No snapshot found for container {id}"))?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn changes(id: &str) -> eyre::Result<Vec<Change>> {
+    let state = ContainerState::load(id)?;
+    let mut changes = if state.rootfs_lower.is_empty() {
+        if state.mount_dir == state::rootfs_dir(id)? {
+            bail!("Container {id} has a tmpfs root, which bento cannot compare from outside");
+        }
+        plain_root_changes(&state, &load_snapshot(id)?)?
+    } else {
+        let upper = overlay::upper_dir(id)?;
+        if !upper.exists() {
+            bail!("Container {id} ran with --rm, which discarded its upper layer");
+        }
+        let snapshot = load_snapshot(id)?;
+        let lowers = state.rootfs_lower.iter().rev().cloned().collect::<Vec<_>>();
+        let mut changes = vec![];
+        upper_changes(&upper, &lowers, &snapshot, Path::new(""), &mut changes)?;
+        changes
+    };
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+/// Prints the paths that a container added, modified, or deleted in its root, leaving out
+/// what bento creates in every root.
+pub fn print_diff(id: &str, json: bool) -> eyre::Result<()> {
+    let changes = changes(id)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+        return Ok(());
+    }
+    for change in changes {
+        let letter = match change.kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        println!("{letter} {}", change.path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgroup::Limits;
+    use nix::{
+        sys::stat::{mknod, Mode, SFlag},
+        unistd::{getuid, Pid},
+    };
+    use tempfile::TempDir;
+
+    fn write_file(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Whiteouts are character devices, which only root may create
+    fn whiteout(path: &Path) -> bool {
+        if !getuid().is_root() {
+            eprintln!("Skipping, as only root can create whiteouts");
+            return false;
+        }
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        mknod(path, SFlag::S_IFCHR, Mode::empty(), 0).unwrap();
+        true
+    }
+
+    /// Records a file of the upper layer as copied by bento, with its parents
+    fn snapshot_copy(snapshot: &mut BTreeMap<PathBuf, Entry>, upper: &Path, path: &str) {
+        let path = Path::new(path);
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.as_os_str().is_empty() {
+                snapshot.insert(ancestor.to_owned(), Entry::Directory);
+            }
+        }
+        let source = upper.join(path);
+        let metadata = fs::metadata(&source).unwrap();
+        snapshot.insert(
+            path.to_owned(),
+            Entry::new(&source, &metadata, true).unwrap(),
+        );
+    }
+
+    fn overlay_changes(
+        upper: &TempDir,
+        lowers: &[&TempDir],
+        snapshot: &BTreeMap<PathBuf, Entry>,
+    ) -> Vec<(String, ChangeKind)> {
+        let lowers = lowers
+            .iter()
+            .map(|lower| lower.path().to_owned())
+            .collect::<Vec<_>>();
+        let mut changes = vec![];
+        upper_changes(upper.path(), &lowers, snapshot, Path::new(""), &mut changes).unwrap();
+        summarize(changes)
+    }
+
+    fn summarize(mut changes: Vec<Change>) -> Vec<(String, ChangeKind)> {
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        changes
+            .into_iter()
+            .map(|change| (change.path.to_string_lossy().into_owned(), change.kind))
+            .collect()
+    }
+
+    #[test]
+    fn finds_paths_below_through_layers() {
+        let bottom = TempDir::new().unwrap();
+        let top = TempDir::new().unwrap();
+        write_file(&bottom.path().join("dir/bottom.txt"), "bottom");
+        write_file(&bottom.path().join("path/hidden.txt"), "hidden");
+        write_file(&top.path().join("path"), "file");
+        let lowers = [top.path().to_owned(), bottom.path().to_owned()];
+
+        assert!(exists_below(&lowers, Path::new("dir/bottom.txt")));
+        assert!(!exists_below(&lowers, Path::new("dir/missing.txt")));
+        assert!(exists_below(&lowers, Path::new("path")));
+        assert!(!exists_below(&lowers, Path::new("path/hidden.txt")));
+    }
+
+    #[test]
+    fn opaque_directory_hides_paths_below() {
+        let bottom = TempDir::new().unwrap();
+        let top = TempDir::new().unwrap();
+        write_file(&bottom.path().join("dir/bottom.txt"), "bottom");
+        fs::create_dir(top.path().join("dir")).unwrap();
+        overlay::mark_opaque(&top.path().join("dir")).unwrap();
+        let lowers = [top.path().to_owned(), bottom.path().to_owned()];
+
+        assert!(exists_below(&lowers, Path::new("dir")));
+        assert!(!exists_below(&lowers, Path::new("dir/bottom.txt")));
+    }
+
+    #[test]
+    fn whiteout_is_a_deletion() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("dir/gone.txt"), "gone");
+        write_file(&lower.path().join("dir/kept.txt"), "kept");
+        if !whiteout(&upper.path().join("dir/gone.txt")) {
+            return;
+        }
+        // The upper layer stacked as a lower directory of a later container
+        let lowers = [upper.path().to_owned(), lower.path().to_owned()];
+        assert!(!exists_below(&lowers, Path::new("dir/gone.txt")));
+
+        assert_eq!(
+            overlay_changes(&upper, &[&lower], &BTreeMap::new()),
+            [
+                ("/dir".to_owned(), ChangeKind::Modified),
+                ("/dir/gone.txt".to_owned(), ChangeKind::Deleted),
+            ]
+        );
+    }
+
+    #[test]
+    fn opaque_directory_deletes_lower_entries() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("dir/lower.txt"), "lower");
+        write_file(&lower.path().join("dir/both.txt"), "lower");
+        write_file(&upper.path().join("dir/both.txt"), "upper");
+        write_file(&upper.path().join("dir/upper.txt"), "upper");
+        overlay::mark_opaque(&upper.path().join("dir")).unwrap();
+
+        assert_eq!(
+            overlay_changes(&upper, &[&lower], &BTreeMap::new()),
+            [
+                ("/dir".to_owned(), ChangeKind::Modified),
+                ("/dir/both.txt".to_owned(), ChangeKind::Modified),
+                ("/dir/lower.txt".to_owned(), ChangeKind::Deleted),
+                ("/dir/upper.txt".to_owned(), ChangeKind::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_copies_that_match_the_snapshot() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("etc/os-release"), "lower");
+        write_file(&upper.path().join("usr/lib/libfoo.so"), "library");
+        let mut snapshot = BTreeMap::new();
+        snapshot_copy(&mut snapshot, upper.path(), "usr/lib/libfoo.so");
+
+        assert_eq!(overlay_changes(&upper, &[&lower], &snapshot), []);
+    }
+
+    #[test]
+    fn reports_modified_copies() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&upper.path().join("usr/lib/libfoo.so"), "library");
+        let mut snapshot = BTreeMap::new();
+        snapshot_copy(&mut snapshot, upper.path(), "usr/lib/libfoo.so");
+        // Same size, so that only the hash tells them apart
+        write_file(&upper.path().join("usr/lib/libfoo.so"), "LIBRARY");
+
+        assert_eq!(
+            overlay_changes(&upper, &[&lower], &snapshot),
+            [
+                ("/usr".to_owned(), ChangeKind::Added),
+                ("/usr/lib".to_owned(), ChangeKind::Added),
+                ("/usr/lib/libfoo.so".to_owned(), ChangeKind::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_plain_root_with_snapshot() {
+        let root = TempDir::new().unwrap();
+        write_file(&root.path().join("kept.txt"), "kept");
+        write_file(&root.path().join("changed.txt"), "before");
+        write_file(&root.path().join("dir/gone.txt"), "gone");
+        let mut snapshot = BTreeMap::new();
+        walk(root.path(), Path::new(""), &mut snapshot).unwrap();
+        // As taking the snapshot does for the parents of the files that bento writes
+        snapshot.insert(PathBuf::from("etc"), Entry::Directory);
+
+        write_file(&root.path().join("changed.txt"), "after, and longer");
+        fs::remove_file(root.path().join("dir/gone.txt")).unwrap();
+        write_file(&root.path().join("new/added.txt"), "added");
+        // Written by bento into every root
+        write_file(&root.path().join("etc/hosts"), "127.0.0.1\tlocalhost\n");
+
+        let state = ContainerState::new(
+            "diff".to_owned(),
+            Pid::this(),
+            None,
+            root.path().to_owned(),
+            Limits::default(),
+        );
+        assert_eq!(
+            summarize(plain_root_changes(&state, &snapshot).unwrap()),
+            [
+                ("/changed.txt".to_owned(), ChangeKind::Modified),
+                ("/dir/gone.txt".to_owned(), ChangeKind::Deleted),
+                ("/new".to_owned(), ChangeKind::Added),
+                ("/new/added.txt".to_owned(), ChangeKind::Added),
+            ]
+        );
+    }
+}
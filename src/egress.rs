@@ -0,0 +1,177 @@
+use eyre::{bail, WrapErr};
+use ipnet::Ipv4Net;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+/// Destinations of egress traffic, written as `all` or `CIDR[:PORT]`, e.g. `10.0.0.0/8:443` or
+/// `all:53`. A plain address is a single host, and without a port the rule covers every protocol.
+/// CIDRs are IPv4 only, so IPv6 destinations are only matched by `all`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EgressRule {
+    /// Every address if `None`, including IPv6 ones
+    pub network: Option<Ipv4Net>,
+    /// TCP or UDP destination port
+    pub port: Option<u16>,
+}
+
+impl EgressRule {
+    /// `port` is `None` for protocols without ports, e.g. ICMP.
+    pub fn matches(&self, address: IpAddr, port: Option<u16>) -> bool {
+        let address_matches = match (self.network, address) {
+            (None, _) => true,
+            (Some(network), IpAddr::V4(address)) => network.contains(&address),
+            (Some(_), IpAddr::V6(_)) => false,
+        };
+        let port_matches = self.port.is_none() || self.port == port;
+        address_matches && port_matches
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (network_str, port) = match s.split_once(':') {
+            Some((network_str, port_str)) => match port_str.parse() {
+                Ok(0) | Err(_) => bail!("Port {port_str} should be between 1 and 65535"),
+                Ok(port) => (network_str, Some(port)),
+            },
+            None => (s, None),
+        };
+        let network = match network_str {
+            "all" => None,
+            _ => {
+                let network = match network_str.parse::<Ipv4Addr>() {
+                    Ok(address) => Ipv4Net::from(address),
+                    Err(_) => network_str
+                        .parse::<Ipv4Net>()
+                        .wrap_err_with(|| format!("Invalid IPv4 network {network_str}"))?,
+                };
+                Some(network.trunc())
+            }
+        };
+        Ok(Self { network, port })
+    }
+}
+
+impl fmt::Display for EgressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.network {
+            Some(network) => write!(f, "{network}")?,
+            None => write!(f, "all")?,
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which destinations a container may reach. Everything is allowed unless a deny rule matches it,
+/// and allow rules make exceptions to the deny rules.
+#[derive(Debug, Clone, Default)]
+pub struct EgressPolicy {
+    pub allow: Vec<EgressRule>,
+    pub deny: Vec<EgressRule>,
+}
+
+impl EgressPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, address: IpAddr, port: Option<u16>) -> bool {
+        self.allow.iter().any(|rule| rule.matches(address, port))
+            || !self.deny.iter().any(|rule| rule.matches(address, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> EgressRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            rule("10.0.0.0/8:443"),
+            EgressRule {
+                network: Some("10.0.0.0/8".parse().unwrap()),
+                port: Some(443),
+            }
+        );
+        assert_eq!(
+            rule("all"),
+            EgressRule {
+                network: None,
+                port: None,
+            }
+        );
+        assert_eq!(rule("192.168.1.1").to_string(), "192.168.1.1/32");
+        assert_eq!(rule("10.1.2.3/8:53").to_string(), "10.0.0.0/8:53");
+        assert_eq!(rule("all:53").to_string(), "all:53");
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for rule in [
+            "",
+            "none",
+            "10.0.0.0/33",
+            "10.0.0.0/8:0",
+            "10.0.0.0/8:65536",
+            "10.0.0.0/8:https",
+            "::1",
+            "fd00::/8",
+        ] {
+            assert!(rule.parse::<EgressRule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn matches_network_and_port() {
+        let dns = rule("10.0.0.0/8:53");
+        assert!(dns.matches("10.1.2.3".parse().unwrap(), Some(53)));
+        assert!(!dns.matches("10.1.2.3".parse().unwrap(), Some(443)));
+        assert!(!dns.matches("10.1.2.3".parse().unwrap(), None));
+        assert!(!dns.matches("11.1.2.3".parse().unwrap(), Some(53)));
+
+        let network = rule("10.0.0.0/8");
+        assert!(network.matches("10.1.2.3".parse().unwrap(), Some(443)));
+        assert!(network.matches("10.1.2.3".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn matches_ipv6_only_with_all() {
+        let address = "2001:db8::1".parse().unwrap();
+        assert!(rule("all").matches(address, Some(443)));
+        assert!(rule("all:443").matches(address, Some(443)));
+        assert!(!rule("0.0.0.0/0").matches(address, Some(443)));
+    }
+
+    #[test]
+    fn allows_exceptions_to_deny_rules() {
+        let policy = EgressPolicy {
+            allow: vec![rule("10.0.0.1:443")],
+            deny: vec![rule("10.0.0.0/8")],
+        };
+        assert!(policy.allows("10.0.0.1".parse().unwrap(), Some(443)));
+        assert!(!policy.allows("10.0.0.1".parse().unwrap(), Some(80)));
+        assert!(!policy.allows("10.0.0.2".parse().unwrap(), Some(443)));
+        assert!(policy.allows("192.168.0.1".parse().unwrap(), Some(80)));
+        assert!(policy.allows("2001:db8::1".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        let policy = EgressPolicy::default();
+        assert!(policy.is_empty());
+        assert!(policy.allows("10.0.0.1".parse().unwrap(), Some(443)));
+    }
+}
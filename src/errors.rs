@@ -0,0 +1,9 @@
+use std::io;
+
+/// Whether an error comes from a system call or netlink request that failed with the given
+/// errno.
+pub fn has_errno(err: &eyre::Report, errno: i32) -> bool {
+    err.downcast_ref::<io::Error>()
+        .and_then(io::Error::raw_os_error)
+        == Some(errno)
+}
@@ -0,0 +1,679 @@
+use crate::{
+    image::{self, ImageConfig, OPAQUE_WHITEOUT, REF_NAME_ANNOTATION, WHITEOUT_PREFIX},
+    overlay,
+    state::{self, ContainerState, Status},
+    uid_gid_mapping::HostIds,
+};
+use eyre::{bail, eyre, WrapErr};
+use nix::{
+    fcntl::{Flock, FlockArg},
+    unistd::{Gid, Pid, Uid},
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    io::{self, BufWriter, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tar::{Builder, EntryType, Header};
+use tracing::debug;
+
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const LAYOUT_VERSION: &str = "1.0.0";
+
+/// Paths that bento creates in the root of every container, which are left out of the upper
+/// layer or plain root of a container when it is archived, but kept in the layers of its image
+pub const RUNTIME_PATHS: [&str; 6] = [
+    "dev",
+    "proc",
+    "sys",
+    "etc/hosts",
+    "etc/hostname",
+    "etc/resolv.conf",
+];
+
+/// Writes a layer directory as a reproducible tar: entries are sorted by name, modification
+/// times are zeroed, owners are mapped back to the IDs inside containers, and whiteouts and
+/// opaque directories become `.wh.` files again. Paths in `exclude` are left out along with
+/// everything below them.
+pub fn write_layer(dir: &Path, writer: impl Write, exclude: &[&str]) -> eyre::Result<()> {
+    let host_ids = HostIds::load()?;
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    append_layer_children(&mut builder, &host_ids, dir, Path::new(""), exclude)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Writes the merged view of layers, top first, as a reproducible tar like [`write_layer`],
+/// without the files that upper layers remove or hide. Paths in `exclude` are left out of the top
+/// layer only, so that the layers below show through in their place.
+pub fn write_merged(layers: &[PathBuf], writer: impl Write, exclude: &[&str]) -> eyre::Result<()> {
+    let host_ids = HostIds::load()?;
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    let top = MergeTop {
+        dir: layers.first().map(PathBuf::as_path),
+        exclude,
+    };
+    append_merged_children(&mut builder, &host_ids, layers, Path::new(""), &top)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn is_excluded(path: &Path, exclude: &[&str]) -> bool {
+    let is_excluded = exclude.iter().any(|excluded| path == Path::new(excluded));
+    if is_excluded {
+        debug!("Leaving {} out of the archive", path.display());
+    }
+    is_excluded
+}
+
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+pub fn sorted_names(dir: &Path) -> io::Result<Vec<OsString>> {
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+fn append_layer_children<W: Write>(
+    builder: &mut Builder<W>,
+    host_ids: &HostIds,
+    dir: &Path,
+    path: &Path,
+    exclude: &[&str],
+) -> eyre::Result<()> {
+    for name in sorted_names(dir)? {
+        let child_path = path.join(&name);
+        if is_excluded(&child_path, exclude) {
+            continue;
+        }
+        let source = dir.join(&name);
+        let metadata = fs::symlink_metadata(&source)?;
+        if is_whiteout(&metadata) {
+            let mut name = OsString::from(WHITEOUT_PREFIX);
+            name.push(child_path.file_name().unwrap_or_default());
+            append_whiteout(builder, &child_path.with_file_name(name))?;
+        } else if append_entry(builder, host_ids, &source, &child_path, &metadata)?
+            && metadata.is_dir()
+        {
+            if overlay::is_opaque(&source)? {
+                append_whiteout(builder, &child_path.join(OPAQUE_WHITEOUT))?;
+            }
+            append_layer_children(builder, host_ids, &source, &child_path, exclude)?;
+        }
+    }
+    Ok(())
+}
+
+/// The top layer of a merged view, and the paths left out of it.
+struct MergeTop<'a> {
+    dir: Option<&'a Path>,
+    exclude: &'a [&'a str],
+}
+
+/// Merges the children of the same directory in several layers, top first. A whiteout, a file,
+/// or an opaque directory hides what the layers below have at the same path, and directories
+/// are merged with the directories below them.
+fn append_merged_children<W: Write>(
+    builder: &mut Builder<W>,
+    host_ids: &HostIds,
+    dirs: &[PathBuf],
+    path: &Path,
+    top: &MergeTop,
+) -> eyre::Result<()> {
+    let mut merged: BTreeMap<OsString, Vec<PathBuf>> = BTreeMap::new();
+    let mut hidden = HashSet::new();
+    for dir in dirs {
+        let is_top = top.dir.is_some_and(|top_dir| dir.starts_with(top_dir));
+        for name in sorted_names(dir)? {
+            if hidden.contains(&name) {
+                continue;
+            }
+            if is_top && is_excluded(&path.join(&name), top.exclude) {
+                continue;
+            }
+            let source = dir.join(&name);
+            let metadata = fs::symlink_metadata(&source)?;
+            let sources = merged.entry(name.clone()).or_default();
+            if is_whiteout(&metadata) {
+                hidden.insert(name);
+            } else if metadata.is_dir() {
+                if overlay::is_opaque(&source)? {
+                    hidden.insert(name);
+                }
+                sources.push(source);
+            } else {
+                // A directory above hides a file below
+                if sources.is_empty() {
+                    sources.push(source);
+                }
+                hidden.insert(name);
+            }
+        }
+    }
+
+    for (name, sources) in merged {
+        let Some(source) = sources.first() else {
+            continue;
+        };
+        let child_path = path.join(&name);
+        let metadata = fs::symlink_metadata(source)?;
+        if append_entry(builder, host_ids, source, &child_path, &metadata)? && metadata.is_dir() {
+            append_merged_children(builder, host_ids, &sources, &child_path, top)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends a directory without its children, a symlink, or a regular file, and returns `false`
+/// for the other file types, which are left out.
+fn append_entry<W: Write>(
+    builder: &mut Builder<W>,
+    host_ids: &HostIds,
+    source: &Path,
+    path: &Path,
+    metadata: &Metadata,
+) -> eyre::Result<bool> {
+    let file_type = metadata.file_type();
+    let mut header = Header::new_gnu();
+    header.set_mtime(0);
+    header.set_mode(metadata.mode() & 0o7777);
+    header.set_uid(host_ids.container_uid(Uid::from_raw(metadata.uid())).into());
+    header.set_gid(host_ids.container_gid(Gid::from_raw(metadata.gid())).into());
+    header.set_size(0);
+
+    if file_type.is_dir() {
+        header.set_entry_type(EntryType::Directory);
+        builder.append_data(&mut header, path, io::empty())?;
+    } else if file_type.is_symlink() {
+        header.set_entry_type(EntryType::Symlink);
+        builder.append_link(&mut header, path, fs::read_link(source)?)?;
+    } else if file_type.is_file() {
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(metadata.len());
+        builder.append_data(&mut header, path, File::open(source)?)?;
+    } else {
+        debug!(
+            "Leaving special file {} out of the archive",
+            source.display()
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn append_whiteout<W: Write>(builder: &mut Builder<W>, path: &Path) -> eyre::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mtime(0);
+    header.set_mode(0o644);
+    header.set_size(0);
+    builder.append_data(&mut header, path, io::empty())?;
+    Ok(())
+}
+
+pub fn file_sha256(path: &Path) -> eyre::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The image config blob, whose digest is the ID of the image.
+pub fn config_json(config: &ImageConfig, diff_ids: &[String]) -> eyre::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&json!({
+        "architecture": image::oci_architecture(),
+        "os": "linux",
+        "config": config,
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": diff_ids,
+        },
+    }))?)
+}
+
+fn descriptor(media_type: &str, hex: &str, size: u64) -> Value {
+    json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{hex}"),
+        "size": size,
+    })
+}
+
+/// Writes a blob unless the layout has it already, and returns its descriptor.
+fn write_blob(blobs_dir: &Path, media_type: &str, bytes: &[u8]) -> eyre::Result<Value> {
+    let hex = image::sha256_hex(bytes);
+    let path = blobs_dir.join(&hex);
+    if !path.exists() {
+        write_layout_file(&path, bytes)?;
+    }
+    Ok(descriptor(media_type, &hex, bytes.len() as u64))
+}
+
+/// Writes a file of the layout through a temporary file unique to the process, so that an
+/// interrupted or concurrent write never leaves it truncated.
+fn write_layout_file(path: &Path, bytes: &[u8]) -> eyre::Result<()> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}.tmp", Pid::this()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = fs::write(&tmp_path, bytes).and_then(|()| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result.wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
+
+/// Writes an image to an OCI image layout, which is created if needed, and tags it in the index,
+/// taking the tag away from the image that had it before. Layers are written as uncompressed
+/// tars, so that their digests match the diff IDs of the config. Paths in `exclude` are left out
+/// of the top layer only.
+pub fn write_layout(
+    dir: &Path,
+    tag: &str,
+    layers: &[PathBuf],
+    config: &ImageConfig,
+    exclude: &[&str],
+) -> eyre::Result<()> {
+    if tag.is_empty() || tag.contains('/') || tag.contains(':') {
+        bail!("Invalid tag {tag:?}");
+    }
+    let blobs_dir = dir.join("blobs/sha256");
+    fs::create_dir_all(&blobs_dir)
+        .wrap_err_with(|| format!("Failed to create {}", blobs_dir.display()))?;
+
+    let mut layer_descriptors = vec![];
+    let mut diff_ids = vec![];
+    for (i, layer) in layers.iter().enumerate() {
+        let exclude = if i + 1 == layers.len() { exclude } else { &[] };
+        let tmp_path = blobs_dir.join(format!(".layer.{}.tmp", Pid::this()));
+        let result = (|| {
+            write_layer(layer, BufWriter::new(File::create(&tmp_path)?), exclude)?;
+            let hex = file_sha256(&tmp_path)?;
+            let size = fs::metadata(&tmp_path)?.len();
+            fs::rename(&tmp_path, blobs_dir.join(&hex))?;
+            Ok::<_, eyre::Report>((hex, size))
+        })();
+        let (hex, size) = match result {
+            Ok(blob) => blob,
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err.wrap_err(format!("Failed to write layer {}", layer.display())));
+            }
+        };
+        debug!("Wrote layer {} as sha256:{hex}", layer.display());
+        layer_descriptors.push(descriptor(LAYER_MEDIA_TYPE, &hex, size));
+        diff_ids.push(format!("sha256:{hex}"));
+    }
+
+    let config = write_blob(
+        &blobs_dir,
+        CONFIG_MEDIA_TYPE,
+        &config_json(config, &diff_ids)?,
+    )?;
+    let manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_MEDIA_TYPE,
+        "config": config,
+        "layers": layer_descriptors,
+    }))?;
+    let mut manifest = write_blob(&blobs_dir, MANIFEST_MEDIA_TYPE, &manifest)?;
+    manifest["annotations"] = json!({ REF_NAME_ANNOTATION: tag });
+
+    // Held while the index is read and written back, so that concurrent writers keep each
+    // other's tags
+    let dir_file = File::open(dir)?;
+    let _lock = Flock::lock(dir_file, FlockArg::LockExclusive)
+        .map_err(|(_, errno)| eyre!("Failed to lock {}: {errno}", dir.display()))?;
+    let index_path = dir.join("index.json");
+    let mut index: Value = match fs::read(&index_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .wrap_err_with(|| format!("Invalid index {}", index_path.display()))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            json!({ "schemaVersion": 2, "manifests": [] })
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let Some(manifests) = index["manifests"].as_array_mut() else {
+        bail!("Index {} has no manifests", index_path.display());
+    };
+    manifests.retain(|manifest| manifest["annotations"][REF_NAME_ANNOTATION] != tag);
+    manifests.push(manifest);
+    write_layout_file(&index_path, &serde_json::to_vec_pretty(&index)?)?;
+    write_layout_file(
+        &dir.join("oci-layout"),
+        &serde_json::to_vec(&json!({ "imageLayoutVersion": LAYOUT_VERSION }))?,
+    )?;
+    debug!("Wrote image {tag} to {}", dir.display());
+    Ok(())
+}
+
+/// An OCI image layout and a tag in it, written as `DIR[:TAG]` with the tag defaulting to
+/// `latest`.
+#[derive(Debug, Clone)]
+pub struct LayoutTarget {
+    pub dir: PathBuf,
+    pub tag: String,
+}
+
+impl FromStr for LayoutTarget {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (dir, tag) = match s.rsplit_once(':') {
+            Some((dir, tag)) if !tag.contains('/') => (dir, tag),
+            _ => (s, "latest"),
+        };
+        if dir.is_empty() || tag.is_empty() {
+            bail!("OCI image layout {s} should be DIR[:TAG]");
+        }
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            tag: tag.to_owned(),
+        })
+    }
+}
+
+/// Directories that make up the root of a stopped container, bottom first: the lower and upper
+/// directories of an overlay root, or the mount directory of a plain one.
+fn root_dirs(id: &str) -> eyre::Result<(ContainerState, Vec<PathBuf>)> {
+    let state = ContainerState::load(id)?;
+    // A paused container may be resumed and write to its root while it is archived
+    if state.status != Status::Stopped {
+        bail!("Container {id} is {:?}, stop it first", state.status);
+    }
+    let dirs = if state.rootfs_lower.is_empty() {
+        if state.mount_dir == state::rootfs_dir(id)? || !state.mount_dir.exists() {
+            bail!("Container {id} ran on a tmpfs root, which was discarded when it exited");
+        }
+        vec![state.mount_dir.clone()]
+    } else {
+        let upper = overlay::upper_dir(id)?;
+        if !upper.exists() {
+            bail!("Container {id} ran with --rm, which discarded its upper layer");
+        }
+        let mut dirs = state.rootfs_lower.clone();
+        dirs.push(upper);
+        dirs
+    };
+    Ok((state, dirs))
+}
+
+/// Writes the root filesystem of a stopped container to a tar, leaving out what bento creates
+/// in every root.
+pub fn export(id: &str, output: &Path) -> eyre::Result<()> {
+    let (_, mut dirs) = root_dirs(id)?;
+    dirs.reverse();
+    let mut tmp_path = output.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", Pid::this()));
+    let result = (|| {
+        write_merged(
+            &dirs,
+            BufWriter::new(File::create(&tmp_path)?),
+            &RUNTIME_PATHS,
+        )?;
+        fs::rename(&tmp_path, output)?;
+        Ok::<_, eyre::Report>(())
+    })();
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.wrap_err(format!(
+            "Failed to export container {id} to {}",
+            output.display()
+        )));
+    }
+    debug!("Exported container {id} to {}", output.display());
+    Ok(())
+}
+
+/// Writes a stopped container to an OCI image layout, with a layer for each lower directory and
+/// one for the upper directory, or a single layer for a plain root. The config of the image the
+/// container ran from is carried over.
+pub fn commit_layout(id: &str, target: &LayoutTarget) -> eyre::Result<()> {
+    let (state, dirs) = root_dirs(id)?;
+    let config = state.image_config.unwrap_or_default();
+    write_layout(&target.dir, &target.tag, &dirs, &config, &RUNTIME_PATHS)
+        .wrap_err_with(|| format!("Failed to commit container {id}"))?;
+    println!("{}:{}", target.dir.display(), target.tag);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{
+        sys::stat::{mknod, Mode, SFlag},
+        unistd::getuid,
+    };
+    use std::{io::Read, os::unix::fs::symlink, time::SystemTime};
+    use tar::Archive;
+    use tempfile::TempDir;
+
+    fn write_file(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Whiteouts are character devices, which only root may create
+    fn whiteout(path: &Path) -> bool {
+        if !getuid().is_root() {
+            eprintln!("Skipping, as only root can create whiteouts");
+            return false;
+        }
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        mknod(path, SFlag::S_IFCHR, Mode::empty(), 0).unwrap();
+        true
+    }
+
+    fn merged(layers: &[&TempDir]) -> Vec<u8> {
+        let layers = layers
+            .iter()
+            .map(|layer| layer.path().to_owned())
+            .collect::<Vec<_>>();
+        let mut tar = vec![];
+        write_merged(&layers, &mut tar, &[]).unwrap();
+        tar
+    }
+
+    /// Paths and types of the entries of a tar, in order
+    fn entries(tar: &[u8]) -> Vec<(String, EntryType)> {
+        Archive::new(tar)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                (path, entry.header().entry_type())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_same_tree_identically() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("dir/lower.txt"), "lower");
+        write_file(&upper.path().join("dir/upper.txt"), "upper");
+        symlink("dir/upper.txt", upper.path().join("link")).unwrap();
+
+        let mut first_layer = vec![];
+        write_layer(upper.path(), &mut first_layer, &[]).unwrap();
+        let first_merged = merged(&[&upper, &lower]);
+
+        File::options()
+            .write(true)
+            .open(upper.path().join("dir/upper.txt"))
+            .unwrap()
+            .set_modified(SystemTime::now())
+            .unwrap();
+        let mut second_layer = vec![];
+        write_layer(upper.path(), &mut second_layer, &[]).unwrap();
+        assert_eq!(first_layer, second_layer);
+        assert_eq!(first_merged, merged(&[&upper, &lower]));
+    }
+
+    #[test]
+    fn whiteout_hides_lower_entry() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("dir/gone.txt"), "gone");
+        write_file(&lower.path().join("dir/kept.txt"), "kept");
+        if !whiteout(&upper.path().join("dir/gone.txt")) {
+            return;
+        }
+
+        let paths = entries(&merged(&[&upper, &lower]))
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["dir", "dir/kept.txt"]);
+
+        let mut layer = vec![];
+        write_layer(upper.path(), &mut layer, &[]).unwrap();
+        let paths = entries(&layer)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["dir", "dir/.wh.gone.txt"]);
+    }
+
+    #[test]
+    fn opaque_directory_hides_lower_entries() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("dir/lower.txt"), "lower");
+        write_file(&lower.path().join("other.txt"), "other");
+        write_file(&upper.path().join("dir/upper.txt"), "upper");
+        overlay::mark_opaque(&upper.path().join("dir")).unwrap();
+
+        let paths = entries(&merged(&[&upper, &lower]))
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["dir", "dir/upper.txt", "other.txt"]);
+    }
+
+    #[test]
+    fn file_hides_lower_directory() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("path/lower.txt"), "lower");
+        write_file(&upper.path().join("path"), "upper");
+
+        assert_eq!(
+            entries(&merged(&[&upper, &lower])),
+            [("path".to_owned(), EntryType::Regular)]
+        );
+    }
+
+    #[test]
+    fn keeps_runtime_paths_of_lower_layers() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("etc/hosts"), "image");
+        write_file(&upper.path().join("etc/hosts"), "bento");
+        write_file(&upper.path().join("etc/hostname"), "bento");
+
+        let layers = [upper.path().to_owned(), lower.path().to_owned()];
+        let mut tar = vec![];
+        write_merged(&layers, &mut tar, &RUNTIME_PATHS).unwrap();
+        let mut archive = Archive::new(tar.as_slice());
+        let contents = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                (path, contents)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            [
+                ("etc".to_owned(), String::new()),
+                ("etc/hosts".to_owned(), "image".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_runtime_paths_out_of_top_layer_only() {
+        let lower = TempDir::new().unwrap();
+        let upper = TempDir::new().unwrap();
+        write_file(&lower.path().join("etc/resolv.conf"), "image");
+        write_file(&upper.path().join("etc/resolv.conf"), "bento");
+        let layout = TempDir::new().unwrap();
+
+        let layers = [lower.path().to_owned(), upper.path().to_owned()];
+        write_layout(
+            layout.path(),
+            "latest",
+            &layers,
+            &ImageConfig::default(),
+            &RUNTIME_PATHS,
+        )
+        .unwrap();
+
+        let mut paths = vec![];
+        for layer in &layers {
+            let mut tar = vec![];
+            let exclude: &[&str] = if layer == upper.path() {
+                &RUNTIME_PATHS
+            } else {
+                &[]
+            };
+            write_layer(layer, &mut tar, exclude).unwrap();
+            let hex = image::sha256_hex(&tar);
+            assert!(layout.path().join("blobs/sha256").join(&hex).exists());
+            paths.push(
+                entries(&tar)
+                    .into_iter()
+                    .map(|(path, _)| path)
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(paths, [vec!["etc", "etc/resolv.conf"], vec!["etc"]]);
+    }
+
+    #[test]
+    fn keeps_other_tags_of_layout() {
+        let layer = TempDir::new().unwrap();
+        write_file(&layer.path().join("file"), "contents");
+        let layout = TempDir::new().unwrap();
+
+        let layers = [layer.path().to_owned()];
+        for tag in ["v1", "v2", "v1"] {
+            write_layout(layout.path(), tag, &layers, &ImageConfig::default(), &[]).unwrap();
+        }
+
+        let index: Value =
+            serde_json::from_slice(&fs::read(layout.path().join("index.json")).unwrap()).unwrap();
+        let tags = index["manifests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|manifest| manifest["annotations"][REF_NAME_ANNOTATION].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, ["v2", "v1"]);
+        for dir in [layout.path(), &layout.path().join("blobs/sha256")] {
+            for entry in fs::read_dir(dir).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(!name.to_string_lossy().ends_with(".tmp"), "{name:?}");
+            }
+        }
+    }
+}
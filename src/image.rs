@@ -0,0 +1,734 @@
+use crate::{
+    overlay,
+    store::{self, ImageRecord},
+    uid_gid_mapping::HostIds,
+};
+use eyre::{bail, eyre, WrapErr};
+use flate2::read::GzDecoder;
+use nix::{
+    libc::{gid_t, uid_t},
+    sys::stat::{mknod, Mode, SFlag},
+    unistd::{getuid, Gid, Uid},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File, Permissions},
+    io::{self, BufRead, BufReader, Read},
+    os::unix::fs::{lchown, MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use tar::{Archive, EntryType};
+use tracing::debug;
+
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// Hides everything below the directory that lower layers put there
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+const DEFAULT_TAG: &str = "latest";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Used when the image does not set PATH itself
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// A local image, written as `DIR[:TAG]` for an OCI image layout, as the path of a `docker save`
+/// archive, or as the name or ID of an image loaded into the store before.
+#[derive(Debug, Clone)]
+pub enum ImageSource {
+    Layout { dir: PathBuf, tag: Option<String> },
+    Archive(PathBuf),
+    Stored(String),
+}
+
+impl FromStr for ImageSource {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let path = Path::new(s);
+        if path.is_file() {
+            return Ok(Self::Archive(path.to_path_buf()));
+        }
+        if path.is_dir() {
+            return Ok(Self::Layout {
+                dir: path.to_path_buf(),
+                tag: None,
+            });
+        }
+        match s.rsplit_once(':') {
+            Some((dir, tag)) if Path::new(dir).is_dir() && !tag.contains('/') => Ok(Self::Layout {
+                dir: PathBuf::from(dir),
+                tag: Some(tag.to_owned()),
+            }),
+            _ => Ok(Self::Stored(s.to_owned())),
+        }
+    }
+}
+
+/// Defaults of the image for the containers run from it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct ImageConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    /// A user name or UID, optionally followed by `:` and a group name or GID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// An image whose layers are unpacked in the store.
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// Bottom first, ready to be stacked as the lower directories of an overlay
+    pub layers: Vec<PathBuf>,
+    pub config: ImageConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    config: ImageConfig,
+    rootfs: RootFs,
+}
+
+/// Entry of the `manifest.json` of a `docker save` archive.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+fn record(id: &str, names: Vec<String>, config: ConfigFile) -> eyre::Result<ImageRecord> {
+    Ok(ImageRecord {
+        id: id.to_owned(),
+        names,
+        layers: config
+            .rootfs
+            .diff_ids
+            .iter()
+            .map(|diff_id| Ok(digest_hex(diff_id)?.to_owned()))
+            .collect::<eyre::Result<_>>()?,
+        config: config.config,
+    })
+}
+
+/// Unpacks the layers of an image that are not in the store yet, verifying their digests, and
+/// records the image in the store.
+pub fn load(source: &ImageSource) -> eyre::Result<Image> {
+    let record = match source {
+        ImageSource::Layout { dir, tag } => load_layout(dir, tag.as_deref()),
+        ImageSource::Archive(path) => load_archive(path),
+        ImageSource::Stored(reference) => return ImageRecord::find(reference)?.image(),
+    }
+    .wrap_err("Failed to load image")?;
+    record.save()?;
+    record.image()
+}
+
+/// Returns the hex part of a SHA-256 digest, which is safe to use as a path component.
+fn digest_hex(digest: &str) -> eyre::Result<&str> {
+    match digest.split_once(':') {
+        Some(("sha256", hex))
+            if hex.len() == 64
+                && hex
+                    .bytes()
+                    .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()) =>
+        {
+            Ok(hex)
+        }
+        _ => bail!("Unsupported digest {digest}, only sha256 is supported"),
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn verify(bytes: &[u8], digest: &str) -> eyre::Result<()> {
+    let actual = sha256_hex(bytes);
+    if actual != digest_hex(digest)? {
+        bail!("Digest mismatch, expected {digest} but got sha256:{actual}");
+    }
+    Ok(())
+}
+
+fn read_blob(dir: &Path, descriptor: &Descriptor) -> eyre::Result<Vec<u8>> {
+    let path = blob_path(dir, &descriptor.digest)?;
+    let bytes = fs::read(&path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    verify(&bytes, &descriptor.digest)?;
+    Ok(bytes)
+}
+
+fn blob_path(dir: &Path, digest: &str) -> eyre::Result<PathBuf> {
+    Ok(dir.join("blobs/sha256").join(digest_hex(digest)?))
+}
+
+pub fn oci_architecture() -> &'static str {
+    match env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        arch => arch,
+    }
+}
+
+fn select_manifest<'a>(index: &'a Index, tag: Option<&str>) -> eyre::Result<&'a Descriptor> {
+    let has_tag = |descriptor: &&Descriptor, tag: &str| {
+        descriptor
+            .annotations
+            .get(REF_NAME_ANNOTATION)
+            .is_some_and(|name| name == tag || name.ends_with(&format!(":{tag}")))
+    };
+    match tag {
+        Some(tag) => index.manifests.iter().find(|d| has_tag(d, tag)),
+        None if index.manifests.len() == 1 => index.manifests.first(),
+        None => index.manifests.iter().find(|d| has_tag(d, DEFAULT_TAG)),
+    }
+    .ok_or_else(|| {
+        eyre!(
+            "No image tagged {} in the index",
+            tag.unwrap_or(DEFAULT_TAG)
+        )
+    })
+}
+
+/// Picks the manifest for the current platform from a multi-platform index.
+fn select_platform(index: &Index) -> eyre::Result<&Descriptor> {
+    let architecture = oci_architecture();
+    index
+        .manifests
+        .iter()
+        .find(|descriptor| {
+            descriptor.platform.as_ref().is_some_and(|platform| {
+                platform.os == "linux" && platform.architecture == architecture
+            })
+        })
+        .ok_or_else(|| eyre!("Image has no manifest for linux/{architecture}"))
+}
+
+fn load_layout(dir: &Path, tag: Option<&str>) -> eyre::Result<ImageRecord> {
+    let index_path = dir.join("index.json");
+    let index: Index = serde_json::from_slice(
+        &fs::read(&index_path)
+            .wrap_err_with(|| format!("Failed to read {}", index_path.display()))?,
+    )?;
+    let mut descriptor = select_manifest(&index, tag)?;
+    // Plain tags are only unique within the layout, so they are qualified with its name
+    let names = match descriptor.annotations.get(REF_NAME_ANNOTATION) {
+        Some(name) if name.contains(':') || name.contains('/') => vec![name.clone()],
+        Some(name) => {
+            let dir_name = dir
+                .canonicalize()?
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            vec![format!("{dir_name}:{name}")]
+        }
+        None => vec![],
+    };
+    let nested_index: Index;
+    if descriptor
+        .media_type
+        .as_deref()
+        .is_some_and(|media_type| INDEX_MEDIA_TYPES.contains(&media_type))
+    {
+        nested_index = serde_json::from_slice(&read_blob(dir, descriptor)?)?;
+        descriptor = select_platform(&nested_index)?;
+    }
+    debug!("Loading image manifest {}", descriptor.digest);
+
+    let manifest: Manifest = serde_json::from_slice(&read_blob(dir, descriptor)?)?;
+    let config: ConfigFile = serde_json::from_slice(&read_blob(dir, &manifest.config)?)?;
+    if config.rootfs.diff_ids.len() != manifest.layers.len() {
+        bail!("Image config and manifest disagree on the number of layers");
+    }
+
+    for (layer, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+        if layer
+            .media_type
+            .as_deref()
+            .is_some_and(|media_type| media_type.ends_with("+zstd"))
+        {
+            bail!(
+                "Layer {} is compressed with zstd, which is not supported",
+                layer.digest
+            );
+        }
+        let path = blob_path(dir, &layer.digest)?;
+        unpack_layer(diff_id, Some(&layer.digest), || {
+            File::open(&path).wrap_err_with(|| format!("Failed to open {}", path.display()))
+        })?;
+    }
+    record(digest_hex(&manifest.config.digest)?, names, config)
+}
+
+/// Path of an archive entry, as `manifest.json` refers to it.
+fn entry_path<R: Read>(entry: &tar::Entry<'_, R>) -> eyre::Result<String> {
+    let path = entry.path()?;
+    let path = path.to_string_lossy();
+    Ok(path.trim_start_matches("./").to_owned())
+}
+
+fn read_archive_file(archive_path: &Path, path: &str) -> eyre::Result<Vec<u8>> {
+    let mut archive = Archive::new(File::open(archive_path)?);
+    for entry in archive.entries_with_seek()? {
+        let mut entry = entry?;
+        if entry_path(&entry)? == path {
+            let mut bytes = vec![];
+            entry.read_to_end(&mut bytes)?;
+            return Ok(bytes);
+        }
+    }
+    bail!("{} has no {path}", archive_path.display())
+}
+
+fn load_archive(archive_path: &Path) -> eyre::Result<ImageRecord> {
+    let manifests: Vec<ArchiveManifest> =
+        serde_json::from_slice(&read_archive_file(archive_path, "manifest.json")?)?;
+    let manifest = match manifests.as_slice() {
+        [manifest] => manifest,
+        _ => bail!(
+            "{} should contain exactly one image, but has {}",
+            archive_path.display(),
+            manifests.len()
+        ),
+    };
+    debug!("Loading image {:?}", manifest.repo_tags);
+
+    let config_bytes = read_archive_file(archive_path, &manifest.config)?;
+    // Named after its digest, either as HEX.json or as blobs/sha256/HEX
+    let config_name = Path::new(&manifest.config)
+        .file_name()
+        .map(|name| name.to_string_lossy().trim_end_matches(".json").to_owned())
+        .unwrap_or_default();
+    let id = sha256_hex(&config_bytes);
+    if digest_hex(&format!("sha256:{config_name}")).is_ok() && config_name != id {
+        bail!(
+            "Image config {} is corrupt, its content is sha256:{id}",
+            manifest.config
+        );
+    }
+    let config: ConfigFile = serde_json::from_slice(&config_bytes)?;
+    if config.rootfs.diff_ids.len() != manifest.layers.len() {
+        bail!("Image config and manifest disagree on the number of layers");
+    }
+
+    // Layers can come in any order, so all missing ones are unpacked in a single pass
+    let layer_dirs = config
+        .rootfs
+        .diff_ids
+        .iter()
+        .map(|diff_id| store::layer_dir(digest_hex(diff_id)?))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let mut missing: HashMap<&str, &str> = manifest
+        .layers
+        .iter()
+        .zip(&config.rootfs.diff_ids)
+        .zip(&layer_dirs)
+        .filter(|(_, dir)| !dir.exists())
+        .map(|((path, diff_id), _)| (path.as_str(), diff_id.as_str()))
+        .collect();
+    if !missing.is_empty() {
+        let mut archive = Archive::new(File::open(archive_path)?);
+        for entry in archive.entries_with_seek()? {
+            let mut entry = entry?;
+            if let Some(diff_id) = missing.remove(entry_path(&entry)?.as_str()) {
+                unpack_layer(diff_id, None, || Ok(&mut entry))?;
+            }
+        }
+    }
+    if let Some(path) = missing.keys().next() {
+        bail!("{} has no layer {path}", archive_path.display());
+    }
+
+    let names = manifest.repo_tags.clone().unwrap_or_default();
+    record(&id, names, config)
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Reads the rest, e.g. the padding after a tar archive, and returns the hex digest.
+    fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Unpacks a layer into the store unless it is there already. The layer is only opened when
+/// needed, and is unpacked next to its final place, so that a failed or mismatching layer never
+/// ends up in the store.
+pub fn unpack_layer<R: Read>(
+    diff_id: &str,
+    digest: Option<&str>,
+    open: impl FnOnce() -> eyre::Result<R>,
+) -> eyre::Result<PathBuf> {
+    let hex = digest_hex(diff_id)?;
+    let dir = store::layer_dir(hex)?;
+    if dir.exists() {
+        debug!("Layer {diff_id} is in the store");
+        return Ok(dir);
+    }
+
+    debug!("Unpacking layer {diff_id}");
+    let tmp_dir = store::tmp_layer_dir(hex)?;
+    fs::create_dir(&tmp_dir)?;
+    let result = (|| {
+        let mut compressed = HashingReader::new(open()?);
+        let mut buffered = BufReader::new(&mut compressed);
+        let is_gzip = buffered.fill_buf()?.starts_with(&GZIP_MAGIC);
+        let decompressed: Box<dyn Read + '_> = if is_gzip {
+            Box::new(GzDecoder::new(buffered))
+        } else {
+            Box::new(buffered)
+        };
+        let mut uncompressed = HashingReader::new(decompressed);
+        unpack_tar(&mut uncompressed, &tmp_dir)?;
+        let uncompressed_hex = uncompressed.finish()?;
+        let compressed_hex = compressed.finish()?;
+
+        if uncompressed_hex != hex {
+            bail!("Layer {diff_id} is corrupt, its content is sha256:{uncompressed_hex}");
+        }
+        if let Some(digest) = digest {
+            if compressed_hex != digest_hex(digest)? {
+                bail!("Layer {digest} is corrupt, its blob is sha256:{compressed_hex}");
+            }
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        store::remove_layer(&tmp_dir)?;
+        return Err(err).wrap_err_with(|| format!("Failed to unpack layer {diff_id}"));
+    }
+
+    match fs::rename(&tmp_dir, &dir) {
+        // Unpacked concurrently by another container
+        Err(err) if dir.exists() => {
+            debug!("Layer {diff_id} appeared while unpacking: {err}");
+            store::remove_layer(&tmp_dir)?;
+        }
+        result => result?,
+    }
+    Ok(dir)
+}
+
+fn is_inside_layer(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Fails if a path of a layer goes through a symlink that an earlier entry created, which could
+/// point anywhere on the host. `Entry::unpack_in` checks this itself, but whiteouts would follow it.
+fn check_no_symlink_parents(dir: &Path, path: &Path) -> eyre::Result<()> {
+    let mut current = dir.to_path_buf();
+    for component in path.parent().unwrap_or(Path::new("")).components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.is_symlink() => {
+                bail!("{} goes through a symlink of the layer", path.display())
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Sets the mode of an unpacked path, leaving symlinks alone, since chmod would follow them out of
+/// the layer, e.g. for a hard link to a symlink.
+fn set_mode(path: &Path, mode: u32) -> eyre::Result<()> {
+    if !fs::symlink_metadata(path)?.is_symlink() {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Turns a whiteout file into what overlayfs expects, a 0/0 character device for a removed path
+/// and an xattr for a directory that hides the one below. Returns `false` for other entries.
+fn convert_whiteout(dir: &Path, path: &Path) -> eyre::Result<bool> {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(false);
+    };
+    let parent = dir.join(path.parent().unwrap_or(Path::new("")));
+    if name == OPAQUE_WHITEOUT {
+        fs::create_dir_all(&parent)?;
+        overlay::mark_opaque(&parent)?;
+    } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+        fs::create_dir_all(&parent)?;
+        mknod(&parent.join(hidden), SFlag::S_IFCHR, Mode::empty(), 0)?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Unpacks a layer tar for overlayfs, without device nodes, which the container's /dev provides
+/// instead. Owners are shifted into the IDs that containers' user namespaces map, which only root
+/// can do. Directories stay writable by their owner until the end, so that an unprivileged user
+/// can fill read-only ones.
+fn unpack_tar(reader: impl Read, dir: &Path) -> eyre::Result<()> {
+    let host_ids = match getuid().is_root() {
+        true => Some(HostIds::load()?),
+        false => None,
+    };
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+
+    let mut read_only_dirs = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if matches!(
+            entry_type,
+            EntryType::Block | EntryType::Char | EntryType::Fifo
+        ) {
+            debug!("Skipping device {}", entry.path()?.display());
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if !is_inside_layer(&path) {
+            debug!("Skipping {} outside the layer", path.display());
+            continue;
+        }
+        check_no_symlink_parents(dir, &path)?;
+        if convert_whiteout(dir, &path)? || !entry.unpack_in(dir)? {
+            continue;
+        }
+
+        let path = dir.join(path);
+        if let Some(host_ids) = &host_ids {
+            let header = entry.header();
+            let uid = host_ids.uid(u32::try_from(header.uid()?)?);
+            let gid = host_ids.gid(u32::try_from(header.gid()?)?);
+            lchown(&path, Some(uid.as_raw()), Some(gid.as_raw()))?;
+        }
+        // Changing the owner clears the setuid and setgid bits
+        if entry_type != EntryType::Symlink {
+            let mode = entry.header().mode()?;
+            let is_read_only_dir = entry_type == EntryType::Directory && mode & 0o700 != 0o700;
+            if is_read_only_dir {
+                read_only_dirs.push((path.clone(), mode));
+            }
+            let mode = if is_read_only_dir { mode | 0o700 } else { mode };
+            set_mode(&path, mode)?;
+        }
+    }
+    for (path, mode) in read_only_dirs.into_iter().rev() {
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
+
+fn find_entry(contents: &str, matches: impl Fn(&[&str]) -> bool) -> Option<Vec<&str>> {
+    contents
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 4 && matches(fields))
+}
+
+/// Resolves the user of an image inside the container's root, by name or ID. The group defaults
+/// to the user's primary group, or to the UID like for `--uid` if the user is not in
+/// `/etc/passwd`.
+pub fn resolve_user(user: &str) -> eyre::Result<(Uid, Gid)> {
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+
+    let (uid, primary_gid) = match user.parse::<uid_t>() {
+        Ok(uid) => {
+            let uid_str = uid.to_string();
+            let gid = find_entry(&passwd, |fields| fields[2] == uid_str)
+                .and_then(|fields| fields[3].parse().ok())
+                .unwrap_or(uid);
+            (uid, gid)
+        }
+        Err(_) => {
+            let fields = find_entry(&passwd, |fields| fields[0] == user)
+                .ok_or_else(|| eyre!("User {user} is not in the image's /etc/passwd"))?;
+            (fields[2].parse()?, fields[3].parse()?)
+        }
+    };
+
+    let gid = match group {
+        None => primary_gid,
+        Some(group) => match group.parse::<gid_t>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+                let fields = find_entry(&groups, |fields| fields[0] == group)
+                    .ok_or_else(|| eyre!("Group {group} is not in the image's /etc/group"))?;
+                fields[2].parse()?
+            }
+        },
+    };
+    Ok((Uid::from_raw(uid), Gid::from_raw(gid)))
+}
+
+/// Finds a command without a slash in the directories of PATH, like a shell would.
+pub fn find_in_path(command: &str, path: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return Some(PathBuf::from(command));
+    }
+    path.split(':')
+        .map(|dir| Path::new(dir).join(command))
+        .find(|candidate| {
+            fs::metadata(candidate)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+    use tempfile::TempDir;
+
+    /// Layer whose entries are symlinks for a non-empty target, hard links for a target starting
+    /// with `=`, directories for a path ending with `/`, and empty files otherwise
+    fn layer(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        for (path, target) in entries {
+            let mut header = Header::new_gnu();
+            header.set_size(0);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            if let Some(target) = target.strip_prefix('=') {
+                header.set_entry_type(EntryType::Link);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else if !target.is_empty() {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_mode(0o777);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else {
+                let entry_type = match path.ends_with('/') {
+                    true => EntryType::Directory,
+                    false => EntryType::Regular,
+                };
+                header.set_entry_type(entry_type);
+                header.set_mode(0o777);
+                builder.append_data(&mut header, path, io::empty()).unwrap();
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Unpacks a layer whose `host` entry is a symlink to a directory outside of it
+    fn unpack_with_host_symlink(entries: &[(&str, &str)]) -> (TempDir, eyre::Result<()>) {
+        let host = TempDir::new().unwrap();
+        fs::set_permissions(host.path(), Permissions::from_mode(0o755)).unwrap();
+        let layer_dir = TempDir::new().unwrap();
+        let target = host.path().to_str().unwrap();
+        let entries = [&[("host", target)], entries].concat();
+        let result = unpack_tar(layer(&entries).as_slice(), layer_dir.path());
+        (host, result)
+    }
+
+    #[test]
+    fn rejects_whiteout_through_symlink() {
+        let (host, result) = unpack_with_host_symlink(&[("host/.wh.foo", "")]);
+        assert!(result.unwrap_err().to_string().contains("symlink"));
+        assert!(fs::symlink_metadata(host.path().join("foo")).is_err());
+    }
+
+    #[test]
+    fn rejects_opaque_whiteout_through_symlink() {
+        let (host, result) = unpack_with_host_symlink(&[("host/.wh..wh..opq", "")]);
+        assert!(result.unwrap_err().to_string().contains("symlink"));
+        assert!(!overlay::is_opaque(host.path()).unwrap_or(false));
+    }
+
+    #[test]
+    fn rejects_file_through_symlink() {
+        let (host, result) = unpack_with_host_symlink(&[("host/file", "")]);
+        assert!(result.is_err());
+        assert!(fs::symlink_metadata(host.path().join("file")).is_err());
+    }
+
+    #[test]
+    fn keeps_mode_behind_directory_entry_on_symlink() {
+        let (host, result) = unpack_with_host_symlink(&[("host/", "")]);
+        assert!(result.is_err());
+        let mode = fs::metadata(host.path()).unwrap().mode() & 0o7777;
+        assert_eq!(mode, 0o755);
+    }
+
+    #[test]
+    fn keeps_mode_behind_hard_link_to_symlink() {
+        let (host, result) = unpack_with_host_symlink(&[("link", "=host")]);
+        assert!(result.is_err());
+        let mode = fs::metadata(host.path()).unwrap().mode() & 0o7777;
+        assert_eq!(mode, 0o755);
+    }
+}
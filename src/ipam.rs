@@ -0,0 +1,100 @@
+use crate::state;
+use eyre::{eyre, WrapErr};
+use ipnet::Ipv4Net;
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    sys::signal::kill,
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{Read, Seek, Write},
+    net::Ipv4Addr,
+};
+use tracing::debug;
+
+/// Container IDs cannot start with a dot, so the lease file never clashes with a container's
+/// state directory.
+const LEASES_FILE_NAME: &str = ".leases.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    id: String,
+    /// PID of the bento process running the container, which holds the lease while it is alive
+    pid: i32,
+}
+
+impl Lease {
+    /// A lease is stale once its bento process is gone, e.g. because it crashed without
+    /// releasing it.
+    fn is_stale(&self) -> bool {
+        kill(Pid::from_raw(self.pid), None) == Err(Errno::ESRCH)
+    }
+}
+
+type Leases = BTreeMap<Ipv4Addr, Lease>;
+
+/// Runs `f` on the leases while holding an exclusive lock on the lease file, and writes them
+/// back afterwards.
+fn with_leases<T>(f: impl FnOnce(&mut Leases) -> eyre::Result<T>) -> eyre::Result<T> {
+    let dir = state::state_dir();
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(LEASES_FILE_NAME);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let mut file = Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, errno)| eyre!("Failed to lock {}: {errno}", path.display()))?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut leases: Leases = if contents.is_empty() {
+        Leases::new()
+    } else {
+        serde_json::from_str(&contents)?
+    };
+
+    let result = f(&mut leases)?;
+
+    file.rewind()?;
+    file.set_len(0)?;
+    file.write_all(serde_json::to_string_pretty(&leases)?.as_bytes())?;
+    Ok(result)
+}
+
+/// Leases the lowest free address of the subnet for a container. The first address is left for
+/// the gateway.
+pub fn lease(id: &str, subnet: Ipv4Net) -> eyre::Result<Ipv4Addr> {
+    with_leases(|leases| {
+        leases.retain(|_, lease| !lease.is_stale());
+        let address = subnet
+            .hosts()
+            .skip(1)
+            .find(|address| !leases.contains_key(address))
+            .ok_or_else(|| eyre!("No free address left in subnet {subnet}"))?;
+        leases.insert(
+            address,
+            Lease {
+                id: id.to_owned(),
+                pid: Pid::this().as_raw(),
+            },
+        );
+        debug!("Leased address {address} to container {id}");
+        Ok(address)
+    })
+}
+
+pub fn release(id: &str) -> eyre::Result<()> {
+    with_leases(|leases| {
+        leases.retain(|_, lease| lease.id != id);
+        debug!("Released address of container {id}");
+        Ok(())
+    })
+}
@@ -1,14 +1,36 @@
 cfg_if::cfg_if! {
     if #[cfg(target_os = "linux")] {
+        mod build;
+        mod cgroup;
         mod child;
         mod cli;
+        mod commands;
         mod container;
         mod container_config;
+        mod devices;
+        mod diff;
+        mod egress;
+        mod errors;
+        mod export;
+        mod image;
+        mod ipam;
+        mod mounts;
+        mod namespaces;
+        mod netlink;
+        mod network;
+        mod nftables;
+        mod overlay;
+        mod ports;
+        mod pressure;
         mod sockets;
+        mod state;
+        mod store;
         mod uid_gid_mapping;
+        mod ulimit;
+        mod user_network;
 
         pub use cli::Args;
-        pub use container::start;
+        pub use commands::run;
     } else {
         compile_error!("Only linux is supported");
     }
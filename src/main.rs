@@ -18,7 +18,7 @@ fn main() -> eyre::Result<()> {
     let args = bento::Args::try_parse_and_validate()?;
 
     debug!("{:?}", args);
-    bento::start(args)?;
+    bento::run(args)?;
 
     Ok(())
 }
@@ -0,0 +1,562 @@
+use crate::{
+    errors,
+    namespaces::{NamespaceMode, Namespaces},
+    overlay::Overlay,
+};
+use eyre::{bail, eyre, WrapErr};
+use nix::{
+    libc,
+    mount::{mount, MsFlags},
+    sys::statvfs::{statvfs, FsFlags},
+};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStringExt, fs::symlink},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use tracing::debug;
+
+/// Paths inside the container have to be absolute, and must not climb out of the new root.
+fn container_path(s: &str) -> eyre::Result<PathBuf> {
+    let path = PathBuf::from(s);
+    if !path.is_absolute() {
+        bail!("Container path {s} should be absolute");
+    }
+    if path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        bail!("Container path {s} must not contain ..");
+    }
+    if path.parent().is_none() {
+        bail!("Cannot mount over the container root");
+    }
+    Ok(path)
+}
+
+/// A host path bind-mounted into the container, written as
+/// `HOST:CONTAINER[:ro|rw][,nosuid,nodev,noexec]`, e.g. `/srv/data:/data:ro,noexec`.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Flags to remount the bind mount with, since binding ignores them
+    pub flags: MsFlags,
+}
+
+impl FromStr for Volume {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (source_str, rest) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("Volume {s} should be HOST:CONTAINER[:OPTIONS]"))?;
+        let (target_str, options) = rest.split_once([':', ',']).unwrap_or((rest, ""));
+
+        let mut flags = MsFlags::empty();
+        for option in options
+            .split([':', ','])
+            .filter(|option| !option.is_empty())
+        {
+            flags |= match option {
+                "ro" => MsFlags::MS_RDONLY,
+                "rw" => MsFlags::empty(),
+                "nosuid" => MsFlags::MS_NOSUID,
+                "nodev" => MsFlags::MS_NODEV,
+                "noexec" => MsFlags::MS_NOEXEC,
+                _ => bail!("Volume option {option} should be ro, rw, nosuid, nodev, or noexec"),
+            };
+        }
+
+        let source = fs::canonicalize(source_str)
+            .wrap_err_with(|| format!("Volume source {source_str} does not exist"))?;
+        Ok(Self {
+            source,
+            target: container_path(target_str)?,
+            flags,
+        })
+    }
+}
+
+/// A tmpfs mounted into the container, written as `PATH[:OPTIONS]`, e.g.
+/// `/tmp:size=64m,mode=1777`.
+#[derive(Debug, Clone)]
+pub struct Tmpfs {
+    pub target: PathBuf,
+    /// Passed on to tmpfs as they are
+    pub options: Option<String>,
+}
+
+impl FromStr for Tmpfs {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (target_str, options) = match s.split_once(':') {
+            Some((target_str, options)) => (target_str, Some(options.to_owned())),
+            None => (s, None),
+        };
+        Ok(Self {
+            target: container_path(target_str)?,
+            options,
+        })
+    }
+}
+
+/// Paths that leak information about the host.
+pub const DEFAULT_MASKED_PATHS: [&str; 5] = [
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/timer_list",
+    "/proc/sched_debug",
+    "/sys/firmware",
+];
+/// Paths that would let the container reconfigure the host's kernel.
+pub const DEFAULT_READ_ONLY_PATHS: [&str; 4] =
+    ["/proc/sys", "/proc/sysrq-trigger", "/proc/irq", "/proc/bus"];
+/// Passed to `--unmask` to unmask every default path
+const UNMASK_ALL: &str = "all";
+
+/// The default paths except the unmasked ones, followed by the extra paths.
+pub fn with_defaults(defaults: &[&str], extra: Vec<PathBuf>, unmasked: &[PathBuf]) -> Vec<PathBuf> {
+    if unmasked.iter().any(|path| path == Path::new(UNMASK_ALL)) {
+        return extra;
+    }
+    defaults
+        .iter()
+        .map(PathBuf::from)
+        .filter(|path| !unmasked.contains(path))
+        .chain(extra)
+        .collect()
+}
+
+/// Mounts of the container besides its root and pseudo filesystems.
+#[derive(Debug, Clone, Default)]
+pub struct MountConfig {
+    pub volumes: Vec<Volume>,
+    pub tmpfs: Vec<Tmpfs>,
+    /// Whether to remount the new root read-only once it is set up
+    pub read_only: bool,
+    pub masked_paths: Vec<PathBuf>,
+    pub read_only_paths: Vec<PathBuf>,
+    /// Whether to mount a procfs of the container's PID namespace at `/proc`
+    pub proc: bool,
+    /// Mounted as the new root instead of binding the mount directory onto itself
+    pub overlay: Option<Overlay>,
+    /// Size in bytes of a tmpfs mounted as the new root otherwise, for containers without a mount
+    /// directory of their own
+    pub root_size: Option<i64>,
+}
+
+/// Device nodes bind-mounted from the host, since creating them needs privileges on the host and
+/// tmpfs in a user namespace ignores them anyway.
+const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+const DEV_SYMLINKS: [(&str, &str); 5] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx"),
+];
+const DEV_OPTIONS: &str = "mode=755,size=64k";
+const DEVPTS_OPTIONS: &str = "newinstance,ptmxmode=0666,mode=0620";
+/// Same as Docker's default, since the private IPC namespace gets a /dev/shm of its own
+const SHM_OPTIONS: &str = "mode=1777,size=64m";
+/// Enough for the command and its libraries, which is all that bento copies into the new root
+pub const DEFAULT_ROOT_SIZE: i64 = 256 * 1024 * 1024;
+
+fn mount_filesystem(
+    fs_type: &str,
+    target: &Path,
+    flags: MsFlags,
+    options: Option<&str>,
+) -> eyre::Result<()> {
+    debug!("Mounting {fs_type} at {}", target.display());
+    fs::create_dir_all(target)?;
+    mount(Some(fs_type), target, Some(fs_type), flags, options)
+        .map_err(io::Error::from)
+        .wrap_err_with(|| format!("Failed to mount {fs_type} at {}", target.display()))
+}
+
+/// Mounts a tmpfs as the new root. It belongs to the container's mount namespace, so it is gone
+/// with everything copied into it once the container exits.
+pub fn mount_root_tmpfs(new_root: &Path, size: i64) -> eyre::Result<()> {
+    let options = format!("mode=755,size={size}");
+    mount_filesystem("tmpfs", new_root, MsFlags::empty(), Some(&options))
+}
+
+/// Bind-mounts a file or directory, creating the mount point like the source. Recursive, since a
+/// user namespace may not bind a mount without the mounts on top of it that it inherited.
+fn bind_mount(source: &Path, target: &Path) -> eyre::Result<()> {
+    debug!("Bind-mounting {} at {}", source.display(), target.display());
+    if source.is_dir() {
+        fs::create_dir_all(target)?;
+    } else if !target.exists() {
+        File::create(target)?;
+    }
+    let flags = MsFlags::MS_BIND | MsFlags::MS_REC;
+    mount::<_, _, Path, Path>(Some(source), target, None, flags, None)
+        .wrap_err_with(|| format!("Failed to bind-mount {}", source.display()))
+}
+
+/// Flags like read-only only apply to a bind mount when it is remounted, which has to keep the
+/// flags that the kernel locks on mounts from a more privileged user namespace, e.g. nosuid.
+fn remount(path: &Path, extra_flags: MsFlags) -> eyre::Result<()> {
+    let current = statvfs(path)?.flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | extra_flags;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if current.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    debug!("Remounting {} with flags {flags:?}", path.display());
+    mount::<Path, _, Path, Path>(None, path, None, flags, None)
+        .wrap_err_with(|| format!("Failed to remount {}", path.display()))
+}
+
+/// Decodes the octal escapes of a path in `/proc/self/mountinfo`, e.g. `\040` for a space.
+fn unescape_mountinfo(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut path = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        match escape
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        {
+            Some(byte) => {
+                path.push(byte);
+                i += 4;
+            }
+            None => {
+                path.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
+
+/// Mount points at or below `path`, parents first, which a bind mount with `MS_REC` brought
+/// along and which a remount of `path` alone would leave as they were.
+fn submounts(path: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mut mount_points = vec![];
+    for line in mountinfo.lines() {
+        let Some(field) = line.split(' ').nth(4) else {
+            bail!("Malformed line in /proc/self/mountinfo: {line}");
+        };
+        let mount_point = unescape_mountinfo(field);
+        if mount_point.starts_with(path) && !mount_points.contains(&mount_point) {
+            mount_points.push(mount_point);
+        }
+    }
+    mount_points.sort_by_key(|mount_point| mount_point.components().count());
+    Ok(mount_points)
+}
+
+const MAX_SYMLINKS: usize = 40;
+
+/// Resolves `path` as if `new_root` were `/`, following the symlinks inside it. Fails if a symlink
+/// climbs above the new root, since a mount there would land on the host.
+pub fn resolve_in_root(new_root: &Path, path: &Path) -> eyre::Result<PathBuf> {
+    let mut resolved = new_root.to_path_buf();
+    let mut pending: VecDeque<OsString> = path
+        .components()
+        .map(|component| component.as_os_str().to_owned())
+        .collect();
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop_front() {
+        match component.to_str() {
+            Some("/") => resolved = new_root.to_path_buf(),
+            Some(".") => {}
+            Some("..") => {
+                if resolved == new_root {
+                    bail!(
+                        "Container path {} escapes the container root",
+                        path.display()
+                    );
+                }
+                resolved.pop();
+            }
+            _ => {
+                let candidate = resolved.join(&component);
+                if !candidate.is_symlink() {
+                    resolved = candidate;
+                    continue;
+                }
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    bail!("Container path {} has too many symlinks", path.display());
+                }
+                let link = fs::read_link(&candidate)?;
+                for component in link.components().rev() {
+                    pending.push_front(component.as_os_str().to_owned());
+                }
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+enum ExtraMount<'a> {
+    Volume(&'a Volume),
+    Tmpfs(&'a Tmpfs),
+}
+
+impl ExtraMount<'_> {
+    fn target(&self) -> &Path {
+        match self {
+            ExtraMount::Volume(volume) => &volume.target,
+            ExtraMount::Tmpfs(tmpfs) => &tmpfs.target,
+        }
+    }
+}
+
+/// Mounts volumes and tmpfs into the new root, with parents before the mounts nested in them.
+pub fn mount_volumes(new_root: &Path, config: &MountConfig) -> eyre::Result<()> {
+    let mut mounts: Vec<_> = config
+        .volumes
+        .iter()
+        .map(ExtraMount::Volume)
+        .chain(config.tmpfs.iter().map(ExtraMount::Tmpfs))
+        .collect();
+    mounts.sort_by_key(|mount| mount.target().components().count());
+
+    // Remounted once everything is in place, so that mount points nested in a read-only volume
+    // can still be created
+    let mut remounts = vec![];
+    let mut targets = vec![];
+    for mount in mounts {
+        let target = resolve_in_root(new_root, mount.target())?;
+        targets.push(target.clone());
+        match mount {
+            ExtraMount::Volume(volume) => {
+                bind_mount(&volume.source, &target)?;
+                if !volume.flags.is_empty() {
+                    remounts.push((target, volume.flags));
+                }
+            }
+            ExtraMount::Tmpfs(tmpfs) => mount_filesystem(
+                "tmpfs",
+                &target,
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                tmpfs.options.as_deref(),
+            )?,
+        }
+    }
+    // Flags of a volume cover what is mounted inside its source too, but not the volumes and
+    // tmpfs mounted inside it here, which have their own
+    let targets = targets
+        .iter()
+        .map(fs::canonicalize)
+        .collect::<io::Result<Vec<_>>>()?;
+    for (target, flags) in remounts {
+        let target = fs::canonicalize(target)?;
+        for mount_point in submounts(&target)? {
+            let is_own_mount = targets.iter().any(|other| {
+                *other != target && other.starts_with(&target) && mount_point.starts_with(other)
+            });
+            if !is_own_mount {
+                remount(&mount_point, flags)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mounts `/proc`, a read-only `/sys`, and a minimal `/dev` into the new root. Has to run in the
+/// container's namespaces, since procfs, sysfs, and mqueue show the namespaces they are mounted in.
+/// Their mount points are resolved inside the new root like those of volumes, while what goes into
+/// `/dev` lands on its fresh tmpfs.
+pub fn mount_pseudo_filesystems(
+    new_root: &Path,
+    namespaces: &Namespaces,
+    config: &MountConfig,
+) -> eyre::Result<()> {
+    if config.proc {
+        mount_proc(new_root, &namespaces.pid)?;
+    } else {
+        debug!("Leaving /proc alone");
+    }
+    mount_sys(new_root)?;
+    mount_dev(new_root, &namespaces.ipc)
+}
+
+fn mount_proc(new_root: &Path, pid: &NamespaceMode) -> eyre::Result<()> {
+    let target = resolve_in_root(new_root, Path::new("/proc"))?;
+    let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    match mount_filesystem("proc", &target, flags, None) {
+        // Only the user namespace that owns a joined PID namespace may mount its procfs. Binding
+        // the host's procfs instead would hand the container every host process.
+        Err(err) if errors::has_errno(&err, libc::EPERM) && *pid != NamespaceMode::Private => {
+            Err(err.wrap_err(
+                "Not allowed to mount the procfs of the joined PID namespace, pass --no-proc to \
+                 leave /proc alone",
+            ))
+        }
+        result => result,
+    }
+}
+
+fn mount_sys(new_root: &Path) -> eyre::Result<()> {
+    let target = resolve_in_root(new_root, Path::new("/sys"))?;
+    let flags = MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    match mount_filesystem("sysfs", &target, flags, None) {
+        // Mounting sysfs needs privileges over the network namespace, which a shared or joined one
+        // is not owned by
+        Err(err) if errors::has_errno(&err, libc::EPERM) => {
+            debug!("Not allowed to mount sysfs, binding the host's instead");
+            bind_mount(Path::new("/sys"), &target)?;
+            // Along with what the host mounts inside, e.g. cgroupfs, securityfs, and bpffs
+            for mount_point in submounts(&fs::canonicalize(&target)?)? {
+                remount(&mount_point, MsFlags::MS_RDONLY)?;
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn mount_dev(new_root: &Path, ipc: &NamespaceMode) -> eyre::Result<()> {
+    let dev = resolve_in_root(new_root, Path::new("/dev"))?;
+    mount_filesystem(
+        "tmpfs",
+        &dev,
+        MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
+        Some(DEV_OPTIONS),
+    )?;
+
+    for device in DEVICES {
+        bind_mount(&Path::new("/dev").join(device), &dev.join(device))?;
+    }
+    for (link, target) in DEV_SYMLINKS {
+        symlink(target, dev.join(link))?;
+    }
+
+    // A new instance, so that the container cannot see the host's terminals
+    mount_filesystem(
+        "devpts",
+        &dev.join("pts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some(DEVPTS_OPTIONS),
+    )?;
+
+    let ipc_flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    let shm = dev.join("shm");
+    if *ipc == NamespaceMode::Host {
+        bind_mount(Path::new("/dev/shm"), &shm)?;
+    } else {
+        mount_filesystem("tmpfs", &shm, ipc_flags, Some(SHM_OPTIONS))?;
+    }
+
+    let mqueue = dev.join("mqueue");
+    match mount_filesystem("mqueue", &mqueue, ipc_flags, None) {
+        // Like procfs, only the owner of a shared or joined IPC namespace may mount it
+        Err(err) if errors::has_errno(&err, libc::EPERM) && *ipc != NamespaceMode::Private => {
+            if Path::new("/dev/mqueue").is_dir() {
+                bind_mount(Path::new("/dev/mqueue"), &mqueue)
+            } else {
+                debug!("Not allowed to mount mqueue, leaving /dev/mqueue empty");
+                Ok(())
+            }
+        }
+        result => result,
+    }
+}
+
+/// Makes the read-only paths read-only, and hides the masked ones under an empty read-only tmpfs,
+/// or `/dev/null` for files. Paths missing from the container are skipped.
+pub fn restrict_paths(new_root: &Path, config: &MountConfig) -> eyre::Result<()> {
+    for path in &config.read_only_paths {
+        let target = resolve_in_root(new_root, path)?;
+        if !target.exists() {
+            debug!("Not making missing {} read-only", path.display());
+            continue;
+        }
+        bind_mount(&target, &target)?;
+        remount(&target, MsFlags::MS_RDONLY)?;
+    }
+
+    for path in &config.masked_paths {
+        let target = resolve_in_root(new_root, path)?;
+        if !target.exists() {
+            debug!("Not masking missing {}", path.display());
+            continue;
+        }
+        if target.is_dir() {
+            let flags =
+                MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+            mount_filesystem("tmpfs", &target, flags, None)?;
+        } else {
+            bind_mount(Path::new("/dev/null"), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remounts the new root read-only. The other mounts on top of it, like volumes, tmpfs, and
+/// `/dev`, stay as they are.
+pub fn make_root_read_only(new_root: &Path) -> eyre::Result<()> {
+    remount(new_root, MsFlags::MS_RDONLY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn resolves_absolute_symlink_inside_root() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("usr/data")).unwrap();
+        symlink("/usr/data", root.path().join("data")).unwrap();
+        let resolved = resolve_in_root(root.path(), Path::new("/data/volume")).unwrap();
+        assert_eq!(resolved, root.path().join("usr/data/volume"));
+    }
+
+    #[test]
+    fn rejects_parent_dir_symlink_escaping_root() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        symlink("../../..", root.path().join("dir/up")).unwrap();
+        assert!(resolve_in_root(root.path(), Path::new("/dir/up/etc")).is_err());
+    }
+
+    #[test]
+    fn resolves_parent_dir_symlink_within_root() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("a/b")).unwrap();
+        symlink("../c", root.path().join("a/b/link")).unwrap();
+        let resolved = resolve_in_root(root.path(), Path::new("/a/b/link")).unwrap();
+        assert_eq!(resolved, root.path().join("a/c"));
+    }
+
+    #[test]
+    fn rejects_symlink_loop() {
+        let root = TempDir::new().unwrap();
+        symlink("/b", root.path().join("a")).unwrap();
+        symlink("/a", root.path().join("b")).unwrap();
+        assert!(resolve_in_root(root.path(), Path::new("/a/volume")).is_err());
+    }
+
+    #[test]
+    fn unescapes_mountinfo_paths() {
+        assert_eq!(
+            unescape_mountinfo(r"/mnt/with\040space\134x"),
+            Path::new(r"/mnt/with space\x")
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use crate::state::{ContainerState, Status};
+use eyre::{bail, WrapErr};
+use nix::{
+    errno::Errno,
+    libc,
+    sched::{setns, unshare, CloneFlags},
+    time::{clock_gettime, ClockId},
+    unistd::getuid,
+};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{debug, error};
+
+/// An existing namespace to join.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum JoinTarget {
+    /// Namespace file, e.g. `/proc/PID/ns/net`
+    Path(PathBuf),
+    /// Namespace of another bento container, by ID
+    Container(String),
+}
+
+impl JoinTarget {
+    /// Parses `ns:PATH` or `container:ID`, returning `None` for other forms.
+    fn parse(s: &str) -> Option<Self> {
+        if let Some(path) = s.strip_prefix("ns:") {
+            Some(JoinTarget::Path(PathBuf::from(path)))
+        } else {
+            s.strip_prefix("container:")
+                .map(|id| JoinTarget::Container(id.to_owned()))
+        }
+    }
+
+    /// Turns a container ID into the namespace file of its init process, so that the child only
+    /// has to deal with paths.
+    fn resolve(&mut self, kind: &str) -> eyre::Result<()> {
+        if let JoinTarget::Container(id) = self {
+            let state = ContainerState::load(id)?;
+            if state.status == Status::Stopped {
+                bail!("Cannot join the {kind} namespace of stopped container {id}");
+            }
+            let path = PathBuf::from(format!("/proc/{pid}/ns/{kind}", pid = state.pid));
+            debug!(
+                "Resolved {kind} namespace of container {id} to {}",
+                path.display()
+            );
+            *self = JoinTarget::Path(path);
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> eyre::Result<&Path> {
+        match self {
+            JoinTarget::Path(path) => Ok(path),
+            JoinTarget::Container(id) => bail!("BUG: namespace of container {id} is unresolved"),
+        }
+    }
+}
+
+/// Whether the container gets its own namespace, shares the host's, or joins an existing one.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum NamespaceMode {
+    #[default]
+    Private,
+    Host,
+    Join(JoinTarget),
+}
+
+impl FromStr for NamespaceMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match s {
+            "private" => Ok(NamespaceMode::Private),
+            "host" => Ok(NamespaceMode::Host),
+            _ => match JoinTarget::parse(s) {
+                Some(target) => Ok(NamespaceMode::Join(target)),
+                None => {
+                    bail!("Namespace mode {s} should be private, host, ns:PATH, or container:ID")
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum NetworkMode {
+    /// New network namespace with only loopback set up
+    #[default]
+    Private,
+    /// New network namespace that is left unconfigured
+    None,
+    /// New network namespace connected to the host's bento bridge
+    Bridge,
+    /// New network namespace with a tap device, served by a userspace TCP/IP stack in bento
+    User,
+    Host,
+    Join(JoinTarget),
+}
+
+impl NetworkMode {
+    fn namespace_mode(&self) -> NamespaceMode {
+        match self {
+            NetworkMode::Private | NetworkMode::None | NetworkMode::Bridge | NetworkMode::User => {
+                NamespaceMode::Private
+            }
+            NetworkMode::Host => NamespaceMode::Host,
+            NetworkMode::Join(target) => NamespaceMode::Join(target.clone()),
+        }
+    }
+}
+
+impl FromStr for NetworkMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match s {
+            "private" => Ok(NetworkMode::Private),
+            "none" => Ok(NetworkMode::None),
+            "bridge" => Ok(NetworkMode::Bridge),
+            "user" => Ok(NetworkMode::User),
+            "host" => Ok(NetworkMode::Host),
+            _ => match JoinTarget::parse(s) {
+                Some(target) => Ok(NetworkMode::Join(target)),
+                None => bail!(
+                    "Network mode {s} should be private, none, bridge, user, host, ns:PATH, \
+                     or container:ID"
+                ),
+            },
+        }
+    }
+}
+
+/// Clock offsets of a private time namespace, in seconds. A clock without an offset starts near
+/// zero inside the container.
+#[derive(Debug, Clone, Default)]
+pub struct TimeOffsets {
+    pub monotonic: Option<i64>,
+    pub boottime: Option<i64>,
+}
+
+impl TimeOffsets {
+    /// Formats the offsets as lines of `CLOCK SECONDS NANOSECONDS` for `timens_offsets`.
+    fn to_timens_offsets(&self) -> eyre::Result<String> {
+        let mut lines = String::new();
+        for (name, clock, offset) in [
+            ("monotonic", ClockId::CLOCK_MONOTONIC, self.monotonic),
+            ("boottime", ClockId::CLOCK_BOOTTIME, self.boottime),
+        ] {
+            let offset = match offset {
+                Some(offset) => offset,
+                // Rounded towards zero, so that the clock never starts below zero
+                None => -clock_gettime(clock)?.tv_sec(),
+            };
+            lines.push_str(&format!("{name} {offset} 0\n"));
+        }
+        Ok(lines)
+    }
+}
+
+/// Namespaces the container can share with the host or other containers. The cgroup and mount
+/// namespaces are always private, since the container gets its own cgroup and root filesystem.
+#[derive(Debug, Clone, Default)]
+pub struct Namespaces {
+    pub net: NetworkMode,
+    pub pid: NamespaceMode,
+    pub ipc: NamespaceMode,
+    pub uts: NamespaceMode,
+    /// Private time namespace, if any. Unlike the others, the container shares the host's by default
+    pub time: Option<TimeOffsets>,
+}
+
+impl Namespaces {
+    pub fn resolve_containers(&mut self) -> eyre::Result<()> {
+        if let NetworkMode::Join(target) = &mut self.net {
+            target.resolve("net")?;
+        }
+        for (mode, kind) in [
+            (&mut self.pid, "pid"),
+            (&mut self.ipc, "ipc"),
+            (&mut self.uts, "uts"),
+        ] {
+            if let NamespaceMode::Join(target) = mode {
+                target.resolve(kind)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clone_flags(&self) -> CloneFlags {
+        let mut flags = CloneFlags::CLONE_NEWCGROUP | CloneFlags::CLONE_NEWNS;
+        for (mode, flag) in [
+            (self.net.namespace_mode(), CloneFlags::CLONE_NEWNET),
+            (self.pid.clone(), CloneFlags::CLONE_NEWPID),
+            (self.ipc.clone(), CloneFlags::CLONE_NEWIPC),
+            (self.uts.clone(), CloneFlags::CLONE_NEWUTS),
+        ] {
+            if mode == NamespaceMode::Private {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+
+    /// Creates the time namespace from within the child and sets its clock offsets. `CLONE_NEWTIME`
+    /// cannot be passed to `clone`, where it overlaps with the exit signal, and `unshare` only moves
+    /// children into the new namespace. The offsets can only be written until a process enters it,
+    /// which for the child is when it calls `execve`.
+    pub fn create_time_namespace(&self) -> eyre::Result<()> {
+        let Some(offsets) = &self.time else {
+            return Ok(());
+        };
+
+        unshare(CloneFlags::from_bits_retain(libc::CLONE_NEWTIME))
+            .wrap_err("Failed to create time namespace")?;
+        let timens_offsets = offsets.to_timens_offsets()?;
+        debug!("Writing time namespace offsets {timens_offsets:?}");
+        fs::write("/proc/self/timens_offsets", timens_offsets)
+            .wrap_err("Failed to set time namespace offsets")?;
+        Ok(())
+    }
+
+    /// Runs `f`, which clones the child, inside the namespaces to join. Joining a PID namespace only
+    /// affects processes created afterwards, and once the child is in its own user namespace it no
+    /// longer has privileges over the others, so all of them are joined by bento itself. The
+    /// original namespaces are restored afterwards, also when joining one of them fails.
+    pub fn with_joined_namespaces<T>(
+        &self,
+        f: impl FnOnce() -> eyre::Result<T>,
+    ) -> eyre::Result<T> {
+        let mut joined = JoinedNamespaces(vec![]);
+        for (mode, kind, flag) in [
+            (self.net.namespace_mode(), "net", CloneFlags::CLONE_NEWNET),
+            (self.pid.clone(), "pid", CloneFlags::CLONE_NEWPID),
+            (self.ipc.clone(), "ipc", CloneFlags::CLONE_NEWIPC),
+            (self.uts.clone(), "uts", CloneFlags::CLONE_NEWUTS),
+        ] {
+            if let NamespaceMode::Join(target) = mode {
+                let original = File::open(format!("/proc/self/ns/{kind}"))?;
+                join_namespace(target.path()?, kind, flag)?;
+                joined.0.push((original, kind, flag));
+            }
+        }
+
+        let result = f();
+        joined.restore()?;
+        result
+    }
+}
+
+/// The original namespaces of bento, which are restored when dropped, so that tearing down a
+/// container that failed to start happens in them.
+struct JoinedNamespaces(Vec<(File, &'static str, CloneFlags)>);
+
+impl JoinedNamespaces {
+    fn restore(&mut self) -> eyre::Result<()> {
+        for (original, kind, flag) in self.0.drain(..) {
+            setns(original, flag)
+                .wrap_err_with(|| format!("Failed to restore the original {kind} namespace"))?;
+            debug!("Restored original {kind} namespace");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for JoinedNamespaces {
+    fn drop(&mut self) {
+        if let Err(err) = self.restore() {
+            error!("{err:#}");
+        }
+    }
+}
+
+/// Joining a namespace needs CAP_SYS_ADMIN in bento's own user namespace, which only root has.
+fn join_namespace(path: &Path, kind: &str, flag: CloneFlags) -> eyre::Result<()> {
+    debug!("Joining namespace {}", path.display());
+    let file = File::open(path)
+        .wrap_err_with(|| format!("Failed to open namespace {}", path.display()))?;
+    match setns(file, flag) {
+        Err(Errno::EPERM) if !getuid().is_root() => {
+            bail!("Joining the {kind} namespace {} needs root", path.display())
+        }
+        result => {
+            result.wrap_err_with(|| format!("Failed to join namespace {}", path.display()))?
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use crate::errors::has_errno;
+use eyre::{bail, eyre, WrapErr};
+use netlink_packet_core::{
+    NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL,
+    NLM_F_REQUEST,
+};
+use netlink_packet_route::{
+    nlas::{
+        address,
+        link::{self, Info, InfoData, InfoKind, VethInfo},
+        route,
+    },
+    AddressMessage, LinkMessage, RouteMessage, RtnlMessage, AF_INET, AF_INET6, IFF_UP, RTN_UNICAST,
+    RTPROT_BOOT, RT_SCOPE_UNIVERSE, RT_TABLE_MAIN,
+};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use nix::{libc, unistd::Pid};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+};
+use tracing::debug;
+
+/// A route netlink socket, used instead of shelling out to `ip`.
+pub struct Netlink {
+    socket: Socket,
+    sequence_number: u32,
+}
+
+impl Netlink {
+    /// Connects to the kernel in the current network namespace.
+    pub fn connect() -> eyre::Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE).wrap_err("Failed to open netlink socket")?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(Self {
+            socket,
+            sequence_number: 0,
+        })
+    }
+
+    /// Sends a request and returns its replies, failing if the kernel reports an error.
+    fn request(&mut self, message: RtnlMessage, flags: u16) -> eyre::Result<Vec<RtnlMessage>> {
+        self.sequence_number += 1;
+        let mut header = NetlinkHeader::default();
+        header.flags = NLM_F_REQUEST | NLM_F_ACK | flags;
+        header.sequence_number = self.sequence_number;
+        let mut request = NetlinkMessage::new(header, NetlinkPayload::from(message));
+        request.finalize();
+
+        let mut buf = vec![0; request.buffer_len()];
+        request.serialize(&mut buf);
+        self.socket.send(&buf, 0)?;
+
+        let mut replies = vec![];
+        loop {
+            let (buf, _) = self.socket.recv_from_full()?;
+            let mut offset = 0;
+            while offset < buf.len() {
+                let reply = NetlinkMessage::<RtnlMessage>::deserialize(&buf[offset..])
+                    .map_err(|err| eyre!("Failed to parse netlink reply: {err}"))?;
+                offset += reply.header.length as usize;
+                // Leftover replies to earlier requests
+                if reply.header.sequence_number != self.sequence_number {
+                    continue;
+                }
+
+                match reply.payload {
+                    NetlinkPayload::Error(err) => {
+                        return match err.code {
+                            None => Ok(replies),
+                            Some(_) => Err(io::Error::from(err).into()),
+                        };
+                    }
+                    NetlinkPayload::Done(_) => return Ok(replies),
+                    NetlinkPayload::InnerMessage(message) => replies.push(message),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn link_index(&mut self, name: &str) -> eyre::Result<u32> {
+        self.find_link(name)?
+            .ok_or_else(|| eyre!("No link named {name}"))
+    }
+
+    /// Returns `None` if there is no link with the name.
+    pub fn find_link(&mut self, name: &str) -> eyre::Result<Option<u32>> {
+        let mut message = LinkMessage::default();
+        message.nlas.push(link::Nla::IfName(name.to_owned()));
+        let replies = match self.request(RtnlMessage::GetLink(message), 0) {
+            Ok(replies) => replies,
+            Err(err) if has_errno(&err, libc::ENODEV) => return Ok(None),
+            Err(err) => return Err(err.wrap_err(format!("Failed to find link {name}"))),
+        };
+        match replies.first() {
+            Some(RtnlMessage::NewLink(link)) => Ok(Some(link.header.index)),
+            _ => bail!("Unexpected reply when looking up link {name}"),
+        }
+    }
+
+    /// Creates a bridge, or returns the existing link with the name.
+    pub fn ensure_bridge(&mut self, name: &str) -> eyre::Result<u32> {
+        if let Some(index) = self.find_link(name)? {
+            return Ok(index);
+        }
+
+        debug!("Creating bridge {name}");
+        let mut message = LinkMessage::default();
+        message.nlas.push(link::Nla::IfName(name.to_owned()));
+        message
+            .nlas
+            .push(link::Nla::Info(vec![Info::Kind(InfoKind::Bridge)]));
+        match self.request(RtnlMessage::NewLink(message), NLM_F_CREATE | NLM_F_EXCL) {
+            // Another container created it concurrently
+            Err(err) if has_errno(&err, libc::EEXIST) => {}
+            result => {
+                result.wrap_err_with(|| format!("Failed to create bridge {name}"))?;
+            }
+        }
+        self.link_index(name)
+    }
+
+    /// Creates a veth pair whose peer is created directly in the network namespace of `peer_pid`,
+    /// so that its name cannot clash with links of the current namespace. Returns the index of
+    /// the local end.
+    pub fn create_veth(&mut self, name: &str, peer_name: &str, peer_pid: Pid) -> eyre::Result<u32> {
+        debug!("Creating veth pair {name} and {peer_name} in namespace of PID {peer_pid}");
+        let mut peer = LinkMessage::default();
+        peer.nlas.push(link::Nla::IfName(peer_name.to_owned()));
+        peer.nlas
+            .push(link::Nla::NetNsPid(peer_pid.as_raw().try_into()?));
+
+        let mut message = LinkMessage::default();
+        message.nlas.push(link::Nla::IfName(name.to_owned()));
+        message.nlas.push(link::Nla::Info(vec![
+            Info::Kind(InfoKind::Veth),
+            Info::Data(InfoData::Veth(VethInfo::Peer(peer))),
+        ]));
+        self.request(RtnlMessage::NewLink(message), NLM_F_CREATE | NLM_F_EXCL)
+            .wrap_err_with(|| format!("Failed to create veth pair {name}"))?;
+        self.link_index(name)
+    }
+
+    /// Attaches a link to a bridge.
+    pub fn set_master(&mut self, index: u32, master_index: u32) -> eyre::Result<()> {
+        debug!("Attaching link {index} to link {master_index}");
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        message.nlas.push(link::Nla::Master(master_index));
+        self.request(RtnlMessage::SetLink(message), 0)
+            .wrap_err_with(|| format!("Failed to attach link {index} to link {master_index}"))?;
+        Ok(())
+    }
+
+    pub fn set_link_up(&mut self, index: u32) -> eyre::Result<()> {
+        debug!("Setting link {index} up");
+        let mut message = LinkMessage::default();
+        message.header.index = index;
+        message.header.flags = IFF_UP;
+        message.header.change_mask = IFF_UP;
+        self.request(RtnlMessage::SetLink(message), 0)
+            .wrap_err_with(|| format!("Failed to set link {index} up"))?;
+        Ok(())
+    }
+
+    /// Adds an address, failing with `EEXIST` if the link already has it.
+    pub fn add_address(&mut self, index: u32, address: IpAddr, prefix_len: u8) -> eyre::Result<()> {
+        debug!("Adding address {address}/{prefix_len} to link {index}");
+        let mut message = AddressMessage::default();
+        message.header.prefix_len = prefix_len;
+        message.header.index = index;
+        match address {
+            IpAddr::V4(address) => {
+                message.header.family = AF_INET as u8;
+                let octets = address.octets().to_vec();
+                message.nlas.push(address::Nla::Local(octets.clone()));
+                message.nlas.push(address::Nla::Address(octets));
+            }
+            IpAddr::V6(address) => {
+                message.header.family = AF_INET6 as u8;
+                message
+                    .nlas
+                    .push(address::Nla::Address(address.octets().to_vec()));
+            }
+        }
+        self.request(RtnlMessage::NewAddress(message), NLM_F_CREATE | NLM_F_EXCL)?;
+        Ok(())
+    }
+
+    pub fn add_default_route(&mut self, gateway: Ipv4Addr) -> eyre::Result<()> {
+        debug!("Adding default route via {gateway}");
+        let mut message = RouteMessage::default();
+        message.header.address_family = AF_INET as u8;
+        message.header.table = RT_TABLE_MAIN;
+        message.header.protocol = RTPROT_BOOT;
+        message.header.scope = RT_SCOPE_UNIVERSE;
+        message.header.kind = RTN_UNICAST;
+        message
+            .nlas
+            .push(route::Nla::Gateway(gateway.octets().to_vec()));
+        self.request(RtnlMessage::NewRoute(message), NLM_F_CREATE | NLM_F_EXCL)
+            .wrap_err_with(|| format!("Failed to add default route via {gateway}"))?;
+        Ok(())
+    }
+}
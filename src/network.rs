@@ -0,0 +1,304 @@
+use crate::{
+    egress::EgressPolicy, errors, ipam, mounts, netlink::Netlink, nftables, ports::PortMapping,
+};
+use eyre::{eyre, WrapErr};
+use ipnet::Ipv4Net;
+use nix::{
+    libc,
+    sched::{setns, CloneFlags},
+    unistd::{gethostname, Pid},
+};
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
+use tracing::debug;
+
+/// An extra `/etc/hosts` entry, written as `HOST:IP`, e.g. `db:10.0.0.2`.
+#[derive(Debug, Clone)]
+pub struct HostEntry {
+    hostname: String,
+    ip: IpAddr,
+}
+
+impl FromStr for HostEntry {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        // Split on the first colon, since IPv6 addresses contain colons themselves
+        let (hostname, ip_str) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("Host entry {s} should be HOST:IP"))?;
+        let ip = ip_str
+            .parse()
+            .wrap_err_with(|| format!("Invalid IP address {ip_str}"))?;
+        Ok(Self {
+            hostname: hostname.to_owned(),
+            ip,
+        })
+    }
+}
+
+/// Network settings of the container, besides its network namespace.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    /// Nameservers, taken from the host if empty
+    pub dns: Vec<IpAddr>,
+    /// Search domains, taken from the host if empty
+    pub dns_search: Vec<String>,
+    pub extra_hosts: Vec<HostEntry>,
+    /// Subnet of the bridge, whose first address is the gateway
+    pub subnet: Ipv4Net,
+    /// Whether to masquerade traffic from the bridge to other interfaces
+    pub nat: bool,
+    /// Whether the gateway of user networking leads to the host's loopback address
+    pub allow_host_loopback: bool,
+    pub published_ports: Vec<PortMapping>,
+    pub egress: EgressPolicy,
+}
+
+fn add_address_if_missing(
+    netlink: &mut Netlink,
+    index: u32,
+    address: IpAddr,
+    prefix_len: u8,
+) -> eyre::Result<()> {
+    match netlink.add_address(index, address, prefix_len) {
+        Err(err) if errors::has_errno(&err, libc::EEXIST) => Ok(()),
+        result => result,
+    }
+}
+
+/// Sets `lo` up, which a new network namespace starts without.
+pub fn bring_up_loopback() -> eyre::Result<()> {
+    let mut netlink = Netlink::connect()?;
+    let index = netlink.link_index("lo")?;
+    netlink.set_link_up(index)?;
+
+    // Setting lo up usually adds these addresses already
+    for (address, prefix_len) in [
+        (IpAddr::V4(Ipv4Addr::LOCALHOST), 8),
+        (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    ] {
+        add_address_if_missing(&mut netlink, index, address, prefix_len)?;
+    }
+    debug!("Loopback is up");
+    Ok(())
+}
+
+const HOST_RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+/// Nameservers and search domains of the host.
+pub fn host_resolv_conf() -> eyre::Result<(Vec<IpAddr>, Vec<String>)> {
+    let contents = match fs::read_to_string(HOST_RESOLV_CONF_PATH) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((vec![], vec![])),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut nameservers = vec![];
+    let mut search_domains = vec![];
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("nameserver") => {
+                nameservers.extend(words.next().and_then(|word| word.parse::<IpAddr>().ok()))
+            }
+            Some("search") => search_domains = words.map(str::to_owned).collect(),
+            _ => {}
+        }
+    }
+    Ok((nameservers, search_domains))
+}
+
+/// Writes `/etc/hosts`, `/etc/hostname`, and `/etc/resolv.conf` into the new root. Has to run
+/// after the hostname is set and before switching root, since it reads the host's resolver.
+pub fn write_etc_files(new_root: &Path, config: &NetworkConfig) -> eyre::Result<()> {
+    // Resolved inside the new root, since an imported layer may link /etc or the files in it to
+    // the host's
+    let etc_dir = mounts::resolve_in_root(new_root, Path::new("/etc"))?;
+    fs::create_dir_all(&etc_dir)?;
+
+    let hostname = gethostname()?.to_string_lossy().into_owned();
+    let mut hosts = format!(
+        "127.0.0.1\tlocalhost\n\
+         ::1\tlocalhost ip6-localhost ip6-loopback\n\
+         127.0.1.1\t{hostname}\n"
+    );
+    for HostEntry { hostname, ip } in &config.extra_hosts {
+        hosts.push_str(&format!("{ip}\t{hostname}\n"));
+    }
+
+    let (mut host_nameservers, host_search_domains) = host_resolv_conf()?;
+    // Loopback nameservers are unreachable from another network namespace
+    host_nameservers.retain(|ip| !ip.is_loopback());
+    let nameservers = if config.dns.is_empty() {
+        &host_nameservers
+    } else {
+        &config.dns
+    };
+    let search_domains = if config.dns_search.is_empty() {
+        &host_search_domains
+    } else {
+        &config.dns_search
+    };
+    let mut resolv_conf = String::new();
+    for nameserver in nameservers {
+        resolv_conf.push_str(&format!("nameserver {nameserver}\n"));
+    }
+    if !search_domains.is_empty() {
+        resolv_conf.push_str(&format!("search {}\n", search_domains.join(" ")));
+    }
+
+    for (file_name, contents) in [
+        ("hosts", hosts),
+        ("hostname", format!("{hostname}\n")),
+        ("resolv.conf", resolv_conf),
+    ] {
+        let path = mounts::resolve_in_root(new_root, &Path::new("/etc").join(file_name))?;
+        debug!("Writing {}", path.display());
+        fs::write(&path, contents)
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+pub const BRIDGE_NAME: &str = "bento0";
+const CONTAINER_INTERFACE_NAME: &str = "eth0";
+
+/// Runs `f` in the network namespace of `pid`, and restores the original one afterwards.
+fn in_network_namespace<T>(pid: Pid, f: impl FnOnce() -> eyre::Result<T>) -> eyre::Result<T> {
+    let original = File::open("/proc/self/ns/net")?;
+    let namespace = File::open(format!("/proc/{pid}/ns/net"))?;
+    setns(namespace, CloneFlags::CLONE_NEWNET)
+        .wrap_err_with(|| format!("Failed to enter network namespace of PID {pid}"))?;
+    let result = f();
+    setns(original, CloneFlags::CLONE_NEWNET)
+        .wrap_err("Failed to restore the original network namespace")?;
+    result
+}
+
+/// Connects the container to the bridge through a veth pair, and returns its leased address.
+/// Runs in the parent, since creating links on the host needs privileges there.
+pub fn connect_to_bridge(
+    id: &str,
+    child_pid: Pid,
+    config: &NetworkConfig,
+) -> eyre::Result<Ipv4Addr> {
+    let address = ipam::lease(id, config.subnet)?;
+    match set_up_bridge_and_veth(id, child_pid, config, address) {
+        Ok(()) => Ok(address),
+        Err(err) => {
+            disconnect_from_bridge(id)?;
+            Err(err)
+        }
+    }
+}
+
+/// Releases the container's address and removes its egress policy. The veth pair goes away
+/// together with the container's network namespace.
+pub fn disconnect_from_bridge(id: &str) -> eyre::Result<()> {
+    nftables::remove_egress_policy(id)?;
+    ipam::release(id)
+}
+
+fn set_up_bridge_and_veth(
+    id: &str,
+    child_pid: Pid,
+    config: &NetworkConfig,
+    address: Ipv4Addr,
+) -> eyre::Result<()> {
+    let subnet = config.subnet;
+    let gateway = subnet
+        .hosts()
+        .next()
+        .ok_or_else(|| eyre!("Subnet {subnet} is too small"))?;
+
+    let mut netlink = Netlink::connect()?;
+    let bridge_index = netlink.ensure_bridge(BRIDGE_NAME)?;
+    add_address_if_missing(
+        &mut netlink,
+        bridge_index,
+        IpAddr::V4(gateway),
+        subnet.prefix_len(),
+    )?;
+    netlink.set_link_up(bridge_index)?;
+
+    // Unique for as long as the address is leased
+    let host_interface_name = format!("vb{:08x}", u32::from(address));
+    let host_index =
+        netlink.create_veth(&host_interface_name, CONTAINER_INTERFACE_NAME, child_pid)?;
+    netlink.set_master(host_index, bridge_index)?;
+    // Before the link is up, so that no packet leaves unfiltered
+    if !config.egress.is_empty() {
+        nftables::apply_egress_policy(
+            id,
+            BRIDGE_NAME,
+            &host_interface_name,
+            address,
+            subnet,
+            gateway,
+            &config.egress,
+        )?;
+    }
+    netlink.set_link_up(host_index)?;
+
+    in_network_namespace(child_pid, || {
+        let mut netlink = Netlink::connect()?;
+        let index = netlink.link_index(CONTAINER_INTERFACE_NAME)?;
+        netlink.add_address(index, IpAddr::V4(address), subnet.prefix_len())?;
+        netlink.set_link_up(index)?;
+        netlink.add_default_route(gateway)
+    })?;
+    debug!("Connected {host_interface_name} with address {address} to {BRIDGE_NAME}");
+
+    if config.nat {
+        fs::write("/proc/sys/net/ipv4/ip_forward", "1")
+            .wrap_err("Failed to enable IP forwarding")?;
+        nftables::enable_masquerade(BRIDGE_NAME)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn writes_etc_files_inside_root_through_absolute_symlink() {
+        // Stands in for the host's /etc, which a layer would link to with `etc -> /etc`
+        let host = TempDir::new().unwrap();
+        let host_etc = host.path().join("etc");
+        fs::create_dir(&host_etc).unwrap();
+        let root = TempDir::new().unwrap();
+        symlink(&host_etc, root.path().join("etc")).unwrap();
+
+        let config = NetworkConfig {
+            dns: vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53))],
+            dns_search: vec![],
+            extra_hosts: vec![],
+            subnet: "10.88.0.0/16".parse().unwrap(),
+            nat: false,
+            allow_host_loopback: false,
+            published_ports: vec![],
+            egress: EgressPolicy::default(),
+        };
+        write_etc_files(root.path(), &config).unwrap();
+
+        assert_eq!(fs::read_dir(&host_etc).unwrap().count(), 0);
+        let etc_dir = mounts::resolve_in_root(root.path(), Path::new("/etc")).unwrap();
+        assert!(etc_dir.starts_with(root.path()));
+        for file_name in ["hosts", "hostname", "resolv.conf"] {
+            assert!(etc_dir.join(file_name).is_file());
+        }
+        assert_eq!(
+            fs::read_to_string(etc_dir.join("resolv.conf")).unwrap(),
+            "nameserver 192.0.2.53\n"
+        );
+    }
+}
@@ -0,0 +1,641 @@
+use crate::{
+    egress::{EgressPolicy, EgressRule},
+    errors,
+};
+use eyre::WrapErr;
+use ipnet::Ipv4Net;
+use netlink_sys::{protocols::NETLINK_NETFILTER, Socket, SocketAddr};
+use nix::libc;
+use std::{io, net::Ipv4Addr};
+use tracing::debug;
+
+// Message types and flags from linux/netlink.h and linux/netfilter/nfnetlink.h
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLA_F_NESTED: u16 = 0x8000;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+
+// Message types and attributes from linux/netfilter/nf_tables.h
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_HOOK_DEV: u16 = 3;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_CT_DREG: u16 = 1;
+const NFTA_CT_KEY: u16 = 2;
+const NFTA_LOG_PREFIX: u16 = 2;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_META_PROTOCOL: u32 = 1;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_CT_STATE: u32 = 0;
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+
+// Connection tracking states as bits, from linux/netfilter/nf_conntrack_common.h
+const NF_CT_STATE_ESTABLISHED: u32 = 1 << 1;
+const NF_CT_STATE_RELATED: u32 = 1 << 2;
+
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NF_INET_PRE_ROUTING: u32 = 0;
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_INET_INGRESS: u32 = 5;
+const NF_IP_PRI_MANGLE: i32 = -150;
+const NF_IP_PRI_FILTER: i32 = 0;
+const NF_IP_PRI_NAT_SRC: i32 = 100;
+const IFNAMSIZ: usize = 16;
+const NF_LOG_PREFIXLEN: usize = 128;
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Netlink attributes, appended in order and padded to 4 bytes.
+#[derive(Debug, Default)]
+pub struct Attributes(Vec<u8>);
+
+impl Attributes {
+    pub fn bytes(mut self, kind: u16, value: &[u8]) -> Self {
+        let len = u16::try_from(4 + value.len()).expect("netlink attribute is too large");
+        self.0.extend(len.to_ne_bytes());
+        self.0.extend(kind.to_ne_bytes());
+        self.0.extend(value);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+        self
+    }
+
+    pub fn string(self, kind: u16, value: &str) -> Self {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.bytes(kind, &bytes)
+    }
+
+    /// Integers in nftables attributes are big endian.
+    pub fn be32(self, kind: u16, value: u32) -> Self {
+        self.bytes(kind, &value.to_be_bytes())
+    }
+
+    pub fn nested(self, kind: u16, value: Attributes) -> Self {
+        self.bytes(kind | NLA_F_NESTED, &value.0)
+    }
+}
+
+/// An expression of a rule, evaluated in order against each packet.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    /// Loads the input interface name into register 1
+    IifName,
+    /// Loads the output interface name into register 1
+    OifName,
+    /// Loads the EtherType of the packet, e.g. IPv4, into register 1
+    Protocol,
+    /// Loads the transport protocol number, e.g. TCP, into register 1
+    L4Proto,
+    /// Loads the IPv4 source address into register 1
+    Ipv4SrcAddr,
+    /// Loads the IPv4 destination address into register 1
+    Ipv4DstAddr,
+    /// Loads the TCP or UDP destination port into register 1
+    DstPort,
+    /// Loads the connection tracking state bits into register 1
+    CtState,
+    /// Masks register 1 with the data
+    And(Vec<u8>),
+    /// Stops evaluating the rule unless register 1 equals the data
+    Eq(Vec<u8>),
+    /// Stops evaluating the rule if register 1 equals the data
+    NotEq(Vec<u8>),
+    /// Rewrites the source address to the one of the output interface
+    Masquerade,
+    /// Logs the packet to the kernel log, after the prefix
+    Log(String),
+    Accept,
+    Drop,
+}
+
+/// Interface names are compared as the kernel stores them, padded with nul bytes.
+pub fn interface_name(name: &str) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.resize(IFNAMSIZ, 0);
+    bytes
+}
+
+impl Expr {
+    fn to_attributes(&self) -> Attributes {
+        let (name, data) = match self {
+            Expr::IifName => ("meta", meta(NFT_META_IIFNAME)),
+            Expr::OifName => ("meta", meta(NFT_META_OIFNAME)),
+            Expr::Protocol => ("meta", meta(NFT_META_PROTOCOL)),
+            Expr::L4Proto => ("meta", meta(NFT_META_L4PROTO)),
+            Expr::Ipv4SrcAddr => ("payload", payload(NFT_PAYLOAD_NETWORK_HEADER, 12, 4)),
+            Expr::Ipv4DstAddr => ("payload", payload(NFT_PAYLOAD_NETWORK_HEADER, 16, 4)),
+            Expr::DstPort => ("payload", payload(NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2)),
+            Expr::CtState => (
+                "ct",
+                Attributes::default()
+                    .be32(NFTA_CT_KEY, NFT_CT_STATE)
+                    .be32(NFTA_CT_DREG, NFT_REG_1),
+            ),
+            Expr::And(mask) => ("bitwise", bitwise_and(mask)),
+            Expr::Eq(value) => ("cmp", cmp(NFT_CMP_EQ, value)),
+            Expr::NotEq(value) => ("cmp", cmp(NFT_CMP_NEQ, value)),
+            Expr::Masquerade => ("masq", Attributes::default()),
+            Expr::Log(prefix) => ("log", Attributes::default().string(NFTA_LOG_PREFIX, prefix)),
+            Expr::Accept => ("immediate", verdict(NF_ACCEPT)),
+            Expr::Drop => ("immediate", verdict(NF_DROP)),
+        };
+        Attributes::default()
+            .string(NFTA_EXPR_NAME, name)
+            .nested(NFTA_EXPR_DATA, data)
+    }
+}
+
+fn meta(key: u32) -> Attributes {
+    Attributes::default()
+        .be32(NFTA_META_KEY, key)
+        .be32(NFTA_META_DREG, NFT_REG_1)
+}
+
+/// Offsets are relative to the start of the header at `base`.
+fn payload(base: u32, offset: u32, len: u32) -> Attributes {
+    Attributes::default()
+        .be32(NFTA_PAYLOAD_DREG, NFT_REG_1)
+        .be32(NFTA_PAYLOAD_BASE, base)
+        .be32(NFTA_PAYLOAD_OFFSET, offset)
+        .be32(NFTA_PAYLOAD_LEN, len)
+}
+
+/// The kernel computes `(register & mask) ^ xor`, so a zero xor leaves just the mask.
+fn bitwise_and(mask: &[u8]) -> Attributes {
+    Attributes::default()
+        .be32(NFTA_BITWISE_SREG, NFT_REG_1)
+        .be32(NFTA_BITWISE_DREG, NFT_REG_1)
+        .be32(NFTA_BITWISE_LEN, mask.len() as u32)
+        .nested(
+            NFTA_BITWISE_MASK,
+            Attributes::default().bytes(NFTA_DATA_VALUE, mask),
+        )
+        .nested(
+            NFTA_BITWISE_XOR,
+            Attributes::default().bytes(NFTA_DATA_VALUE, &vec![0; mask.len()]),
+        )
+}
+
+fn verdict(code: u32) -> Attributes {
+    let verdict = Attributes::default().be32(NFTA_VERDICT_CODE, code);
+    Attributes::default()
+        .be32(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT)
+        .nested(
+            NFTA_IMMEDIATE_DATA,
+            Attributes::default().nested(NFTA_DATA_VERDICT, verdict),
+        )
+}
+
+fn cmp(op: u32, value: &[u8]) -> Attributes {
+    Attributes::default()
+        .be32(NFTA_CMP_SREG, NFT_REG_1)
+        .be32(NFTA_CMP_OP, op)
+        .nested(
+            NFTA_CMP_DATA,
+            Attributes::default().bytes(NFTA_DATA_VALUE, value),
+        )
+}
+
+/// A base chain, attached to a netfilter hook.
+#[derive(Debug)]
+pub struct Chain<'a> {
+    pub name: &'a str,
+    /// `filter`, `nat`, or `route`
+    pub chain_type: &'a str,
+    pub hook: u32,
+    pub priority: i32,
+    /// Interface of an ingress hook
+    pub device: Option<&'a str>,
+}
+
+/// A transaction of nftables changes, which the kernel applies atomically.
+#[derive(Debug)]
+pub struct Batch {
+    family: u8,
+    messages: Vec<(u16, u16, Attributes)>,
+}
+
+impl Batch {
+    pub fn new(family: u8) -> Self {
+        Self {
+            family,
+            messages: vec![],
+        }
+    }
+
+    /// Creates the table if it does not exist yet.
+    pub fn add_table(&mut self, table: &str) -> &mut Self {
+        self.push(
+            NFT_MSG_NEWTABLE,
+            NLM_F_CREATE,
+            Attributes::default().string(NFTA_TABLE_NAME, table),
+        )
+    }
+
+    pub fn delete_table(&mut self, table: &str) -> &mut Self {
+        self.push(
+            NFT_MSG_DELTABLE,
+            0,
+            Attributes::default().string(NFTA_TABLE_NAME, table),
+        )
+    }
+
+    /// Deletes the table together with its chains and rules, and creates it again empty. This
+    /// makes setting up a table idempotent, since adding it first ensures it exists.
+    pub fn recreate_table(&mut self, table: &str) -> &mut Self {
+        self.add_table(table).delete_table(table).add_table(table)
+    }
+
+    pub fn add_chain(&mut self, table: &str, chain: &Chain) -> &mut Self {
+        let mut hook = Attributes::default()
+            .be32(NFTA_HOOK_HOOKNUM, chain.hook)
+            .be32(NFTA_HOOK_PRIORITY, chain.priority as u32);
+        if let Some(device) = chain.device {
+            hook = hook.string(NFTA_HOOK_DEV, device);
+        }
+        self.push(
+            NFT_MSG_NEWCHAIN,
+            NLM_F_CREATE,
+            Attributes::default()
+                .string(NFTA_CHAIN_TABLE, table)
+                .string(NFTA_CHAIN_NAME, chain.name)
+                .nested(NFTA_CHAIN_HOOK, hook)
+                .string(NFTA_CHAIN_TYPE, chain.chain_type),
+        )
+    }
+
+    /// Appends a rule to the end of the chain.
+    pub fn add_rule(&mut self, table: &str, chain: &str, exprs: &[Expr]) -> &mut Self {
+        let list = exprs.iter().fold(Attributes::default(), |list, expr| {
+            list.nested(NFTA_LIST_ELEM, expr.to_attributes())
+        });
+        self.push(
+            NFT_MSG_NEWRULE,
+            NLM_F_CREATE | NLM_F_APPEND,
+            Attributes::default()
+                .string(NFTA_RULE_TABLE, table)
+                .string(NFTA_RULE_CHAIN, chain)
+                .nested(NFTA_RULE_EXPRESSIONS, list),
+        )
+    }
+
+    fn push(&mut self, message_type: u16, flags: u16, attributes: Attributes) -> &mut Self {
+        self.messages.push((message_type, flags, attributes));
+        self
+    }
+
+    /// Sends the batch, failing with the first error the kernel reports.
+    pub fn commit(&self) -> eyre::Result<()> {
+        let mut socket =
+            Socket::new(NETLINK_NETFILTER).wrap_err("Failed to open nfnetlink socket")?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+
+        // Batch messages carry the subsystem in the resource ID
+        let mut buf = message(
+            NFNL_MSG_BATCH_BEGIN,
+            NLM_F_REQUEST,
+            0,
+            0,
+            NFNL_SUBSYS_NFTABLES,
+            &[],
+        );
+        for (i, (message_type, flags, attributes)) in self.messages.iter().enumerate() {
+            buf.extend(message(
+                (NFNL_SUBSYS_NFTABLES << 8) | message_type,
+                NLM_F_REQUEST | NLM_F_ACK | flags,
+                i as u32 + 1,
+                self.family,
+                0,
+                &attributes.0,
+            ));
+        }
+        buf.extend(message(
+            NFNL_MSG_BATCH_END,
+            NLM_F_REQUEST,
+            self.messages.len() as u32 + 1,
+            0,
+            NFNL_SUBSYS_NFTABLES,
+            &[],
+        ));
+        debug!(
+            "Committing nftables batch of {} messages",
+            self.messages.len()
+        );
+        socket.send(&buf, 0)?;
+
+        let mut acks = 0;
+        while acks < self.messages.len() {
+            let (buf, _) = socket.recv_from_full()?;
+            let mut offset = 0;
+            while offset + 20 <= buf.len() {
+                let len = u32::from_ne_bytes(buf[offset..offset + 4].try_into()?) as usize;
+                let message_type = u16::from_ne_bytes(buf[offset + 4..offset + 6].try_into()?);
+                if message_type == NLMSG_ERROR {
+                    let code = i32::from_ne_bytes(buf[offset + 16..offset + 20].try_into()?);
+                    if code != 0 {
+                        return Err(io::Error::from_raw_os_error(-code))
+                            .wrap_err("nftables rejected the batch");
+                    }
+                    acks += 1;
+                }
+                if len == 0 {
+                    break;
+                }
+                offset += len.next_multiple_of(4);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A netlink message with a `struct nfgenmsg` header.
+fn message(
+    message_type: u16,
+    flags: u16,
+    sequence_number: u32,
+    family: u8,
+    resource_id: u16,
+    attributes: &[u8],
+) -> Vec<u8> {
+    let len = 16 + 4 + attributes.len();
+    let mut buf = Vec::with_capacity(len);
+    buf.extend((len as u32).to_ne_bytes());
+    buf.extend(message_type.to_ne_bytes());
+    buf.extend(flags.to_ne_bytes());
+    buf.extend(sequence_number.to_ne_bytes());
+    buf.extend(0u32.to_ne_bytes());
+    buf.push(family);
+    buf.push(0); // NFNETLINK_V0
+    buf.extend(resource_id.to_be_bytes());
+    buf.extend(attributes);
+    buf
+}
+
+const NAT_TABLE: &str = "bento_nat";
+
+/// Masquerades traffic leaving the bridge for other interfaces, so that containers can reach
+/// beyond the host with private addresses.
+pub fn enable_masquerade(bridge: &str) -> eyre::Result<()> {
+    let chain = Chain {
+        name: "postrouting",
+        chain_type: "nat",
+        hook: NF_INET_POST_ROUTING,
+        priority: NF_IP_PRI_NAT_SRC,
+        device: None,
+    };
+    Batch::new(NFPROTO_IPV4)
+        .recreate_table(NAT_TABLE)
+        .add_chain(NAT_TABLE, &chain)
+        .add_rule(
+            NAT_TABLE,
+            chain.name,
+            &[
+                Expr::IifName,
+                Expr::Eq(interface_name(bridge)),
+                Expr::OifName,
+                Expr::NotEq(interface_name(bridge)),
+                Expr::Masquerade,
+            ],
+        )
+        .commit()
+        .wrap_err("Failed to set up NAT masquerading")?;
+    debug!("Masquerading traffic from {bridge}");
+    Ok(())
+}
+
+fn egress_table(id: &str) -> String {
+    format!("bento_egress_{id}")
+}
+
+/// Expressions that match the destinations of a rule, as one alternative per transport protocol if
+/// it has a port.
+fn destination_matches(rule: &EgressRule) -> Vec<Vec<Expr>> {
+    let mut exprs = vec![];
+    if let Some(network) = rule.network.filter(|network| network.prefix_len() > 0) {
+        exprs.extend([
+            Expr::Ipv4DstAddr,
+            Expr::And(network.netmask().octets().to_vec()),
+            Expr::Eq(network.network().octets().to_vec()),
+        ]);
+    }
+    match rule.port {
+        Some(port) => [IPPROTO_TCP, IPPROTO_UDP]
+            .into_iter()
+            .map(|protocol| {
+                let mut exprs = exprs.clone();
+                exprs.extend([
+                    Expr::L4Proto,
+                    Expr::Eq(vec![protocol]),
+                    Expr::DstPort,
+                    Expr::Eq(port.to_be_bytes().to_vec()),
+                ]);
+                exprs
+            })
+            .collect(),
+        None => vec![exprs],
+    }
+}
+
+/// Appends rules that accept packets to allowed destinations, and log and drop packets to denied
+/// ones, once `matches` matched the container's packets.
+fn add_policy_rules(
+    batch: &mut Batch,
+    table: &str,
+    chain: &str,
+    matches: &[Expr],
+    policy: &EgressPolicy,
+    log_prefix: &str,
+) {
+    let verdicts = [
+        (&policy.allow, vec![Expr::Accept]),
+        (
+            &policy.deny,
+            vec![Expr::Log(log_prefix.to_owned()), Expr::Drop],
+        ),
+    ];
+    for (rules, verdict) in verdicts {
+        for rule in rules {
+            for destination in destination_matches(rule) {
+                let exprs = [matches, &destination[..], &verdict[..]].concat();
+                batch.add_rule(table, chain, &exprs);
+            }
+        }
+    }
+}
+
+/// Enforces the egress policy of a container on the bridge, in a table of its own. Routed traffic
+/// is filtered after connection tracking, so that replies to connections into the container pass.
+/// Traffic to other containers is bridged without ever being routed, so the veth's ingress filters
+/// it without connection tracking, and also drops packets with spoofed source addresses that would
+/// slip past the other rules.
+pub fn apply_egress_policy(
+    id: &str,
+    bridge: &str,
+    interface: &str,
+    address: Ipv4Addr,
+    subnet: Ipv4Net,
+    gateway: Ipv4Addr,
+    policy: &EgressPolicy,
+) -> eyre::Result<()> {
+    let table = egress_table(id);
+    let mut log_prefix = format!("bento: container {id} denied egress: ");
+    log_prefix.truncate(NF_LOG_PREFIXLEN - 1);
+    let is_ipv4 = [Expr::Protocol, Expr::Eq(ETH_P_IP.to_be_bytes().to_vec())];
+
+    let ingress = Chain {
+        name: "ingress",
+        chain_type: "filter",
+        hook: NF_INET_INGRESS,
+        priority: NF_IP_PRI_FILTER,
+        device: Some(interface),
+    };
+    let prerouting = Chain {
+        name: "prerouting",
+        chain_type: "filter",
+        hook: NF_INET_PRE_ROUTING,
+        priority: NF_IP_PRI_MANGLE,
+        device: None,
+    };
+    let mut batch = Batch::new(NFPROTO_INET);
+    batch
+        .recreate_table(&table)
+        .add_chain(&table, &ingress)
+        .add_chain(&table, &prerouting);
+
+    // The bridge network is IPv4 only, but link-local IPv6 addresses would still reach the host
+    batch.add_rule(
+        &table,
+        ingress.name,
+        &[
+            Expr::Protocol,
+            Expr::Eq(ETH_P_IPV6.to_be_bytes().to_vec()),
+            Expr::Drop,
+        ],
+    );
+    let spoofed = [
+        Expr::Ipv4SrcAddr,
+        Expr::NotEq(address.octets().to_vec()),
+        Expr::Drop,
+    ];
+    batch.add_rule(&table, ingress.name, &[&is_ipv4[..], &spoofed].concat());
+    let bridged = [
+        Expr::Ipv4DstAddr,
+        Expr::And(subnet.netmask().octets().to_vec()),
+        Expr::Eq(subnet.network().octets().to_vec()),
+        Expr::Ipv4DstAddr,
+        Expr::NotEq(gateway.octets().to_vec()),
+    ];
+    add_policy_rules(
+        &mut batch,
+        &table,
+        ingress.name,
+        &[&is_ipv4[..], &bridged].concat(),
+        policy,
+        &log_prefix,
+    );
+
+    let from_container = [
+        Expr::IifName,
+        Expr::Eq(interface_name(bridge)),
+        Expr::Ipv4SrcAddr,
+        Expr::Eq(address.octets().to_vec()),
+    ];
+    let from_container = [&is_ipv4[..], &from_container].concat();
+    // Unlike packet fields, the state is loaded in host byte order
+    let established = [
+        Expr::CtState,
+        Expr::And(
+            (NF_CT_STATE_ESTABLISHED | NF_CT_STATE_RELATED)
+                .to_ne_bytes()
+                .to_vec(),
+        ),
+        Expr::NotEq(0u32.to_ne_bytes().to_vec()),
+        Expr::Accept,
+    ];
+    batch.add_rule(
+        &table,
+        prerouting.name,
+        &[&from_container[..], &established].concat(),
+    );
+    add_policy_rules(
+        &mut batch,
+        &table,
+        prerouting.name,
+        &from_container,
+        policy,
+        &log_prefix,
+    );
+
+    batch
+        .commit()
+        .wrap_err_with(|| format!("Failed to apply the egress policy of container {id}"))?;
+    debug!("Applied egress policy to {interface}");
+    Ok(())
+}
+
+/// Removes the egress policy of a container, if it has one.
+pub fn remove_egress_policy(id: &str) -> eyre::Result<()> {
+    match Batch::new(NFPROTO_INET)
+        .delete_table(&egress_table(id))
+        .commit()
+    {
+        Err(err) if errors::has_errno(&err, libc::ENOENT) => Ok(()),
+        result => {
+            result.wrap_err_with(|| format!("Failed to remove the egress policy of {id}"))?;
+            debug!("Removed egress policy of container {id}");
+            Ok(())
+        }
+    }
+}
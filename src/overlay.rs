@@ -0,0 +1,284 @@
+use crate::state::{self, ContainerState, Status};
+use eyre::{bail, WrapErr};
+use nix::{
+    errno::Errno,
+    libc,
+    mount::{mount, MsFlags},
+    sys::stat::{mknod, Mode, SFlag},
+    unistd::{chown, getuid, Gid, Uid},
+};
+use std::{
+    ffi::CString,
+    fs::{self, Permissions},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, symlink, FileTypeExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+const UPPER_DIR_NAME: &str = "upper";
+const WORK_DIR_NAME: &str = "work";
+/// Marks a directory of an upper layer as hiding the directory of the same path below it
+const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// A copy-on-write root made of read-only lower directories and a writable upper directory in
+/// the container's state directory.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    /// Bottom first, so that later layers are stacked on top of earlier ones
+    pub lower: Vec<PathBuf>,
+    pub upper: PathBuf,
+    pub work: PathBuf,
+}
+
+pub fn upper_dir(id: &str) -> eyre::Result<PathBuf> {
+    Ok(state::container_dir(id)?.join(UPPER_DIR_NAME))
+}
+
+impl Overlay {
+    /// Creates empty upper and work directories, replacing those from an earlier run of a
+    /// container with the same ID.
+    pub fn create(id: &str, lower: Vec<PathBuf>) -> eyre::Result<Self> {
+        let lower = lower
+            .iter()
+            .map(|dir| {
+                let dir = dir
+                    .canonicalize()
+                    .wrap_err_with(|| format!("Lower directory {} not found", dir.display()))?;
+                // Separators of the overlay mount options
+                if dir.as_os_str().as_bytes().contains(&b':')
+                    || dir.as_os_str().as_bytes().contains(&b',')
+                {
+                    bail!(
+                        "Lower directory {} should not contain ':' or ','",
+                        dir.display()
+                    );
+                }
+                Ok(dir)
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        let container_dir = state::container_dir(id)?;
+        let overlay = Self {
+            lower,
+            upper: container_dir.join(UPPER_DIR_NAME),
+            work: container_dir.join(WORK_DIR_NAME),
+        };
+        if overlay.upper.exists() {
+            warn!("Discarding the upper layer of an earlier run of container {id}");
+        }
+        for dir in [&overlay.upper, &overlay.work] {
+            remove_layer_dir(dir)?;
+            fs::create_dir_all(dir)?;
+        }
+        debug!("Created upper layer {}", overlay.upper.display());
+        Ok(overlay)
+    }
+
+    /// The child sets up the root filesystem as the container's root user, who has to own the
+    /// upper and work directories to write to them.
+    pub fn chown(&self, uid: Uid, gid: Gid) -> eyre::Result<()> {
+        for dir in [&self.upper, &self.work] {
+            chown(dir, Some(uid), Some(gid))
+                .wrap_err_with(|| format!("Failed to chown {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Mounts the overlay at `new_root`. Inside a user namespace, overlayfs may not use trusted
+    /// xattrs, so it keeps its metadata in user xattrs instead.
+    pub fn mount(&self, new_root: &Path, in_user_namespace: bool) -> eyre::Result<()> {
+        let lower = self
+            .lower
+            .iter()
+            .rev()
+            .map(|dir| dir.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(":");
+        let mut options = format!(
+            "lowerdir={lower},upperdir={upper},workdir={work}",
+            upper = self.upper.display(),
+            work = self.work.display()
+        );
+        if in_user_namespace {
+            options.push_str(",userxattr");
+        }
+        debug!("Mounting overlay at {} with {options}", new_root.display());
+        mount(
+            Some("overlay"),
+            new_root,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        )
+        .map_err(io::Error::from)
+        .wrap_err_with(|| format!("Failed to mount overlay at {}", new_root.display()))
+    }
+
+    /// Removes the work directory, and the upper directory as well if `discard` is set.
+    pub fn clean_up(&self, discard: bool) -> eyre::Result<()> {
+        remove_layer_dir(&self.work)?;
+        if discard {
+            remove_layer_dir(&self.upper)?;
+            debug!("Discarded upper layer {}", self.upper.display());
+        }
+        Ok(())
+    }
+}
+
+/// Overlayfs leaves directories without any permissions in the work directory, which even their
+/// owner has to make accessible before removing them.
+fn remove_layer_dir(dir: &Path) -> eyre::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && entry.metadata()?.mode() & 0o700 != 0o700 {
+            fs::set_permissions(entry.path(), Permissions::from_mode(0o700))?;
+        }
+    }
+    fs::remove_dir_all(dir).wrap_err_with(|| format!("Failed to remove {}", dir.display()))
+}
+
+/// Copies the upper layer of a stopped container to `destination`, which can then be passed to
+/// `--rootfs-lower` on top of the container's own lower directories.
+pub fn commit(id: &str, destination: &Path) -> eyre::Result<()> {
+    let state = ContainerState::load(id)?;
+    // A paused container may be resumed and write to its upper layer while it is copied
+    if state.status != Status::Stopped {
+        bail!(
+            "Container {id} is {:?}, stop it before committing",
+            state.status
+        );
+    }
+    let upper = upper_dir(id)?;
+    if !upper.exists() {
+        bail!(
+            "Container {id} has no upper layer, since it ran without --rootfs-lower or with --rm"
+        );
+    }
+    if destination.exists() {
+        bail!("{} already exists", destination.display());
+    }
+
+    copy_layer(&upper, destination)
+        .wrap_err_with(|| format!("Failed to copy upper layer to {}", destination.display()))?;
+    println!("{}", destination.display());
+    debug!(
+        "Committed container {id} on top of {:?} to {}",
+        state.rootfs_lower,
+        destination.display()
+    );
+    Ok(())
+}
+
+/// Copies a layer with its whiteouts and opaque directories, keeping modes and, where allowed,
+/// ownership.
+pub fn copy_layer(source: &Path, destination: &Path) -> eyre::Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(destination)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_layer(&entry.path(), &destination.join(entry.file_name()))?;
+        }
+        copy_opaque_xattrs(source, destination)?;
+    } else if file_type.is_symlink() {
+        symlink(fs::read_link(source)?, destination)?;
+    } else if file_type.is_char_device() && metadata.rdev() == 0 {
+        // A whiteout, which unprivileged users may create as well
+        mknod(destination, SFlag::S_IFCHR, Mode::empty(), 0)?;
+    } else if file_type.is_file() {
+        fs::copy(source, destination)?;
+    } else {
+        warn!("Skipping special file {}", source.display());
+        return Ok(());
+    }
+
+    match lchown(destination, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => debug!(
+            "Not allowed to keep the owner of {}, leaving it to the current user",
+            destination.display()
+        ),
+        result => result?,
+    }
+    if !file_type.is_symlink() {
+        fs::set_permissions(destination, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+fn copy_opaque_xattrs(source: &Path, destination: &Path) -> eyre::Result<()> {
+    let source = CString::new(source.as_os_str().as_bytes())?;
+    let destination = CString::new(destination.as_os_str().as_bytes())?;
+    for name in OPAQUE_XATTRS {
+        let name = CString::new(name)?;
+        if let Some(value) = get_xattr(&source, &name)? {
+            set_xattr(&destination, &name, &[value])?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a one-byte xattr, or `None` if it is missing or may not be read.
+fn get_xattr(path: &CString, name: &CString) -> eyre::Result<Option<u8>> {
+    let mut value = [0u8; 1];
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    if len < 0 {
+        return match Errno::last() {
+            Errno::ENODATA | Errno::ENOTSUP | Errno::EPERM => Ok(None),
+            errno => Err(errno).wrap_err_with(|| format!("Failed to read xattrs of {path:?}")),
+        };
+    }
+    Ok((len == 1).then_some(value[0]))
+}
+
+fn set_xattr(path: &CString, name: &CString, value: &[u8]) -> eyre::Result<()> {
+    let result = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    Errno::result(result).wrap_err_with(|| format!("Failed to mark {path:?} as opaque"))?;
+    Ok(())
+}
+
+/// Whether a directory of a layer hides the directory of the same path below it.
+pub fn is_opaque(dir: &Path) -> eyre::Result<bool> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    for name in OPAQUE_XATTRS {
+        if get_xattr(&path, &CString::new(name)?)? == Some(b'y') {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Marks a directory of a layer as opaque, for overlays mounted with and without user xattrs.
+/// Only root may set trusted xattrs, and only root mounts overlays without a user namespace.
+pub fn mark_opaque(dir: &Path) -> eyre::Result<()> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    for name in OPAQUE_XATTRS {
+        if name.starts_with("trusted.") && !getuid().is_root() {
+            continue;
+        }
+        set_xattr(&path, &CString::new(name)?, b"y")?;
+    }
+    Ok(())
+}
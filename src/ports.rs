@@ -0,0 +1,486 @@
+use eyre::{bail, eyre, WrapErr};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::UnixDatagram,
+    },
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, warn};
+
+/// UDP has no connections, so a flow's socket is closed once it has been idle for this long
+pub const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+/// Connections to published ports forwarded at once, each taking two threads or the buffers of
+/// a userspace socket, beyond which new ones are closed
+pub const MAX_TCP_CONNECTIONS: usize = 256;
+/// Peers whose datagrams to published ports are forwarded at once, each taking a socket
+pub const MAX_UDP_PEERS: usize = 1024;
+/// How long a listener is left out of polling after failing to accept a connection, e.g. as bento
+/// ran out of file descriptors. The connection stays queued, so the listener would be ready again
+/// at once and fail the same way.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A container port published on the host, written as `[HOSTIP:]HOSTPORT:CONTAINERPORT[/tcp|udp]`,
+/// e.g. `8080:80` or `127.0.0.1:5353:53/udp`. Published on all host addresses over TCP by
+/// default.
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub host_ip: IpAddr,
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl PortMapping {
+    fn host_address(&self) -> SocketAddr {
+        SocketAddr::new(self.host_ip, self.host_port)
+    }
+}
+
+fn parse_port(s: &str) -> eyre::Result<u16> {
+    match s.parse() {
+        Ok(0) | Err(_) => bail!("Port {s} should be between 1 and 65535"),
+        Ok(port) => Ok(port),
+    }
+}
+
+impl FromStr for PortMapping {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, protocol)) => bail!("Protocol {protocol} should be tcp or udp"),
+            None => (s, Protocol::Tcp),
+        };
+
+        // Split from the right, since IPv6 host addresses contain colons themselves
+        let mut parts = ports.rsplitn(3, ':');
+        let container_port_str = parts.next().unwrap_or_default();
+        let host_port_str = parts
+            .next()
+            .ok_or_else(|| eyre!("Published port {s} should be [HOSTIP:]HOSTPORT:CONTAINERPORT"))?;
+        let host_ip = match parts.next() {
+            Some(ip_str) => {
+                let ip_str = ip_str.trim_start_matches('[').trim_end_matches(']');
+                ip_str
+                    .parse()
+                    .wrap_err_with(|| format!("Invalid IP address {ip_str}"))?
+            }
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+
+        Ok(Self {
+            host_ip,
+            host_port: parse_port(host_port_str)?,
+            container_port: parse_port(container_port_str)?,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{host}->{container_port}/{protocol}",
+            host = self.host_address(),
+            container_port = self.container_port,
+            protocol = self.protocol
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum HostSocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl HostSocket {
+    pub fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            HostSocket::Tcp(listener) => listener.as_fd(),
+            HostSocket::Udp(socket) => socket.as_fd(),
+        }
+    }
+}
+
+/// A published port whose host socket is bound.
+#[derive(Debug)]
+pub struct PublishedPort {
+    pub mapping: PortMapping,
+    pub socket: HostSocket,
+    /// Set when the listener failed to accept a connection
+    accept_paused_until: Option<Instant>,
+}
+
+impl PublishedPort {
+    fn is_paused(&self) -> bool {
+        self.accept_paused_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Nothing is polled for while the listener is paused.
+    pub fn poll_fd(&self) -> PollFd<'_> {
+        let flags = match self.is_paused() {
+            true => PollFlags::empty(),
+            false => PollFlags::POLLIN,
+        };
+        PollFd::new(self.socket.as_fd(), flags)
+    }
+
+    /// Accepts a connection on a TCP port. Returns `None` once none are queued, or while the
+    /// listener is paused after failing to accept one.
+    pub fn accept(&mut self, id: &str) -> Option<(TcpStream, SocketAddr)> {
+        let HostSocket::Tcp(listener) = &self.socket else {
+            return None;
+        };
+        if self.is_paused() {
+            return None;
+        }
+        loop {
+            match listener.accept() {
+                Ok(accepted) => return Some(accepted),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return None,
+                // The peer gave up before being accepted, which says nothing about the others
+                // waiting
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+                    ) => {}
+                Err(err) => {
+                    warn!(
+                        "Failed to accept a connection to container {id}, retrying in {}ms: {err}",
+                        ACCEPT_BACKOFF.as_millis()
+                    );
+                    self.accept_paused_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Shortens `timeout` so that polling wakes up when the first paused listener resumes.
+pub fn poll_timeout(published_ports: &[PublishedPort], timeout: Duration) -> Duration {
+    published_ports
+        .iter()
+        .filter(|port| port.is_paused())
+        .filter_map(|port| port.accept_paused_until)
+        .map(|until| until.saturating_duration_since(Instant::now()))
+        .fold(timeout, Duration::min)
+}
+
+/// Binds the host sockets of all published ports up front, so that a port taken by another
+/// container or host service fails the start instead of a later connection.
+pub fn bind(mappings: &[PortMapping]) -> eyre::Result<Vec<PublishedPort>> {
+    mappings
+        .iter()
+        .map(|mapping| {
+            let address = mapping.host_address();
+            let result = match mapping.protocol {
+                Protocol::Tcp => TcpListener::bind(address).map(HostSocket::Tcp),
+                Protocol::Udp => UdpSocket::bind(address).map(HostSocket::Udp),
+            };
+            let socket = match result {
+                Err(err) if err.kind() == ErrorKind::AddrInUse => {
+                    bail!(
+                        "Host port {address}/{protocol} is already in use",
+                        protocol = mapping.protocol
+                    )
+                }
+                result => result
+                    .wrap_err_with(|| format!("Failed to bind {address}/{}", mapping.protocol))?,
+            };
+            match &socket {
+                HostSocket::Tcp(listener) => listener.set_nonblocking(true)?,
+                HostSocket::Udp(socket) => socket.set_nonblocking(true)?,
+            }
+            debug!("Publishing {mapping}");
+            Ok(PublishedPort {
+                mapping: mapping.clone(),
+                socket,
+                accept_paused_until: None,
+            })
+        })
+        .collect()
+}
+
+/// A non-blocking UDP socket that only exchanges datagrams with `destination`.
+pub fn connect_udp(destination: SocketAddr) -> io::Result<UdpSocket> {
+    let unspecified = match destination {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect(destination)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Copies a host connection to the container and back until both sides are done.
+fn splice(host: TcpStream, container_address: SocketAddr) -> io::Result<()> {
+    let container = TcpStream::connect(container_address)?;
+    let (mut host_reader, mut container_writer) = (host.try_clone()?, container.try_clone()?);
+    let upload = thread::spawn(move || {
+        io::copy(&mut host_reader, &mut container_writer)?;
+        container_writer.shutdown(Shutdown::Write)
+    });
+    io::copy(&mut &container, &mut &host)?;
+    host.shutdown(Shutdown::Write)?;
+    upload
+        .join()
+        .map_err(|_| io::Error::other("Upload thread panicked"))?
+}
+
+struct UdpPeer {
+    /// Connected to the container, so that replies can be told apart by peer
+    socket: UdpSocket,
+    last_active: Instant,
+}
+
+/// Forwards published ports to the container's address on the bridge, from a thread in bento.
+#[derive(Debug)]
+pub struct PortProxy {
+    stop_socket: UnixDatagram,
+    handle: JoinHandle<()>,
+}
+
+impl PortProxy {
+    /// Returns `None` if no ports are published.
+    pub fn start(
+        id: String,
+        container_ip: Ipv4Addr,
+        mut published_ports: Vec<PublishedPort>,
+    ) -> eyre::Result<Option<Self>> {
+        if published_ports.is_empty() {
+            return Ok(None);
+        }
+
+        let (stop_socket, proxy_socket) = UnixDatagram::pair()?;
+        let handle = thread::spawn(move || {
+            if let Err(err) = proxy(&id, container_ip, &mut published_ports, &proxy_socket) {
+                error!("Port proxy of container {id} failed: {err}");
+            }
+        });
+        debug!("Started port proxy");
+
+        Ok(Some(Self {
+            stop_socket,
+            handle,
+        }))
+    }
+
+    pub fn stop(self) -> eyre::Result<()> {
+        self.stop_socket.send(&[1])?;
+        self.handle
+            .join()
+            .map_err(|_| eyre!("Port proxy thread panicked"))?;
+        debug!("Stopped port proxy");
+        Ok(())
+    }
+}
+
+fn proxy(
+    id: &str,
+    container_ip: Ipv4Addr,
+    published_ports: &mut [PublishedPort],
+    stop_socket: &UnixDatagram,
+) -> eyre::Result<()> {
+    // By index of the published port and address of the peer on the host
+    let mut udp_peers = HashMap::<(usize, SocketAddr), UdpPeer>::new();
+    let mut buf = [0; UDP_BUFFER_SIZE];
+    let tcp_connections = Arc::new(AtomicUsize::new(0));
+    loop {
+        let mut poll_fds = vec![PollFd::new(stop_socket.as_fd(), PollFlags::POLLIN)];
+        poll_fds.extend(published_ports.iter().map(PublishedPort::poll_fd));
+        poll_fds.extend(
+            udp_peers
+                .values()
+                .map(|peer| PollFd::new(peer.socket.as_fd(), PollFlags::POLLIN)),
+        );
+        let timeout = poll_timeout(published_ports, MAX_POLL_INTERVAL);
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        match poll(&mut poll_fds, timeout) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+        if poll_fds[0].any().unwrap_or(true) {
+            return Ok(());
+        }
+
+        for (index, port) in published_ports.iter_mut().enumerate() {
+            let container_address =
+                SocketAddr::new(IpAddr::V4(container_ip), port.mapping.container_port);
+            match &port.socket {
+                HostSocket::Tcp(_) => {
+                    while let Some((stream, peer)) = port.accept(id) {
+                        if tcp_connections.load(Ordering::Relaxed) >= MAX_TCP_CONNECTIONS {
+                            warn!(
+                                "Closed connection from {peer} to container {id}, which has \
+                                 {MAX_TCP_CONNECTIONS} connections already"
+                            );
+                            continue;
+                        }
+                        debug!("Forwarding connection from {peer} to {container_address}");
+                        let id = id.to_owned();
+                        let tcp_connections = Arc::clone(&tcp_connections);
+                        tcp_connections.fetch_add(1, Ordering::Relaxed);
+                        thread::spawn(move || {
+                            if let Err(err) = splice(stream, container_address) {
+                                debug!("Connection from {peer} to container {id} failed: {err}");
+                            }
+                            tcp_connections.fetch_sub(1, Ordering::Relaxed);
+                        });
+                    }
+                }
+                HostSocket::Udp(socket) => {
+                    while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                        let is_full = udp_peers.len() >= MAX_UDP_PEERS;
+                        let udp_peer = match udp_peers.entry((index, peer)) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(_) if is_full => {
+                                debug!("Dropped datagram from {peer}, as there are too many peers");
+                                continue;
+                            }
+                            Entry::Vacant(entry) => match connect_udp(container_address) {
+                                Ok(socket) => entry.insert(UdpPeer {
+                                    socket,
+                                    last_active: Instant::now(),
+                                }),
+                                Err(err) => {
+                                    debug!("Failed to forward datagram from {peer}: {err}");
+                                    continue;
+                                }
+                            },
+                        };
+                        if let Err(err) = udp_peer.socket.send(&buf[..len]) {
+                            debug!("Dropped datagram from {peer}: {err}");
+                        }
+                        udp_peer.last_active = Instant::now();
+                    }
+                }
+            }
+        }
+
+        for ((index, peer), udp_peer) in &mut udp_peers {
+            let HostSocket::Udp(host_socket) = &published_ports[*index].socket else {
+                continue;
+            };
+            while let Ok(len) = udp_peer.socket.recv(&mut buf) {
+                if let Err(err) = host_socket.send_to(&buf[..len], peer) {
+                    debug!("Dropped datagram to {peer}: {err}");
+                }
+                udp_peer.last_active = Instant::now();
+            }
+        }
+        udp_peers.retain(|_, peer| peer.last_active.elapsed() < UDP_FLOW_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    #[test]
+    fn parses_mapping_on_all_addresses_over_tcp_by_default() {
+        let mapping = "8080:80".parse::<PortMapping>().unwrap();
+        assert_eq!(mapping.host_ip, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(mapping.host_port, 8080);
+        assert_eq!(mapping.container_port, 80);
+        assert_eq!(mapping.protocol, Protocol::Tcp);
+    }
+
+    #[test]
+    fn parses_mapping_with_host_ip_and_protocol() {
+        let mapping = "127.0.0.1:5353:53/udp".parse::<PortMapping>().unwrap();
+        assert_eq!(mapping.host_ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(mapping.host_port, 5353);
+        assert_eq!(mapping.container_port, 53);
+        assert_eq!(mapping.protocol, Protocol::Udp);
+        assert_eq!(mapping.to_string(), "127.0.0.1:5353->53/udp");
+    }
+
+    #[test]
+    fn parses_mapping_with_ipv6_host_ip() {
+        for mapping in ["[::1]:8443:443/tcp", "::1:8443:443/tcp"] {
+            let mapping = mapping.parse::<PortMapping>().unwrap();
+            assert_eq!(mapping.host_ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+            assert_eq!(mapping.host_port, 8443);
+            assert_eq!(mapping.container_port, 443);
+            assert_eq!(mapping.to_string(), "[::1]:8443->443/tcp");
+        }
+    }
+
+    #[test]
+    fn leaves_paused_listener_alone_until_backoff_ends() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut port = PublishedPort {
+            mapping: format!("{}:80", address.port()).parse().unwrap(),
+            socket: HostSocket::Tcp(listener),
+            accept_paused_until: Some(Instant::now() + ACCEPT_BACKOFF),
+        };
+        let _stream = TcpStream::connect(address).unwrap();
+
+        assert!(port.accept("test").is_none());
+        assert_eq!(port.poll_fd().events(), PollFlags::empty());
+        let timeout = poll_timeout(slice::from_ref(&port), Duration::from_secs(1));
+        assert!(timeout <= ACCEPT_BACKOFF);
+
+        port.accept_paused_until = Some(Instant::now());
+        assert_eq!(port.poll_fd().events(), PollFlags::POLLIN);
+        let timeout = poll_timeout(slice::from_ref(&port), Duration::from_secs(1));
+        assert_eq!(timeout, Duration::from_secs(1));
+        assert!(port.accept("test").is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_mappings() {
+        for mapping in [
+            "80",
+            "8080:80/sctp",
+            "0:80",
+            "8080:0",
+            "8080:65536",
+            "http:80",
+            "localhost:8080:80",
+            "8080:80/",
+        ] {
+            assert!(mapping.parse::<PortMapping>().is_err(), "{mapping}");
+        }
+    }
+}
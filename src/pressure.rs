@@ -0,0 +1,391 @@
+use crate::{
+    cgroup,
+    state::{self, ContainerState, Status},
+};
+use eyre::{bail, eyre, WrapErr};
+use nix::{
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    unistd::getuid,
+};
+use serde::Serialize;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::{
+        fd::AsFd,
+        unix::{fs::OpenOptionsExt, net::UnixDatagram},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+    Memory,
+    Cpu,
+    Io,
+}
+
+impl Resource {
+    fn file_name(self) -> &'static str {
+        match self {
+            Resource::Memory => "memory.pressure",
+            Resource::Cpu => "cpu.pressure",
+            Resource::Io => "io.pressure",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StallKind {
+    /// At least one task is stalled
+    Some,
+    /// All non-idle tasks are stalled
+    Full,
+}
+
+impl fmt::Display for StallKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StallKind::Some => write!(f, "some"),
+            StallKind::Full => write!(f, "full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Log,
+    Freeze,
+    Kill,
+}
+
+/// A PSI trigger, written as `RESOURCE:some|full:STALL/WINDOW[:ACTION]`, e.g.
+/// `memory:some:150ms/1s:freeze`.
+#[derive(Debug, Clone)]
+pub struct PressureTrigger {
+    resource: Resource,
+    kind: StallKind,
+    stall: Duration,
+    window: Duration,
+    action: Action,
+}
+
+// Bounds enforced by the kernel on trigger windows
+const MIN_WINDOW: Duration = Duration::from_millis(500);
+const MAX_WINDOW: Duration = Duration::from_secs(10);
+/// Without CAP_SYS_RESOURCE, the kernel only accepts windows that are a multiple of this
+const UNPRIVILEGED_WINDOW_STEP: Duration = Duration::from_secs(2);
+
+fn parse_duration(s: &str) -> eyre::Result<Duration> {
+    let (digits, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((s, ""), |i| s.split_at(i));
+    let value = digits
+        .parse()
+        .wrap_err_with(|| format!("Invalid duration {s}"))?;
+    match unit {
+        "us" => Ok(Duration::from_micros(value)),
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        _ => bail!("Duration {s} should end with us, ms, or s"),
+    }
+}
+
+impl FromStr for PressureTrigger {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        let (resource_str, kind_str, threshold_str, action_str) = match parts[..] {
+            [resource, kind, threshold] => (resource, kind, threshold, "log"),
+            [resource, kind, threshold, action] => (resource, kind, threshold, action),
+            _ => bail!(
+                "Pressure trigger {s} should have 3 or 4 parts, but has {len}",
+                len = parts.len()
+            ),
+        };
+
+        let resource = match resource_str {
+            "memory" => Resource::Memory,
+            "cpu" => Resource::Cpu,
+            "io" => Resource::Io,
+            _ => bail!("Unknown pressure resource {resource_str}"),
+        };
+        let kind = match kind_str {
+            "some" => StallKind::Some,
+            "full" => StallKind::Full,
+            _ => bail!("Pressure kind {kind_str} should be some or full"),
+        };
+        let (stall_str, window_str) = threshold_str
+            .split_once('/')
+            .ok_or_else(|| eyre!("Pressure threshold {threshold_str} should be STALL/WINDOW"))?;
+        let stall = parse_duration(stall_str)?;
+        let window = parse_duration(window_str)?;
+        if !(MIN_WINDOW..=MAX_WINDOW).contains(&window) {
+            bail!("Pressure window {window_str} should be between 500ms and 10s");
+        }
+        if !getuid().is_root() && window.as_micros() % UNPRIVILEGED_WINDOW_STEP.as_micros() != 0 {
+            bail!(
+                "Pressure window {window_str} should be a multiple of 2s when not running as root"
+            );
+        }
+        if stall.is_zero() || stall > window {
+            bail!("Pressure stall {stall_str} should be positive and at most {window_str}");
+        }
+        let action = match action_str {
+            "log" => Action::Log,
+            "freeze" => Action::Freeze,
+            "kill" => Action::Kill,
+            _ => bail!("Pressure action {action_str} should be log, freeze, or kill"),
+        };
+
+        Ok(Self {
+            resource,
+            kind,
+            stall,
+            window,
+            action,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PressureEvent<'a> {
+    time: u64,
+    id: &'a str,
+    resource: Resource,
+    kind: StallKind,
+    stall_us: u128,
+    window_us: u128,
+    action: Action,
+}
+
+const EVENTS_FILE_NAME: &str = "events.jsonl";
+
+fn events_path(id: &str) -> eyre::Result<PathBuf> {
+    Ok(state::container_dir(id)?.join(EVENTS_FILE_NAME))
+}
+
+fn record_event(id: &str, trigger: &PressureTrigger) -> eyre::Result<()> {
+    let event = PressureEvent {
+        time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        id,
+        resource: trigger.resource,
+        kind: trigger.kind,
+        stall_us: trigger.stall.as_micros(),
+        window_us: trigger.window.as_micros(),
+        action: trigger.action,
+    };
+    warn!("Pressure stall in container {id}: {event:?}");
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(events_path(id)?)?;
+    writeln!(file, "{}", serde_json::to_string(&event)?)?;
+    Ok(())
+}
+
+fn take_action(id: &str, cgroup_path: &str, action: Action) -> eyre::Result<()> {
+    match action {
+        Action::Log => {}
+        Action::Freeze => {
+            ContainerState::modify(id, |state| {
+                if state.status == Status::Running {
+                    cgroup::freeze(&state.cgroup()?)?;
+                    state.status = Status::Paused;
+                    info!("Froze container {id} due to pressure stall");
+                }
+                Ok(())
+            })?;
+        }
+        Action::Kill => {
+            cgroup::load_cgroup(cgroup_path)?.kill()?;
+            info!("Killed container {id} due to pressure stall");
+        }
+    }
+    Ok(())
+}
+
+/// Opens a PSI file and registers the trigger. The kernel keeps the trigger alive for as long as
+/// the file stays open, and signals it with `POLLPRI`.
+fn register_trigger(cgroup_dir: &Path, trigger: &PressureTrigger) -> eyre::Result<File> {
+    let path = cgroup_dir.join(trigger.resource.file_name());
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(nix::libc::O_NONBLOCK)
+        .open(&path)
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let trigger_str = format!(
+        "{kind} {stall} {window}",
+        kind = trigger.kind,
+        stall = trigger.stall.as_micros(),
+        window = trigger.window.as_micros()
+    );
+    debug!("Registering trigger {trigger_str:?} on {}", path.display());
+    file.write_all(trigger_str.as_bytes())?;
+    Ok(file)
+}
+
+#[derive(Debug)]
+pub struct PressureMonitor {
+    stop_socket: UnixDatagram,
+    handle: JoinHandle<eyre::Result<()>>,
+}
+
+impl PressureMonitor {
+    /// Returns `None` if there are no triggers to monitor.
+    pub fn start(
+        id: String,
+        cgroup_path: String,
+        triggers: Vec<PressureTrigger>,
+    ) -> eyre::Result<Option<Self>> {
+        if triggers.is_empty() {
+            return Ok(None);
+        }
+
+        let cgroup_dir = cgroup::cgroup_dir(&cgroup::load_cgroup(&cgroup_path)?);
+        let files = triggers
+            .iter()
+            .map(|trigger| register_trigger(&cgroup_dir, trigger))
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        let (stop_socket, monitor_socket) = UnixDatagram::pair()?;
+        let handle = thread::spawn(move || {
+            monitor(&id, &cgroup_path, &triggers, &files, &monitor_socket)
+                .wrap_err_with(|| format!("Pressure monitor of container {id} failed"))
+        });
+        debug!("Started pressure monitor");
+
+        Ok(Some(Self {
+            stop_socket,
+            handle,
+        }))
+    }
+
+    /// Returns the error that the monitor stopped on by itself, if any.
+    pub fn stop(self) -> eyre::Result<()> {
+        // Fails if the monitor already stopped on an error, which joining it returns instead
+        if let Err(err) = self.stop_socket.send(&[1]) {
+            debug!("Pressure monitor is no longer listening: {err}");
+        }
+        self.handle
+            .join()
+            .map_err(|_| eyre!("Pressure monitor thread panicked"))??;
+        debug!("Stopped pressure monitor");
+        Ok(())
+    }
+}
+
+fn monitor(
+    id: &str,
+    cgroup_path: &str,
+    triggers: &[PressureTrigger],
+    files: &[File],
+    stop_socket: &UnixDatagram,
+) -> eyre::Result<()> {
+    loop {
+        let mut poll_fds = vec![PollFd::new(stop_socket.as_fd(), PollFlags::POLLIN)];
+        poll_fds.extend(
+            files
+                .iter()
+                .map(|file| PollFd::new(file.as_fd(), PollFlags::POLLPRI)),
+        );
+        match poll(&mut poll_fds, PollTimeout::NONE) {
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+
+        if poll_fds[0].any().unwrap_or(true) {
+            return Ok(());
+        }
+
+        for (poll_fd, trigger) in poll_fds[1..].iter().zip(triggers) {
+            let revents = poll_fd.revents().unwrap_or(PollFlags::POLLERR);
+            if revents.contains(PollFlags::POLLERR) {
+                bail!("Pressure file {} is gone", trigger.resource.file_name());
+            }
+            if revents.contains(PollFlags::POLLPRI) {
+                if let Err(err) = record_event(id, trigger)
+                    .and_then(|()| take_action(id, cgroup_path, trigger.action))
+                {
+                    error!("Failed to handle pressure stall in container {id}: {err}");
+                }
+            }
+        }
+    }
+}
+
+pub fn print_events(id: &str) -> eyre::Result<()> {
+    ContainerState::load(id)?;
+    let path = events_path(id)?;
+    if path.exists() {
+        print!("{}", fs::read_to_string(path)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trigger_with_default_action() {
+        let trigger = "io:full:100ms/2s".parse::<PressureTrigger>().unwrap();
+        assert!(matches!(trigger.resource, Resource::Io));
+        assert!(matches!(trigger.kind, StallKind::Full));
+        assert_eq!(trigger.stall, Duration::from_millis(100));
+        assert_eq!(trigger.window, Duration::from_secs(2));
+        assert!(matches!(trigger.action, Action::Log));
+    }
+
+    #[test]
+    fn parses_trigger_with_action() {
+        let trigger = "memory:some:1500000us/4s:freeze"
+            .parse::<PressureTrigger>()
+            .unwrap();
+        assert!(matches!(trigger.resource, Resource::Memory));
+        assert_eq!(trigger.stall, Duration::from_millis(1500));
+        assert!(matches!(trigger.action, Action::Freeze));
+    }
+
+    #[test]
+    fn rejects_malformed_triggers() {
+        for trigger in [
+            "memory:some",
+            "memory:some:150ms/2s:kill:now",
+            "disk:some:150ms/2s",
+            "memory:most:150ms/2s",
+            "memory:some:150ms",
+            "memory:some:150/2s",
+            "memory:some:150ms/2s:restart",
+            "memory:some:0ms/2s",
+            "memory:some:3s/2s",
+        ] {
+            assert!(trigger.parse::<PressureTrigger>().is_err(), "{trigger}");
+        }
+    }
+
+    #[test]
+    fn rejects_windows_out_of_kernel_bounds() {
+        for trigger in ["cpu:some:100ms/400ms", "cpu:some:100ms/12s"] {
+            assert!(trigger.parse::<PressureTrigger>().is_err(), "{trigger}");
+        }
+    }
+
+    #[test]
+    fn accepts_windows_below_2s_steps_only_as_root() {
+        let result = "cpu:some:100ms/1s".parse::<PressureTrigger>();
+        assert_eq!(result.is_ok(), getuid().is_root());
+    }
+}
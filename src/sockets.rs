@@ -1,5 +1,5 @@
 use eyre::WrapErr;
-use std::os::unix::net::UnixDatagram;
+use std::os::{fd::RawFd, unix::net::UnixDatagram};
 
 pub fn send_bool(socket: &UnixDatagram, value: bool) -> eyre::Result<()> {
     socket
@@ -15,3 +15,21 @@ pub fn recv_bool(socket: &UnixDatagram) -> eyre::Result<bool> {
         .wrap_err("Failed to recv bool through socket")?;
     Ok(buf[0] != 0)
 }
+
+/// Sends the number of a file descriptor, or `None` if the sender failed to open it. The number
+/// only refers to the same file because bento and the child share their fd table.
+pub fn send_fd(socket: &UnixDatagram, fd: Option<RawFd>) -> eyre::Result<()> {
+    socket
+        .send(&fd.unwrap_or(-1).to_ne_bytes())
+        .wrap_err_with(|| format!("Failed to send fd {fd:?} through socket"))?;
+    Ok(())
+}
+
+pub fn recv_fd(socket: &UnixDatagram) -> eyre::Result<Option<RawFd>> {
+    let mut buf = [0; 4];
+    socket
+        .recv(&mut buf)
+        .wrap_err("Failed to recv fd through socket")?;
+    let fd = RawFd::from_ne_bytes(buf);
+    Ok((fd >= 0).then_some(fd))
+}
@@ -0,0 +1,227 @@
+use crate::{
+    cgroup::{self, Limits},
+    image::ImageConfig,
+};
+use cgroups_rs::cgroup::Cgroup;
+use eyre::{bail, eyre, WrapErr};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    sys::signal::kill,
+    unistd::{gettid, getuid, Pid},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{self, File},
+    net::Ipv4Addr,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Running,
+    Paused,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub pid: i32,
+    pub status: Status,
+    /// `None` if the container runs without a cgroup
+    pub cgroup_path: Option<String>,
+    pub mount_dir: PathBuf,
+    pub limits: Limits,
+    /// Address on the bento0 bridge, if connected to it
+    #[serde(default)]
+    pub ip_address: Option<Ipv4Addr>,
+    /// Lower directories of the overlay root, bottom first, if it has one
+    #[serde(default)]
+    pub rootfs_lower: Vec<PathBuf>,
+    /// Config of the image the container runs, which committing it to an image carries over
+    #[serde(default)]
+    pub image_config: Option<ImageConfig>,
+    /// Whether the state said the container was running or paused, but its process was gone, as
+    /// the bento running it was killed before it could stop the container
+    #[serde(skip)]
+    pub stale: bool,
+}
+
+const STATE_FILE_NAME: &str = "state.json";
+
+impl ContainerState {
+    pub fn new(
+        id: String,
+        pid: Pid,
+        cgroup_path: Option<String>,
+        mount_dir: PathBuf,
+        limits: Limits,
+    ) -> Self {
+        Self {
+            id,
+            pid: pid.as_raw(),
+            status: Status::Running,
+            cgroup_path,
+            mount_dir,
+            limits,
+            ip_address: None,
+            rootfs_lower: vec![],
+            image_config: None,
+            stale: false,
+        }
+    }
+
+    /// Parses a saved state, which is stopped if its process is gone.
+    fn parse(contents: &str) -> eyre::Result<Self> {
+        let mut state: Self = serde_json::from_str(contents)?;
+        if state.status != Status::Stopped
+            && kill(Pid::from_raw(state.pid), None) == Err(Errno::ESRCH)
+        {
+            debug!(
+                "Container {id} is {:?}, but its PID {pid} is gone",
+                state.status,
+                id = state.id,
+                pid = state.pid
+            );
+            state.status = Status::Stopped;
+            state.stale = true;
+        }
+        Ok(state)
+    }
+
+    pub fn load(id: &str) -> eyre::Result<Self> {
+        let path = container_dir(id)?.join(STATE_FILE_NAME);
+        let contents = fs::read_to_string(&path)
+            .wrap_err_with(|| format!("No state found for container {id}"))?;
+        Self::parse(&contents)
+    }
+
+    /// States of every container, skipping directories that hold no state, like those of
+    /// containers that failed to start.
+    pub fn load_all() -> eyre::Result<Vec<Self>> {
+        let dir = state_dir();
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut states = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path().join(STATE_FILE_NAME);
+            if path.exists() {
+                states.push(Self::parse(&fs::read_to_string(&path)?)?);
+            }
+        }
+        Ok(states)
+    }
+
+    pub fn cgroup(&self) -> eyre::Result<Cgroup> {
+        match &self.cgroup_path {
+            Some(cgroup_path) => cgroup::load_cgroup(cgroup_path),
+            None => bail!("Container {id} runs without a cgroup", id = self.id),
+        }
+    }
+
+    /// Loads the state of a container, lets `f` change it, and saves it, all while holding an
+    /// exclusive lock on the container's directory. Otherwise bento processes and the pressure
+    /// monitor changing the same state at once could each lose the other's changes.
+    pub fn modify<T>(id: &str, f: impl FnOnce(&mut Self) -> eyre::Result<T>) -> eyre::Result<T> {
+        let dir = container_dir(id)?;
+        let dir_file =
+            File::open(&dir).wrap_err_with(|| format!("No state found for container {id}"))?;
+        let _lock = Flock::lock(dir_file, FlockArg::LockExclusive)
+            .map_err(|(_, errno)| eyre!("Failed to lock {}: {errno}", dir.display()))?;
+
+        let mut state = Self::load(id)?;
+        let result = f(&mut state)?;
+        state.save()?;
+        Ok(result)
+    }
+
+    /// Writes the state to a temporary file first, so that concurrent readers never observe a
+    /// partially written state. The temporary file is unique to the process and thread, as the
+    /// pressure monitor and other bento processes may save the same state at the same time.
+    pub fn save(&self) -> eyre::Result<()> {
+        let dir = container_dir(&self.id)?;
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(STATE_FILE_NAME);
+        let tmp_path = dir.join(format!(
+            "{STATE_FILE_NAME}.{}.{}.tmp",
+            Pid::this(),
+            gettid()
+        ));
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+        debug!(
+            "Saved state of container {id} to {}",
+            path.display(),
+            id = self.id
+        );
+        Ok(())
+    }
+}
+
+/// Root directory holding the state of every container.
+pub fn state_dir() -> PathBuf {
+    let uid = getuid();
+    if uid.is_root() {
+        return PathBuf::from("/run/bento");
+    }
+
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("bento"),
+        None => env::temp_dir().join(format!("bento-{uid}")),
+    }
+}
+
+/// Root directory of data that outlives containers, like unpacked image layers.
+pub fn data_dir() -> PathBuf {
+    let uid = getuid();
+    if uid.is_root() {
+        return PathBuf::from("/var/lib/bento");
+    }
+
+    match (env::var_os("XDG_DATA_HOME"), env::var_os("HOME")) {
+        (Some(data_home), _) => PathBuf::from(data_home).join("bento"),
+        (None, Some(home)) => PathBuf::from(home).join(".local/share/bento"),
+        (None, None) => env::temp_dir().join(format!("bento-data-{uid}")),
+    }
+}
+
+pub fn container_dir(id: &str) -> eyre::Result<PathBuf> {
+    validate_id(id)?;
+    Ok(state_dir().join(id))
+}
+
+/// Mount point of the root of a container started without a mount directory.
+pub fn rootfs_dir(id: &str) -> eyre::Result<PathBuf> {
+    Ok(container_dir(id)?.join("rootfs"))
+}
+
+/// IDs are used as path components of both the state directory and the cgroup, so they are
+/// restricted to a conservative set of characters.
+pub fn validate_id(id: &str) -> eyre::Result<()> {
+    let is_valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if id.is_empty() || id.starts_with('.') || !id.chars().all(is_valid_char) {
+        bail!("Invalid container ID {id:?}");
+    }
+    Ok(())
+}
+
+pub fn generate_id() -> eyre::Result<String> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| eyre!("System time is before the Unix epoch: {err}"))?
+        .as_nanos();
+    Ok(format!("{pid:x}{nanos:x}", pid = Pid::this().as_raw()))
+}
+
+pub fn print(id: &str) -> eyre::Result<()> {
+    let state = ContainerState::load(id)?;
+    println!("{}", serde_json::to_string_pretty(&state)?);
+    Ok(())
+}
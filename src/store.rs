@@ -0,0 +1,452 @@
+use crate::{
+    image::{Image, ImageConfig},
+    overlay,
+    state::{self, ContainerState, Status},
+    uid_gid_mapping::HostIds,
+};
+use eyre::{bail, eyre, WrapErr};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    sys::signal::kill,
+    unistd::{chown, gettid, getuid, Pid},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, ErrorKind},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+const LAYERS_DIR_NAME: &str = "layers";
+const IMAGES_DIR_NAME: &str = "images";
+const BUILD_CACHE_DIR_NAME: &str = "build-cache";
+const TMP_SUFFIX: &str = ".tmp";
+const LOCK_FILE_NAME: &str = ".lock";
+/// Length of the abbreviated image IDs that `bento images` shows
+pub const SHORT_ID_LEN: usize = 12;
+
+/// An image loaded into the store, whose layers are unpacked in the layers directory. Images
+/// share the layers they have in common, and a layer is kept as long as an image or a container
+/// refers to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageRecord {
+    /// Hex digest of the image config
+    pub id: String,
+    /// Names the image was loaded with, each of which belongs to at most one image
+    pub names: Vec<String>,
+    /// Hex digests of the uncompressed layers, bottom first
+    pub layers: Vec<String>,
+    pub config: ImageConfig,
+}
+
+/// Creates the data directory closed to other users of the host, who could otherwise run the
+/// setuid binaries of unpacked layers. Under root, the group of the container's root user may
+/// traverse it, as that user mounts overlay roots from inside the user namespace. Ownership and
+/// mode are only set by the process that creates it.
+fn data_dir() -> eyre::Result<PathBuf> {
+    let dir = state::data_dir();
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::create_dir(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => return Ok(dir),
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("Failed to create {}", dir.display()))
+        }
+    }
+    let mode = if getuid().is_root() {
+        chown(&dir, None, Some(HostIds::load()?.gid(0)))?;
+        0o710
+    } else {
+        0o700
+    };
+    fs::set_permissions(&dir, Permissions::from_mode(mode))?;
+    debug!("Created data directory {}", dir.display());
+    Ok(dir)
+}
+
+/// A lock on the store. Containers that start and builds hold it shared until their layers are
+/// referred to by an image or a container state, and `rmi` and `prune` hold it exclusively while
+/// they look for unreferenced layers and remove them.
+#[derive(Debug)]
+pub struct StoreLock {
+    /// Unlocked when dropped
+    _file: Flock<File>,
+}
+
+fn lock(dir: &Path, arg: FlockArg) -> eyre::Result<StoreLock> {
+    let path = dir.join(LOCK_FILE_NAME);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let file = Flock::lock(file, arg)
+        .map_err(|(_, errno)| eyre!("Failed to lock {}: {errno}", path.display()))?;
+    Ok(StoreLock { _file: file })
+}
+
+pub fn lock_shared() -> eyre::Result<StoreLock> {
+    lock(&data_dir()?, FlockArg::LockShared)
+}
+
+fn lock_exclusive() -> eyre::Result<StoreLock> {
+    lock(&data_dir()?, FlockArg::LockExclusive)
+}
+
+/// Directory of the unpacked layers, by the digest of their uncompressed tar.
+fn layers_dir() -> eyre::Result<PathBuf> {
+    let dir = data_dir()?.join(LAYERS_DIR_NAME);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn images_dir() -> eyre::Result<PathBuf> {
+    let dir = data_dir()?.join(IMAGES_DIR_NAME);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn build_cache_dir() -> eyre::Result<PathBuf> {
+    let dir = data_dir()?.join(BUILD_CACHE_DIR_NAME);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+pub fn layer_dir(hex: &str) -> eyre::Result<PathBuf> {
+    Ok(layers_dir()?.join(hex))
+}
+
+/// Directory a layer is unpacked or built in before it is moved into place. It is named after
+/// the current process, so that `bento prune` can tell abandoned directories from those in use.
+pub fn tmp_layer_dir(name: &str) -> eyre::Result<PathBuf> {
+    Ok(layers_dir()?.join(format!("{name}.{}{TMP_SUFFIX}", Pid::this())))
+}
+
+impl ImageRecord {
+    fn path(id: &str) -> eyre::Result<PathBuf> {
+        Ok(images_dir()?.join(format!("{id}.json")))
+    }
+
+    pub fn load_all() -> eyre::Result<Vec<Self>> {
+        let mut records = vec![];
+        for entry in fs::read_dir(images_dir()?)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let contents = fs::read_to_string(&path)?;
+                records.push(
+                    serde_json::from_str(&contents)
+                        .wrap_err_with(|| format!("Invalid image record {}", path.display()))?,
+                );
+            }
+        }
+        Ok(records)
+    }
+
+    /// Finds an image by name, by name without the `:latest` tag, or by a unique prefix of its
+    /// ID.
+    pub fn find(reference: &str) -> eyre::Result<Self> {
+        let records = Self::load_all()?;
+        let latest = format!("{reference}:latest");
+        if let Some(record) = records.iter().find(|record| {
+            record
+                .names
+                .iter()
+                .any(|name| *name == reference || *name == latest)
+        }) {
+            return Ok(record.clone());
+        }
+
+        let prefix = reference.strip_prefix("sha256:").unwrap_or(reference);
+        let mut matches = records
+            .into_iter()
+            .filter(|record| !prefix.is_empty() && record.id.starts_with(prefix));
+        match (matches.next(), matches.next()) {
+            (Some(record), None) => Ok(record),
+            (Some(_), Some(_)) => bail!("Image ID {reference} is ambiguous"),
+            (None, _) => bail!("No image {reference} in the store, nor a layout or archive"),
+        }
+    }
+
+    /// Records the image, keeping the names of an earlier record of it, and taking its names away
+    /// from the images that had them before. The records are locked for the whole change, so that
+    /// concurrent saves cannot lose each other's names. That lock is separate from the store's,
+    /// which callers already hold shared until the record refers to their layers.
+    pub fn save(&self) -> eyre::Result<()> {
+        let _lock = lock(&images_dir()?, FlockArg::LockExclusive)?;
+        let mut record = self.clone();
+        for mut other in Self::load_all()? {
+            if other.id == record.id {
+                for name in other.names {
+                    if !record.names.contains(&name) {
+                        record.names.push(name);
+                    }
+                }
+            } else if other.names.iter().any(|name| record.names.contains(name)) {
+                other.names.retain(|name| !record.names.contains(name));
+                debug!("Image {} lost its names to {}", other.id, record.id);
+                other.write()?;
+            }
+        }
+        record.write()
+    }
+
+    /// Writes the record to a temporary file unique to the process and thread first, so that
+    /// readers never observe a partially written record.
+    fn write(&self) -> eyre::Result<()> {
+        let path = Self::path(&self.id)?;
+        let tmp_path =
+            path.with_extension(format!("json.{}.{}{TMP_SUFFIX}", Pid::this(), gettid()));
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+        debug!("Saved image {} to {}", self.id, path.display());
+        Ok(())
+    }
+
+    /// The image with its layers, which `bento prune` may have removed if the image lost its
+    /// names in the meantime.
+    pub fn image(&self) -> eyre::Result<Image> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|hex| {
+                let dir = layer_dir(hex)?;
+                if !dir.exists() {
+                    bail!(
+                        "Layer {hex} of image {} is missing from the store, load the image again",
+                        self.id
+                    );
+                }
+                Ok(dir)
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        Ok(Image {
+            layers,
+            config: self.config.clone(),
+        })
+    }
+}
+
+/// Layer that an earlier build made for a step, by the digest of everything the step depends on,
+/// if the layer is still in the store.
+pub fn cached_layer(key: &str) -> eyre::Result<Option<String>> {
+    let hex = match fs::read_to_string(build_cache_dir()?.join(key)) {
+        Ok(hex) => hex,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(layer_dir(&hex)?.exists().then_some(hex))
+}
+
+pub fn cache_layer(key: &str, hex: &str) -> eyre::Result<()> {
+    fs::write(build_cache_dir()?.join(key), hex)?;
+    Ok(())
+}
+
+/// Forgets the build steps whose layers are gone.
+fn remove_stale_cache_entries() -> eyre::Result<()> {
+    for entry in fs::read_dir(build_cache_dir()?)? {
+        let path = entry?.path();
+        if !layer_dir(&fs::read_to_string(&path)?)?.exists() {
+            fs::remove_file(&path)?;
+            debug!("Removed build cache entry {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Layers that images refer to, or that the overlays of containers stack, including stopped
+/// containers whose upper layer can still be committed.
+fn referenced_layers() -> eyre::Result<HashSet<String>> {
+    let mut layers: HashSet<_> = ImageRecord::load_all()?
+        .into_iter()
+        .flat_map(|record| record.layers)
+        .collect();
+
+    let layers_dir = layers_dir()?.canonicalize()?;
+    for state in ContainerState::load_all()? {
+        if state.status == Status::Stopped && !overlay::upper_dir(&state.id)?.exists() {
+            continue;
+        }
+        for dir in &state.rootfs_lower {
+            if dir.parent() == Some(layers_dir.as_path()) {
+                if let Some(name) = dir.file_name() {
+                    layers.insert(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+    Ok(layers)
+}
+
+/// Removes a layer, whose directories may lack write permissions, and returns its size.
+pub fn remove_layer(dir: &Path) -> eyre::Result<u64> {
+    make_writable(dir)?;
+    let size = dir_size(dir)?;
+    fs::remove_dir_all(dir).wrap_err_with(|| format!("Failed to remove {}", dir.display()))?;
+    Ok(size)
+}
+
+fn make_writable(dir: &Path) -> io::Result<()> {
+    fs::set_permissions(dir, Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Apparent size of the files in a directory.
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{size} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// Removes the layers that nothing refers to, and returns the space reclaimed.
+fn remove_unreferenced_layers() -> eyre::Result<u64> {
+    let referenced = referenced_layers()?;
+    let mut reclaimed = 0;
+    for entry in fs::read_dir(layers_dir()?)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(TMP_SUFFIX) || referenced.contains(&name) {
+            continue;
+        }
+        reclaimed += remove_layer(&entry.path())?;
+        debug!("Removed layer {name}");
+    }
+    Ok(reclaimed)
+}
+
+/// Removes the directories of layers whose unpacking process died before finishing.
+fn remove_abandoned_layers() -> eyre::Result<u64> {
+    let mut reclaimed = 0;
+    for entry in fs::read_dir(layers_dir()?)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(pid) = name
+            .strip_suffix(TMP_SUFFIX)
+            .and_then(|name| name.rsplit_once('.'))
+            .and_then(|(_, pid)| pid.parse().ok())
+        else {
+            continue;
+        };
+        if kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH) {
+            debug!("Layer {name} is still being unpacked");
+            continue;
+        }
+        reclaimed += remove_layer(&entry.path())?;
+        debug!("Removed abandoned layer {name}");
+    }
+    Ok(reclaimed)
+}
+
+pub fn print_images() -> eyre::Result<()> {
+    let records = ImageRecord::load_all()?;
+    let mut rows = records
+        .iter()
+        .map(|record| {
+            let mut size = 0;
+            for hex in &record.layers {
+                size += match dir_size(&layer_dir(hex)?) {
+                    Ok(layer_size) => layer_size,
+                    Err(err) if err.kind() == ErrorKind::NotFound => 0,
+                    Err(err) => return Err(err.into()),
+                };
+            }
+            let names = match record.names.is_empty() {
+                true => vec!["<none>".to_owned()],
+                false => record.names.clone(),
+            };
+            Ok(names.into_iter().map(move |name| {
+                (
+                    name,
+                    record.id[..SHORT_ID_LEN.min(record.id.len())].to_owned(),
+                    record.layers.len(),
+                    format_size(size),
+                )
+            }))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    rows.sort();
+
+    let name_width = rows
+        .iter()
+        .map(|(name, ..)| name.len())
+        .chain(["NAME".len()])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:name_width$}  {:SHORT_ID_LEN$}  LAYERS  SIZE",
+        "NAME", "ID"
+    );
+    for (name, id, layers, size) in rows {
+        println!("{name:name_width$}  {id:SHORT_ID_LEN$}  {layers:>6}  {size}");
+    }
+    Ok(())
+}
+
+/// Removes images from the store, along with the layers no other image or container uses.
+pub fn remove_images(references: &[String]) -> eyre::Result<()> {
+    let _lock = lock_exclusive()?;
+    for reference in references {
+        let record = ImageRecord::find(reference)?;
+        fs::remove_file(ImageRecord::path(&record.id)?)?;
+        println!("Deleted {}", record.id);
+    }
+    let reclaimed = remove_unreferenced_layers()?;
+    debug!("Reclaimed {}", format_size(reclaimed));
+    Ok(())
+}
+
+/// Removes images that lost their names to newer ones, unreferenced layers, the remains of
+/// interrupted unpacking, and the build steps whose layers are gone.
+pub fn prune() -> eyre::Result<()> {
+    let _lock = lock_exclusive()?;
+    for record in ImageRecord::load_all()? {
+        if record.names.is_empty() {
+            fs::remove_file(ImageRecord::path(&record.id)?)?;
+            println!("Deleted {}", record.id);
+        }
+    }
+    let reclaimed = remove_unreferenced_layers()? + remove_abandoned_layers()?;
+    remove_stale_cache_entries()?;
+    println!("Reclaimed {}", format_size(reclaimed));
+    Ok(())
+}
@@ -1,8 +1,9 @@
-use eyre::eyre;
+use eyre::{bail, eyre, WrapErr};
 use nix::unistd::{getgid, getuid, Gid, Group, Pid, Uid, User};
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, Write},
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind},
+    process::Command,
     str::FromStr,
 };
 use tracing::debug;
@@ -58,7 +59,11 @@ fn read_subuid() -> eyre::Result<Option<UidMapping>> {
         .name;
     debug!("Current UID {current_uid} has name {current_user}");
 
-    let file = File::open(SUBUID_PATH)?;
+    let file = match File::open(SUBUID_PATH) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
     let reader = BufReader::new(file);
 
     for line in reader.lines() {
@@ -77,25 +82,6 @@ fn read_subuid() -> eyre::Result<Option<UidMapping>> {
     Ok(None)
 }
 
-fn uid_map_path(pid: Pid) -> String {
-    format!("/proc/{pid}/uid_map")
-}
-
-fn write_to_uid_map(
-    pid: Pid,
-    UidMapping {
-        uid_or_user: _,
-        sub_uid,
-        sub_count,
-    }: &UidMapping,
-) -> eyre::Result<()> {
-    let path = uid_map_path(pid);
-    debug!("Writing to UID map: {path}");
-    let mut file = File::create(path)?;
-    file.write_all(format!("0 {sub_uid} {sub_count}\n").as_bytes())?;
-    Ok(())
-}
-
 #[derive(Debug)]
 enum GidOrGroup {
     Gid(Gid),
@@ -147,7 +133,11 @@ fn read_subgid() -> eyre::Result<Option<GidMapping>> {
         .name;
     debug!("Current GID {current_gid} has name {current_group}");
 
-    let file = File::open(SUBGID_PATH)?;
+    let file = match File::open(SUBGID_PATH) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
     let reader = BufReader::new(file);
 
     for line in reader.lines() {
@@ -166,35 +156,130 @@ fn read_subgid() -> eyre::Result<Option<GidMapping>> {
     Ok(None)
 }
 
-fn gid_map_path(pid: Pid) -> String {
-    format!("/proc/{pid}/gid_map")
+/// Lines of a UID or GID map, as (ID inside the container, ID outside, count).
+type IdMap = Vec<(u32, u32, u32)>;
+
+/// Root maps the container's root user to its first subordinate ID, so that host root stays
+/// unmapped, and only to itself without any. Other users map it to themselves, which lets them
+/// set up the container's root filesystem in directories they own, and the other container IDs
+/// to their subordinate IDs.
+fn id_map(current_id: u32, sub_ids: Option<(u32, u32)>) -> IdMap {
+    match sub_ids {
+        Some((sub_id, sub_count)) if current_id == 0 => vec![(0, sub_id, sub_count)],
+        Some((sub_id, sub_count)) => vec![(0, current_id, 1), (1, sub_id, sub_count)],
+        None => vec![(0, current_id, 1)],
+    }
 }
 
-fn write_to_gid_map(
-    pid: Pid,
-    GidMapping {
-        gid_or_group: _,
-        sub_gid,
-        sub_count,
-    }: &GidMapping,
-) -> eyre::Result<()> {
-    let path = gid_map_path(pid);
-    debug!("Writing to GID map: {path}");
-    let mut file = File::create(path)?;
-    file.write_all(format!("0 {sub_gid} {sub_count}\n").as_bytes())?;
+/// Writes `/proc/PID/uid_map` or `/proc/PID/gid_map`. Without root, only the current ID can be
+/// mapped directly, and subordinate IDs need the setuid `newuidmap` and `newgidmap` helpers.
+fn write_id_map(pid: Pid, kind: &str, id_map: &IdMap) -> eyre::Result<()> {
+    if !getuid().is_root() && id_map.len() > 1 {
+        let helper = format!("new{kind}map");
+        let mut command = Command::new(&helper);
+        command.arg(pid.to_string());
+        for (inside, outside, count) in id_map {
+            command.args([inside.to_string(), outside.to_string(), count.to_string()]);
+        }
+        debug!("Running {command:?}");
+        let status = command.status().wrap_err_with(|| {
+            format!("Failed to run {helper}, is the uidmap package installed?")
+        })?;
+        if !status.success() {
+            bail!("{helper} failed with {status}");
+        }
+        return Ok(());
+    }
+
+    if !getuid().is_root() && kind == "gid" {
+        // Writing a GID map without privileges requires giving up setgroups
+        fs::write(format!("/proc/{pid}/setgroups"), "deny")?;
+    }
+    let path = format!("/proc/{pid}/{kind}_map");
+    debug!("Writing {id_map:?} to {path}");
+    // The kernel only accepts the whole map in a single write
+    let contents = id_map
+        .iter()
+        .map(|(inside, outside, count)| format!("{inside} {outside} {count}\n"))
+        .collect::<String>();
+    fs::write(&path, contents).wrap_err_with(|| format!("Failed to write {path}"))?;
     Ok(())
 }
 
-pub fn read_and_write_uid_and_gid_mappings(pid: Pid) -> eyre::Result<()> {
-    let subuid = read_subuid()?;
-    let subgid = read_subgid()?;
+fn uid_and_gid_maps() -> eyre::Result<(IdMap, IdMap)> {
+    let uid_map = id_map(
+        getuid().as_raw(),
+        read_subuid()?.map(|mapping| (mapping.sub_uid.as_raw(), mapping.sub_count)),
+    );
+    let gid_map = id_map(
+        getgid().as_raw(),
+        read_subgid()?.map(|mapping| (mapping.sub_gid.as_raw(), mapping.sub_count)),
+    );
+    Ok((uid_map, gid_map))
+}
 
-    if let Some(uid_mapping) = subuid {
-        write_to_uid_map(pid, &uid_mapping)?;
+/// Returns the host UID and GID of the container's root user.
+pub fn write_uid_and_gid_mappings(pid: Pid) -> eyre::Result<(Uid, Gid)> {
+    let (uid_map, gid_map) = uid_and_gid_maps()?;
+    if getuid().is_root() && (uid_map[0].1 == 0 || gid_map[0].1 == 0) {
+        // Printed regardless of the log level, since the container is not contained as expected
+        eprintln!(
+            "WARNING: root has no entry in {SUBUID_PATH} or {SUBGID_PATH}, so the root user of \
+             the container is the root user of the host. Add one, e.g. root:100000:65536, to \
+             both files"
+        );
     }
-    if let Some(gid_mapping) = subgid {
-        write_to_gid_map(pid, &gid_mapping)?;
+    write_id_map(pid, "uid", &uid_map)?;
+    write_id_map(pid, "gid", &gid_map)?;
+
+    Ok((Uid::from_raw(uid_map[0].1), Gid::from_raw(gid_map[0].1)))
+}
+
+/// Translates container IDs to the host IDs that containers' user namespaces map them to, for
+/// files that bento creates for containers ahead of time, and back for files that containers
+/// leave behind.
+#[derive(Debug)]
+pub struct HostIds {
+    uid_map: IdMap,
+    gid_map: IdMap,
+}
+
+impl HostIds {
+    pub fn load() -> eyre::Result<Self> {
+        let (uid_map, gid_map) = uid_and_gid_maps()?;
+        Ok(Self { uid_map, gid_map })
     }
 
-    Ok(())
+    pub fn uid(&self, uid: u32) -> Uid {
+        Uid::from_raw(to_host(&self.uid_map, uid))
+    }
+
+    pub fn gid(&self, gid: u32) -> Gid {
+        Gid::from_raw(to_host(&self.gid_map, gid))
+    }
+
+    pub fn container_uid(&self, uid: Uid) -> u32 {
+        to_container(&self.uid_map, uid.as_raw())
+    }
+
+    pub fn container_gid(&self, gid: Gid) -> u32 {
+        to_container(&self.gid_map, gid.as_raw())
+    }
+}
+
+/// IDs outside the map become the container's root user, who could not change them otherwise.
+fn to_host(id_map: &IdMap, id: u32) -> u32 {
+    id_map
+        .iter()
+        .find(|(inside, _, count)| id >= *inside && id - inside < *count)
+        .map_or(id_map[0].1, |(inside, outside, _)| outside + (id - inside))
+}
+
+/// Host IDs outside the map, like those of files copied in by the host's root user, become the
+/// container's root user.
+fn to_container(id_map: &IdMap, id: u32) -> u32 {
+    id_map
+        .iter()
+        .find(|(_, outside, count)| id >= *outside && id - outside < *count)
+        .map_or(0, |(inside, outside, _)| inside + (id - outside))
 }
@@ -0,0 +1,200 @@
+use eyre::{bail, eyre, WrapErr};
+use nix::{libc, unistd::Pid};
+use rlimit::{prlimit, setrlimit, Resource, INFINITY};
+use std::str::FromStr;
+use tracing::debug;
+
+const RESOURCES: [(&str, Resource); 12] = [
+    ("nofile", Resource::NOFILE),
+    ("nproc", Resource::NPROC),
+    ("core", Resource::CORE),
+    ("as", Resource::AS),
+    ("data", Resource::DATA),
+    ("stack", Resource::STACK),
+    ("fsize", Resource::FSIZE),
+    ("cpu", Resource::CPU),
+    ("memlock", Resource::MEMLOCK),
+    ("msgqueue", Resource::MSGQUEUE),
+    ("rtprio", Resource::RTPRIO),
+    ("nice", Resource::NICE),
+];
+
+/// A resource limit, written as `NAME=SOFT[:HARD]`, e.g. `nofile=1024:4096`. Limits can be
+/// `unlimited`, and the hard limit defaults to the soft limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Ulimit {
+    resource: Resource,
+    soft: u64,
+    hard: u64,
+}
+
+const DEFAULT_FD_LIMIT: u64 = 64;
+
+impl Ulimit {
+    /// Limits applied to every container unless overridden.
+    fn defaults() -> Vec<Ulimit> {
+        vec![Ulimit {
+            resource: Resource::NOFILE,
+            soft: DEFAULT_FD_LIMIT,
+            hard: DEFAULT_FD_LIMIT,
+        }]
+    }
+
+    /// Merges `ulimits` into the defaults, with later limits overriding earlier ones on the same
+    /// resource.
+    pub fn with_defaults(ulimits: Vec<Ulimit>) -> Vec<Ulimit> {
+        let mut merged: Vec<Ulimit> = vec![];
+        for ulimit in Ulimit::defaults().into_iter().chain(ulimits) {
+            merged.retain(|existing| existing.resource != ulimit.resource);
+            merged.push(ulimit);
+        }
+        merged
+    }
+}
+
+fn parse_limit(s: &str) -> eyre::Result<u64> {
+    match s {
+        "unlimited" => Ok(INFINITY),
+        _ => s.parse().wrap_err_with(|| format!("Invalid limit {s}")),
+    }
+}
+
+impl FromStr for Ulimit {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (name, limits_str) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("Ulimit {s} should be NAME=SOFT[:HARD]"))?;
+        let resource = RESOURCES
+            .iter()
+            .find(|(resource_name, _)| resource_name.eq_ignore_ascii_case(name))
+            .map(|(_, resource)| *resource)
+            .ok_or_else(|| eyre!("Unknown ulimit {name}"))?;
+
+        let (soft, hard) = match limits_str.split_once(':') {
+            Some((soft_str, hard_str)) => (parse_limit(soft_str)?, parse_limit(hard_str)?),
+            None => {
+                let limit = parse_limit(limits_str)?;
+                (limit, limit)
+            }
+        };
+        if soft > hard {
+            bail!("Soft limit of ulimit {s} is above its hard limit");
+        }
+
+        Ok(Self {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+/// Raises the hard limits of the child from bento before it continues, since that needs
+/// CAP_SYS_RESOURCE in the initial user namespace, which the child never has. Its soft limits
+/// stay as they are, so that copying files and the other setup are not held to the container's
+/// limits.
+pub fn raise_hard_limits(pid: Pid, ulimits: &[Ulimit]) -> eyre::Result<()> {
+    for Ulimit { resource, hard, .. } in ulimits {
+        let (mut soft, mut current_hard) = (0, 0);
+        prlimit(
+            pid.as_raw(),
+            *resource,
+            None,
+            Some((&mut soft, &mut current_hard)),
+        )?;
+        if *hard <= current_hard {
+            continue;
+        }
+        debug!("Raising hard {resource:?} of PID {pid} to {hard}");
+        match prlimit(pid.as_raw(), *resource, Some((soft, *hard)), None) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => bail!(
+                "Hard limit {hard} of {} is above bento's own, which only root may raise",
+                resource.as_name()
+            ),
+            result => result.wrap_err_with(|| format!("Failed to set {}", resource.as_name()))?,
+        }
+    }
+    Ok(())
+}
+
+/// Sets the final limits in the child right before it runs the command. The hard limits are
+/// at least as high already, so this only lowers them.
+pub fn set_ulimits(ulimits: &[Ulimit]) -> eyre::Result<()> {
+    for Ulimit {
+        resource,
+        soft,
+        hard,
+    } in ulimits
+    {
+        debug!("Setting {resource:?} to soft {soft}, hard {hard}");
+        setrlimit(*resource, *soft, *hard)
+            .wrap_err_with(|| format!("Failed to set {}", resource.as_name()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ulimit(s: &str) -> Ulimit {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_every_resource_name() {
+        for (name, resource) in RESOURCES {
+            assert_eq!(ulimit(&format!("{name}=1")).resource, resource);
+        }
+        assert_eq!(ulimit("NOFILE=1").resource, Resource::NOFILE);
+    }
+
+    #[test]
+    fn parses_soft_and_hard_limits() {
+        let limit = ulimit("nofile=1024:4096");
+        assert_eq!((limit.soft, limit.hard), (1024, 4096));
+        let limit = ulimit("core=0:unlimited");
+        assert_eq!((limit.soft, limit.hard), (0, INFINITY));
+    }
+
+    #[test]
+    fn defaults_hard_limit_to_soft_limit() {
+        let limit = ulimit("nproc=512");
+        assert_eq!((limit.soft, limit.hard), (512, 512));
+        let limit = ulimit("stack=unlimited");
+        assert_eq!((limit.soft, limit.hard), (INFINITY, INFINITY));
+    }
+
+    #[test]
+    fn rejects_soft_limit_above_hard_limit() {
+        for ulimit in ["nofile=4096:1024", "nofile=unlimited:1024"] {
+            assert!(ulimit.parse::<Ulimit>().is_err(), "{ulimit}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_ulimits() {
+        for ulimit in [
+            "nofile",
+            "files=1024",
+            "nofile=",
+            "nofile=many",
+            "nofile=-1",
+            "nofile=1024:",
+            "nofile=1:2:3",
+        ] {
+            assert!(ulimit.parse::<Ulimit>().is_err(), "{ulimit}");
+        }
+    }
+
+    #[test]
+    fn overrides_defaults_on_the_same_resource() {
+        let merged = Ulimit::with_defaults(vec![ulimit("core=0"), ulimit("nofile=1024:4096")]);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].resource, Resource::CORE);
+        assert_eq!(merged[1].resource, Resource::NOFILE);
+        assert_eq!((merged[1].soft, merged[1].hard), (1024, 4096));
+    }
+}
@@ -0,0 +1,865 @@
+use crate::{
+    egress::EgressPolicy,
+    netlink::Netlink,
+    network,
+    ports::{self, HostSocket, Protocol, PublishedPort, UDP_FLOW_TIMEOUT},
+};
+use eyre::{eyre, WrapErr};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    libc,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, SockaddrStorage},
+};
+use smoltcp::{
+    iface::{Config, Interface, PollIngressSingleResult, SocketHandle, SocketSet},
+    phy::{self, Device, DeviceCapabilities, Medium},
+    socket::{tcp, udp},
+    time::Instant,
+    wire::{
+        EthernetAddress, EthernetFrame, EthernetProtocol, HardwareAddress, IpAddress, IpCidr,
+        IpEndpoint, IpListenEndpoint, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+        ETHERNET_HEADER_LEN,
+    },
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
+    os::{
+        fd::{AsFd, AsRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    thread::{self, JoinHandle},
+    time::{self, Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, warn};
+
+// The addresses that slirp and QEMU's user networking use
+const CONTAINER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+/// Stands for the host's loopback address
+const GATEWAY_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const PREFIX_LEN: u8 = 24;
+const GATEWAY_MAC_ADDRESS: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+const DNS_PORT: u16 = 53;
+
+const TAP_NAME: &str = "tap0";
+const MTU: usize = 1500;
+
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_PACKETS: usize = 32;
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Ports on the gateway address for connections into the container start at the dynamic range
+const FIRST_LOCAL_PORT: u16 = 49152;
+
+/// Creates and configures the tap device in the child's network namespace. The fd is left open
+/// on purpose, since bento shares the fd table and serves the device after the child has
+/// executed the command.
+pub fn create_tap() -> eyre::Result<RawFd> {
+    let tap = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+        .wrap_err("Failed to open /dev/net/tun")?;
+
+    let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+    for (c, byte) in ifreq.ifr_name.iter_mut().zip(TAP_NAME.bytes()) {
+        *c = byte as libc::c_char;
+    }
+    ifreq.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+    if unsafe { libc::ioctl(tap.as_raw_fd(), libc::TUNSETIFF, &ifreq) } < 0 {
+        return Err(io::Error::last_os_error()).wrap_err("Failed to create tap device");
+    }
+
+    let mut netlink = Netlink::connect()?;
+    let index = netlink.link_index(TAP_NAME)?;
+    netlink.add_address(index, IpAddr::V4(CONTAINER_ADDRESS), PREFIX_LEN)?;
+    netlink.set_link_up(index)?;
+    netlink.add_default_route(GATEWAY_ADDRESS)?;
+    debug!("Created {TAP_NAME} with address {CONTAINER_ADDRESS}");
+
+    Ok(tap.into_raw_fd())
+}
+
+/// The tap device as seen by smoltcp. Frames are read ahead of handing them to the interface, so
+/// that new flows can be spotted before smoltcp answers them.
+struct Tap {
+    file: File,
+    received: VecDeque<Vec<u8>>,
+}
+
+impl Tap {
+    fn read_frames(&mut self) -> io::Result<()> {
+        loop {
+            let mut frame = vec![0; ETHERNET_HEADER_LEN + MTU];
+            match self.file.read(&mut frame) {
+                Ok(len) => {
+                    frame.truncate(len);
+                    self.received.push_back(frame);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a File);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        if let Err(err) = (&mut &*self.0).write_all(&frame) {
+            debug!("Dropped frame to {TAP_NAME}: {err}");
+        }
+        result
+    }
+}
+
+impl Device for Tap {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let frame = self.received.pop_front()?;
+        Some((RxToken(frame), TxToken(&self.file)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&self.file))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = ETHERNET_HEADER_LEN + MTU;
+        capabilities
+    }
+}
+
+/// A flow between the container and a remote endpoint, as addressed by the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Flow {
+    protocol: Protocol,
+    container: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+impl Flow {
+    /// Finds the flow that a frame from the container opens, which is any UDP datagram but only
+    /// the SYN of a TCP connection.
+    fn parse(frame: &[u8]) -> Option<Self> {
+        let frame = EthernetFrame::new_checked(frame).ok()?;
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return None;
+        }
+        let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+        let (protocol, src_port, dst_port) = match packet.next_header() {
+            IpProtocol::Tcp => {
+                let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+                if !segment.syn() || segment.ack() {
+                    return None;
+                }
+                (Protocol::Tcp, segment.src_port(), segment.dst_port())
+            }
+            IpProtocol::Udp => {
+                let datagram = UdpPacket::new_checked(packet.payload()).ok()?;
+                (Protocol::Udp, datagram.src_port(), datagram.dst_port())
+            }
+            _ => return None,
+        };
+        Some(Self {
+            protocol,
+            container: SocketAddrV4::new(packet.src_addr(), src_port),
+            remote: SocketAddrV4::new(packet.dst_addr(), dst_port),
+        })
+    }
+}
+
+fn endpoint(address: SocketAddrV4) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(*address.ip()), address.port())
+}
+
+/// Starts connecting without blocking, so that one slow host does not stall the other flows.
+fn connect_tcp(destination: SocketAddr) -> io::Result<TcpStream> {
+    let family = match destination {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    match connect(fd.as_raw_fd(), &SockaddrStorage::from(destination)) {
+        Ok(()) | Err(Errno::EINPROGRESS) => Ok(TcpStream::from(fd)),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+struct TcpFlow {
+    handle: SocketHandle,
+    stream: TcpStream,
+    connected: bool,
+    host_closed: bool,
+    container_closed: bool,
+    /// Accepted on a published port, rather than opened by the container
+    inbound: bool,
+}
+
+impl TcpFlow {
+    /// Copies data both ways between the smoltcp socket and the host socket, and forwards the
+    /// end of the stream from either side.
+    fn relay(&mut self, socket: &mut tcp::Socket) -> io::Result<()> {
+        if !self.connected {
+            if let Some(err) = self.stream.take_error()? {
+                return Err(err);
+            }
+            if self.stream.peer_addr().is_err() {
+                // Still connecting
+                return Ok(());
+            }
+            self.connected = true;
+        }
+
+        while socket.can_recv() {
+            let result = socket.recv(|data| match self.stream.write(data) {
+                Ok(len) => (len, Ok(len)),
+                Err(err) => (0, Err(err)),
+            });
+            match result {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) if err.kind() == ErrorKind::WouldBlock => break,
+                Ok(Err(err)) => return Err(err),
+            }
+        }
+        let container_finished = matches!(
+            socket.state(),
+            tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing
+        );
+        if container_finished && socket.recv_queue() == 0 && !self.container_closed {
+            self.stream.shutdown(std::net::Shutdown::Write)?;
+            self.container_closed = true;
+        }
+
+        let mut buf = [0; TCP_BUFFER_SIZE];
+        while !self.host_closed && socket.can_send() {
+            let space = socket.send_capacity() - socket.send_queue();
+            match self.stream.read(&mut buf[..space]) {
+                Ok(0) => {
+                    socket.close();
+                    self.host_closed = true;
+                }
+                Ok(len) => {
+                    // Cannot fail, since the data fits into the free space
+                    let _ = socket.send_slice(&buf[..len]);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_flags(&self, socket: &tcp::Socket) -> PollFlags {
+        let mut flags = PollFlags::empty();
+        if !self.connected {
+            flags |= PollFlags::POLLOUT;
+            return flags;
+        }
+        if !self.host_closed && socket.can_send() {
+            flags |= PollFlags::POLLIN;
+        }
+        if socket.recv_queue() > 0 {
+            flags |= PollFlags::POLLOUT;
+        }
+        flags
+    }
+}
+
+struct UdpFlow {
+    socket: UdpSocket,
+    last_active: time::Instant,
+}
+
+/// Datagrams from a peer on the host to a published UDP port.
+struct InboundUdpFlow {
+    /// smoltcp socket on the gateway address, which the container replies to
+    handle: SocketHandle,
+    local_port: u16,
+    last_active: time::Instant,
+}
+
+/// A slirp-style TCP/IP stack: smoltcp terminates the container's TCP connections and UDP
+/// datagrams, whatever their destination, and each flow is relayed through a host socket.
+struct Stack {
+    id: String,
+    tap: Tap,
+    interface: Interface,
+    sockets: SocketSet<'static>,
+    nameserver: Option<IpAddr>,
+    tcp_flows: HashMap<Flow, TcpFlow>,
+    /// smoltcp sockets by the remote endpoint they stand in for
+    udp_sockets: HashMap<SocketAddrV4, SocketHandle>,
+    udp_flows: HashMap<Flow, UdpFlow>,
+    published_ports: Vec<PublishedPort>,
+    /// By index of the published port and address of the peer on the host
+    inbound_udp_flows: HashMap<(usize, SocketAddr), InboundUdpFlow>,
+    last_local_port: u16,
+    egress: EgressPolicy,
+    allow_host_loopback: bool,
+}
+
+impl Stack {
+    fn new(
+        id: String,
+        tap: File,
+        nameserver: Option<IpAddr>,
+        published_ports: Vec<PublishedPort>,
+        egress: EgressPolicy,
+        allow_host_loopback: bool,
+    ) -> eyre::Result<Self> {
+        let mut tap = Tap {
+            file: tap,
+            received: VecDeque::new(),
+        };
+
+        let mut config = Config::new(HardwareAddress::Ethernet(GATEWAY_MAC_ADDRESS));
+        config.random_seed = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        let mut interface = Interface::new(config, &mut tap, Instant::now());
+        interface.update_ip_addrs(|addresses| {
+            for address in [GATEWAY_ADDRESS, DNS_ADDRESS] {
+                addresses
+                    .push(IpCidr::new(IpAddress::Ipv4(address), PREFIX_LEN))
+                    .expect("interface should have room for two addresses");
+            }
+        });
+        // Together with a route through one of its own addresses, this makes the interface
+        // accept packets to any destination, so that it can stand in for all of them
+        interface.set_any_ip(true);
+        interface
+            .routes_mut()
+            .add_default_ipv4_route(GATEWAY_ADDRESS)
+            .map_err(|_| eyre!("Failed to add default route to the userspace stack"))?;
+
+        Ok(Self {
+            id,
+            tap,
+            interface,
+            sockets: SocketSet::new(vec![]),
+            nameserver,
+            tcp_flows: HashMap::new(),
+            udp_sockets: HashMap::new(),
+            udp_flows: HashMap::new(),
+            published_ports,
+            inbound_udp_flows: HashMap::new(),
+            last_local_port: FIRST_LOCAL_PORT,
+            egress,
+            allow_host_loopback,
+        })
+    }
+
+    /// Where a flow goes on the host. The gateway stands for the host's loopback address if
+    /// allowed, as services listening there expect only local clients, and the DNS address for
+    /// the host's nameserver.
+    fn destination(&self, flow: &Flow) -> Option<SocketAddr> {
+        let remote = flow.remote;
+        if *remote.ip() == GATEWAY_ADDRESS {
+            return self.allow_host_loopback.then_some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                remote.port(),
+            ));
+        }
+        if *remote.ip() == DNS_ADDRESS {
+            return (remote.port() == DNS_PORT)
+                .then_some(self.nameserver)
+                .flatten()
+                .map(|nameserver| SocketAddr::new(nameserver, DNS_PORT));
+        }
+        let subnet = u32::from(GATEWAY_ADDRESS) >> (32 - PREFIX_LEN);
+        if u32::from(*remote.ip()) >> (32 - PREFIX_LEN) == subnet {
+            return None;
+        }
+        Some(SocketAddr::V4(remote))
+    }
+
+    fn run(&mut self, stop_socket: &UnixDatagram) -> eyre::Result<()> {
+        loop {
+            let timeout = self
+                .interface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map_or(MAX_POLL_INTERVAL, |delay| delay.into())
+                .min(MAX_POLL_INTERVAL);
+            let timeout = ports::poll_timeout(&self.published_ports, timeout);
+            if self.wait(stop_socket, timeout)? {
+                return Ok(());
+            }
+
+            self.tap.read_frames()?;
+            self.receive_frames();
+            self.accept_published();
+            self.relay_tcp();
+            self.relay_udp();
+            self.interface
+                .poll_egress(Instant::now(), &mut self.tap, &mut self.sockets);
+            self.remove_closed_flows();
+        }
+    }
+
+    /// Waits until the tap device or a host socket is ready, or smoltcp has timers to handle.
+    /// Returns whether to stop.
+    fn wait(&self, stop_socket: &UnixDatagram, timeout: Duration) -> eyre::Result<bool> {
+        let mut poll_fds = vec![
+            PollFd::new(stop_socket.as_fd(), PollFlags::POLLIN),
+            PollFd::new(self.tap.file.as_fd(), PollFlags::POLLIN),
+        ];
+        for flow in self.tcp_flows.values() {
+            let socket = self.sockets.get::<tcp::Socket>(flow.handle);
+            poll_fds.push(PollFd::new(flow.stream.as_fd(), flow.poll_flags(socket)));
+        }
+        for flow in self.udp_flows.values() {
+            poll_fds.push(PollFd::new(flow.socket.as_fd(), PollFlags::POLLIN));
+        }
+        poll_fds.extend(self.published_ports.iter().map(PublishedPort::poll_fd));
+
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        match poll(&mut poll_fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(poll_fds[0].any().unwrap_or(true))
+    }
+
+    /// Hands the frames to smoltcp one at a time, so that each new TCP connection is accepted by
+    /// the socket that was set up for it.
+    fn receive_frames(&mut self) {
+        while let Some(frame) = self.tap.received.front() {
+            let flow = Flow::parse(frame);
+            if let Some(flow) = &flow {
+                self.open_flow(flow);
+            }
+
+            let result = self.interface.poll_ingress_single(
+                Instant::now(),
+                &mut self.tap,
+                &mut self.sockets,
+            );
+            if result == PollIngressSingleResult::None {
+                break;
+            }
+
+            // Drop a listening socket that smoltcp did not hand the SYN to, e.g. because its
+            // checksum was wrong, as it would take over the next connection to the same endpoint
+            if let Some(
+                flow @ Flow {
+                    protocol: Protocol::Tcp,
+                    ..
+                },
+            ) = flow
+            {
+                let listening = self.tcp_flows.get(&flow).is_some_and(|tcp_flow| {
+                    self.sockets.get::<tcp::Socket>(tcp_flow.handle).state() == tcp::State::Listen
+                });
+                if listening {
+                    let tcp_flow = self.tcp_flows.remove(&flow).unwrap();
+                    self.sockets.remove(tcp_flow.handle);
+                }
+            }
+        }
+    }
+
+    fn open_flow(&mut self, flow: &Flow) {
+        let exists = match flow.protocol {
+            Protocol::Tcp => self.tcp_flows.contains_key(flow),
+            Protocol::Udp => self.udp_flows.contains_key(flow),
+        };
+        // Replies to datagrams from a published port are not new flows
+        let inbound = *flow.remote.ip() == GATEWAY_ADDRESS
+            && self
+                .inbound_udp_flows
+                .values()
+                .any(|inbound_flow| inbound_flow.local_port == flow.remote.port());
+        if exists || inbound {
+            return;
+        }
+        // smoltcp resets TCP connections and drops UDP datagrams that no socket accepts
+        let Some(destination) = self.destination(flow) else {
+            debug!("No destination for {flow:?} of container {}", self.id);
+            return;
+        };
+        // Checked against where the flow goes on the host rather than the address the container
+        // sees. No rule can tell the host's loopback services apart, so a deny policy keeps the
+        // container away from all of them.
+        let is_host_loopback = *flow.remote.ip() == GATEWAY_ADDRESS;
+        if (is_host_loopback && !self.egress.deny.is_empty())
+            || !self
+                .egress
+                .allows(destination.ip(), Some(destination.port()))
+        {
+            warn!(
+                "Denied egress of container {id} to {remote} ({destination})/{protocol}",
+                id = self.id,
+                remote = flow.remote,
+                protocol = flow.protocol
+            );
+            return;
+        }
+
+        let result = match flow.protocol {
+            Protocol::Tcp => self.open_tcp_flow(flow, destination),
+            Protocol::Udp => self.open_udp_flow(flow, destination),
+        };
+        match result {
+            Ok(()) => debug!("Relaying {flow:?} to {destination}"),
+            Err(err) => debug!("Failed to relay {flow:?} to {destination}: {err}"),
+        }
+    }
+
+    fn open_tcp_flow(&mut self, flow: &Flow, destination: SocketAddr) -> eyre::Result<()> {
+        let stream = connect_tcp(destination)?;
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        socket.listen(IpListenEndpoint::from(endpoint(flow.remote)))?;
+        let handle = self.sockets.add(socket);
+        self.tcp_flows.insert(
+            *flow,
+            TcpFlow {
+                handle,
+                stream,
+                connected: false,
+                host_closed: false,
+                container_closed: false,
+                inbound: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn open_udp_flow(&mut self, flow: &Flow, destination: SocketAddr) -> eyre::Result<()> {
+        let socket = ports::connect_udp(destination)?;
+        if !self.udp_sockets.contains_key(&flow.remote) {
+            let handle = self.add_udp_socket(flow.remote)?;
+            self.udp_sockets.insert(flow.remote, handle);
+        }
+        self.udp_flows.insert(
+            *flow,
+            UdpFlow {
+                socket,
+                last_active: time::Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn add_udp_socket(&mut self, local: SocketAddrV4) -> eyre::Result<SocketHandle> {
+        let buffer = || {
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            )
+        };
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        socket.bind(IpListenEndpoint::from(endpoint(local)))?;
+        Ok(self.sockets.add(socket))
+    }
+
+    /// Picks a port on the gateway address to connect to the container from.
+    fn next_local_port(&mut self) -> u16 {
+        loop {
+            self.last_local_port = self
+                .last_local_port
+                .checked_add(1)
+                .unwrap_or(FIRST_LOCAL_PORT);
+            let port = self.last_local_port;
+            let in_use = self
+                .tcp_flows
+                .keys()
+                .any(|flow| flow.remote == SocketAddrV4::new(GATEWAY_ADDRESS, port))
+                || self
+                    .inbound_udp_flows
+                    .values()
+                    .any(|flow| flow.local_port == port);
+            if !in_use {
+                return port;
+            }
+        }
+    }
+
+    /// Accepts connections and datagrams on published ports, and passes them on to the container
+    /// from the gateway address.
+    fn accept_published(&mut self) {
+        let mut buf = [0; UDP_BUFFER_SIZE];
+        for index in 0..self.published_ports.len() {
+            let container_port = self.published_ports[index].mapping.container_port;
+            let container = SocketAddrV4::new(CONTAINER_ADDRESS, container_port);
+            loop {
+                let result = match &self.published_ports[index].socket {
+                    HostSocket::Tcp(_) => match self.published_ports[index].accept(&self.id) {
+                        Some((stream, peer)) => self
+                            .connect_to_container(stream, peer, container)
+                            .map_err(io::Error::other),
+                        None => break,
+                    },
+                    HostSocket::Udp(socket) => {
+                        socket.recv_from(&mut buf).and_then(|(len, peer)| {
+                            self.forward_to_container(index, peer, container, &buf[..len])
+                                .map_err(io::Error::other)
+                        })
+                    }
+                };
+                match result {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        debug!(
+                            "Failed to forward to {container} of container {}: {err}",
+                            self.id
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn connect_to_container(
+        &mut self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        container: SocketAddrV4,
+    ) -> eyre::Result<()> {
+        let connections = self.tcp_flows.values().filter(|flow| flow.inbound).count();
+        if connections >= ports::MAX_TCP_CONNECTIONS {
+            warn!(
+                "Closed connection from {peer} to container {}, which has {} connections already",
+                self.id,
+                ports::MAX_TCP_CONNECTIONS
+            );
+            return Ok(());
+        }
+        debug!("Forwarding connection from {peer} to {container}");
+        stream.set_nonblocking(true)?;
+        let local = SocketAddrV4::new(GATEWAY_ADDRESS, self.next_local_port());
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        socket.connect(
+            self.interface.context(),
+            endpoint(container),
+            endpoint(local),
+        )?;
+        let handle = self.sockets.add(socket);
+        self.tcp_flows.insert(
+            Flow {
+                protocol: Protocol::Tcp,
+                container,
+                remote: local,
+            },
+            TcpFlow {
+                handle,
+                stream,
+                connected: true,
+                host_closed: false,
+                container_closed: false,
+                inbound: true,
+            },
+        );
+        Ok(())
+    }
+
+    fn forward_to_container(
+        &mut self,
+        index: usize,
+        peer: SocketAddr,
+        container: SocketAddrV4,
+        data: &[u8],
+    ) -> eyre::Result<()> {
+        if !self.inbound_udp_flows.contains_key(&(index, peer)) {
+            if self.inbound_udp_flows.len() >= ports::MAX_UDP_PEERS {
+                debug!("Dropped datagram from {peer}, as there are too many peers");
+                return Ok(());
+            }
+            let local_port = self.next_local_port();
+            let handle = self.add_udp_socket(SocketAddrV4::new(GATEWAY_ADDRESS, local_port))?;
+            debug!("Forwarding datagrams from {peer} to {container}");
+            self.inbound_udp_flows.insert(
+                (index, peer),
+                InboundUdpFlow {
+                    handle,
+                    local_port,
+                    last_active: time::Instant::now(),
+                },
+            );
+        }
+        let inbound_flow = self.inbound_udp_flows.get_mut(&(index, peer)).unwrap();
+        inbound_flow.last_active = time::Instant::now();
+        self.sockets
+            .get_mut::<udp::Socket>(inbound_flow.handle)
+            .send_slice(data, endpoint(container))?;
+        Ok(())
+    }
+
+    fn relay_tcp(&mut self) {
+        for (flow, tcp_flow) in &mut self.tcp_flows {
+            let socket = self.sockets.get_mut::<tcp::Socket>(tcp_flow.handle);
+            if let Err(err) = tcp_flow.relay(socket) {
+                debug!("Resetting {flow:?} of container {}: {err}", self.id);
+                socket.abort();
+            }
+        }
+    }
+
+    fn relay_udp(&mut self) {
+        let now = time::Instant::now();
+        for (remote, handle) in &self.udp_sockets {
+            let socket = self.sockets.get_mut::<udp::Socket>(*handle);
+            while let Ok((data, metadata)) = socket.recv() {
+                let IpAddress::Ipv4(container_address) = metadata.endpoint.addr;
+                let flow = Flow {
+                    protocol: Protocol::Udp,
+                    container: SocketAddrV4::new(container_address, metadata.endpoint.port),
+                    remote: *remote,
+                };
+                if let Some(udp_flow) = self.udp_flows.get_mut(&flow) {
+                    if let Err(err) = udp_flow.socket.send(data) {
+                        debug!("Dropped datagram of {flow:?}: {err}");
+                    }
+                    udp_flow.last_active = now;
+                }
+            }
+        }
+
+        for ((index, peer), inbound_flow) in &mut self.inbound_udp_flows {
+            let HostSocket::Udp(host_socket) = &self.published_ports[*index].socket else {
+                continue;
+            };
+            let socket = self.sockets.get_mut::<udp::Socket>(inbound_flow.handle);
+            while let Ok((data, _)) = socket.recv() {
+                if let Err(err) = host_socket.send_to(data, peer) {
+                    debug!("Dropped datagram to {peer}: {err}");
+                }
+                inbound_flow.last_active = now;
+            }
+        }
+
+        let mut buf = [0; UDP_BUFFER_SIZE];
+        for (flow, udp_flow) in &mut self.udp_flows {
+            let socket = self
+                .sockets
+                .get_mut::<udp::Socket>(self.udp_sockets[&flow.remote]);
+            while let Ok(len) = udp_flow.socket.recv(&mut buf) {
+                if let Err(err) = socket.send_slice(&buf[..len], endpoint(flow.container)) {
+                    debug!("Dropped datagram of {flow:?}: {err}");
+                }
+                udp_flow.last_active = now;
+            }
+        }
+    }
+
+    fn remove_closed_flows(&mut self) {
+        let sockets = &mut self.sockets;
+        self.tcp_flows.retain(|_, tcp_flow| {
+            let state = sockets.get::<tcp::Socket>(tcp_flow.handle).state();
+            let closed = matches!(state, tcp::State::Closed | tcp::State::TimeWait);
+            if closed {
+                sockets.remove(tcp_flow.handle);
+            }
+            !closed
+        });
+
+        self.udp_flows
+            .retain(|_, udp_flow| udp_flow.last_active.elapsed() < UDP_FLOW_TIMEOUT);
+        self.inbound_udp_flows.retain(|_, inbound_flow| {
+            let active = inbound_flow.last_active.elapsed() < UDP_FLOW_TIMEOUT;
+            if !active {
+                sockets.remove(inbound_flow.handle);
+            }
+            active
+        });
+
+        let udp_flows = &self.udp_flows;
+        self.udp_sockets.retain(|remote, handle| {
+            let in_use = udp_flows.keys().any(|flow| flow.remote == *remote);
+            if !in_use {
+                sockets.remove(*handle);
+            }
+            in_use
+        });
+    }
+}
+
+/// Serves the tap device of a `--net=user` container from a thread in bento.
+#[derive(Debug)]
+pub struct UserNetwork {
+    stop_socket: UnixDatagram,
+    handle: JoinHandle<()>,
+}
+
+impl UserNetwork {
+    pub fn start(
+        id: String,
+        tap: OwnedFd,
+        published_ports: Vec<PublishedPort>,
+        egress: EgressPolicy,
+        allow_host_loopback: bool,
+    ) -> eyre::Result<Self> {
+        fcntl(tap.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        // The stack runs in the host's network namespace, so loopback nameservers work as well
+        let (nameservers, _) = network::host_resolv_conf()?;
+        let mut stack = Stack::new(
+            id.clone(),
+            File::from(tap),
+            nameservers.first().copied(),
+            published_ports,
+            egress,
+            allow_host_loopback,
+        )?;
+
+        let (stop_socket, stack_socket) = UnixDatagram::pair()?;
+        let handle = thread::spawn(move || {
+            if let Err(err) = stack.run(&stack_socket) {
+                error!("User network of container {id} failed: {err}");
+            }
+        });
+        debug!("Started user network");
+
+        Ok(Self {
+            stop_socket,
+            handle,
+        })
+    }
+
+    pub fn stop(self) -> eyre::Result<()> {
+        self.stop_socket.send(&[1])?;
+        self.handle
+            .join()
+            .map_err(|_| eyre!("User network thread panicked"))?;
+        debug!("Stopped user network");
+        Ok(())
+    }
+}